@@ -0,0 +1,503 @@
+// Copyright (c) 2024 Dmitry Markin
+//
+// SPDX-License-Identifier: MIT
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! Chatbot context.
+
+use crate::chat_client::openai_api::message::{
+    AssistantMessage, Content, Message, SystemMessage, UserMessage,
+};
+use iter_accumulate::IterAccumulate;
+use std::sync::Arc;
+
+/// Chatbot context.
+#[derive(Default, Clone)]
+pub struct Context {
+    system_message: Option<String>,
+    conversation: Vec<(String, String)>,
+    tokenizer: Option<Arc<tiktoken_rs::CoreBPE>>,
+    min_history_tokens: Option<usize>,
+    max_history_tokens: Option<usize>,
+    summary: Option<String>,
+    evicted: Option<Vec<(String, String)>>,
+}
+
+impl Context {
+    /// Create a new chat context.
+    pub fn new(system_message: Option<String>) -> Self {
+        Self {
+            system_message,
+            conversation: Vec::new(),
+            tokenizer: None,
+            min_history_tokens: None,
+            max_history_tokens: None,
+            summary: None,
+            evicted: None,
+        }
+    }
+
+    /// Create a new chat context wth tokenizer.
+    pub fn new_with_rolling_window(
+        system_message: Option<String>,
+        tokenizer: Arc<tiktoken_rs::CoreBPE>,
+        min_history_tokens: Option<usize>,
+        max_history_tokens: Option<usize>,
+    ) -> Self {
+        debug_assert!(min_history_tokens.is_some() || max_history_tokens.is_some());
+
+        Self {
+            system_message,
+            conversation: Vec::new(),
+            tokenizer: Some(tokenizer),
+            min_history_tokens,
+            max_history_tokens,
+            summary: None,
+            evicted: None,
+        }
+    }
+
+    /// Replace the system message, discarding old records if it grew.
+    pub fn set_system_message(&mut self, system_message: Option<String>) {
+        self.system_message = system_message;
+        self.keep_recent();
+    }
+
+    /// Set the summary of the discarded conversation.
+    ///
+    /// The summary is sent as a second system message and is not counted against the history
+    /// token limits.
+    pub fn set_summary(&mut self, summary: Option<String>) {
+        self.summary = summary;
+    }
+
+    /// Start keeping the records discarded from the context.
+    pub fn collect_evicted(&mut self) {
+        self.evicted.get_or_insert_with(Vec::new);
+    }
+
+    /// Take the records discarded since the last call.
+    pub fn take_evicted(&mut self) -> Vec<(String, String)> {
+        self.evicted
+            .as_mut()
+            .map(std::mem::take)
+            .unwrap_or_default()
+    }
+
+    /// Number of request-response pairs in the context.
+    pub fn num_turns(&self) -> usize {
+        self.conversation.len()
+    }
+
+    /// Context so far with a new request message.
+    pub fn with_request(&self, request: Content) -> impl Iterator<Item = Message> + '_ {
+        self.system_message
+            .iter()
+            .chain(self.summary.iter())
+            .map(|system_message| SystemMessage::new(system_message.clone()).into())
+            .chain(self.conversation.iter().flat_map(|(request, response)| {
+                [
+                    UserMessage::new(Content::Text(request.clone())).into(),
+                    AssistantMessage::new(response.clone()).into(),
+                ]
+                .into_iter()
+            }))
+            .chain(std::iter::once(UserMessage::new(request).into()))
+    }
+
+    /// Extend the context with a new pair of request and response.
+    ///
+    /// Images are only sent with the request they came with: the context keeps the request text
+    /// with a placeholder instead of them.
+    pub fn push(&mut self, request: Content, response: String) {
+        let text = request.text();
+        let placeholder = match request.num_images() {
+            0 => None,
+            1 => Some(String::from("[1 image omitted]")),
+            n => Some(format!("[{n} images omitted]")),
+        };
+        let request = match placeholder {
+            Some(placeholder) if text.is_empty() => placeholder,
+            Some(placeholder) => format!("{text}\n{placeholder}"),
+            None => text,
+        };
+
+        self.conversation.push((request, response));
+        self.keep_recent();
+    }
+
+    /// Discard old records to keep the context within the limits.
+    fn keep_recent(&mut self) {
+        let Some(ref tokenizer) = self.tokenizer else {
+            return;
+        };
+
+        // At least one of the numbers is limited if tokenizer is set.
+        debug_assert!(self.min_history_tokens.is_some() || self.max_history_tokens.is_some());
+        let min_tokens = self.min_history_tokens.unwrap_or(usize::MAX);
+        let max_tokens = self.max_history_tokens.unwrap_or(usize::MAX);
+
+        let num_tokens = |m| tokenizer.encode_with_special_tokens(m).len();
+
+        let system_tokens = self
+            .system_message
+            .as_ref()
+            .map(|m| num_tokens(m))
+            .unwrap_or_default();
+
+        let keep = self
+            .conversation
+            .iter()
+            .rev()
+            .map(|transaction| num_tokens(&transaction.0) + num_tokens(&transaction.1))
+            .accumulate((0, system_tokens), |(_, acc), x| (acc, acc + x))
+            .map_while(|(prev, current)| (prev < min_tokens).then_some(current))
+            .take_while(|current| *current <= max_tokens)
+            .count();
+
+        let discard = self.conversation.len() - keep;
+        let discarded = self.conversation.drain(0..discard);
+        if let Some(evicted) = &mut self.evicted {
+            evicted.extend(discarded);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chat_client::openai_api::message::ContentPart;
+
+    #[test]
+    fn empty() {
+        let context = Context::default();
+
+        assert_eq!(
+            context
+                .with_request(String::from("req").into())
+                .collect::<Vec<_>>(),
+            vec![UserMessage::new(String::from("req").into()).into()],
+        );
+    }
+
+    #[test]
+    fn non_empty() {
+        let mut context = Context::default();
+        context.push(String::from("req1").into(), String::from("resp1"));
+
+        assert_eq!(
+            context
+                .with_request(String::from("req2").into())
+                .collect::<Vec<_>>(),
+            vec![
+                UserMessage::new(String::from("req1").into()).into(),
+                AssistantMessage::new(String::from("resp1")).into(),
+                UserMessage::new(String::from("req2").into()).into(),
+            ],
+        );
+    }
+
+    #[test]
+    fn empty_with_system_message() {
+        let context = Context::new(Some(String::from("system")));
+
+        assert_eq!(
+            context
+                .with_request(String::from("req").into())
+                .collect::<Vec<_>>(),
+            vec![
+                SystemMessage::new(String::from("system")).into(),
+                UserMessage::new(String::from("req").into()).into(),
+            ]
+        );
+    }
+
+    #[test]
+    fn non_empty_with_system_message() {
+        let mut context = Context::new(Some(String::from("system")));
+        context.push(String::from("req1").into(), String::from("resp1"));
+
+        assert_eq!(
+            context
+                .with_request(String::from("req2").into())
+                .collect::<Vec<_>>(),
+            vec![
+                SystemMessage::new(String::from("system")).into(),
+                UserMessage::new(String::from("req1").into()).into(),
+                AssistantMessage::new(String::from("resp1")).into(),
+                UserMessage::new(String::from("req2").into()).into(),
+            ]
+        );
+    }
+
+    #[test]
+    fn min_history_tokens() {
+        let tokenizer = Arc::new(tiktoken_rs::o200k_base().unwrap());
+        let num_tokens = |m| tokenizer.encode_with_special_tokens(m).len();
+        let system = "to to to to to".to_string();
+        let request = "do do do do do".to_string();
+        let response = "be be be be be".to_string();
+        assert_eq!(num_tokens(&system), 5);
+        assert_eq!(num_tokens(&request), 5);
+        assert_eq!(num_tokens(&response), 5);
+
+        let mut context = Context::new_with_rolling_window(
+            Some(system.to_string()),
+            tokenizer.clone(),
+            Some(20),
+            None,
+        );
+        assert!(context.conversation.is_empty());
+
+        // 15 tokens
+        context.push(request.clone().into(), response.clone());
+        assert_eq!(context.conversation.len(), 1);
+
+        // 25 tokens
+        context.push(request.clone().into(), response.clone());
+        assert_eq!(context.conversation.len(), 2);
+
+        // 25 tokens again: one transaction was discarded
+        context.push(request.clone().into(), response.clone());
+        assert_eq!(context.conversation.len(), 2);
+    }
+
+    #[test]
+    fn min_history_tokens_exact() {
+        let tokenizer = Arc::new(tiktoken_rs::o200k_base().unwrap());
+        let num_tokens = |m| tokenizer.encode_with_special_tokens(m).len();
+        let request = "do do do do do".to_string();
+        let response = "be be be be be".to_string();
+        assert_eq!(num_tokens(&request), 5);
+        assert_eq!(num_tokens(&response), 5);
+
+        let mut context = Context::new_with_rolling_window(None, tokenizer.clone(), Some(20), None);
+        assert!(context.conversation.is_empty());
+
+        // 10 tokens
+        context.push(request.clone().into(), response.clone());
+        assert_eq!(context.conversation.len(), 1);
+
+        // 20 tokens
+        context.push(request.clone().into(), response.clone());
+        assert_eq!(context.conversation.len(), 2);
+
+        // 20 tokens again: one transaction was discarded
+        context.push(request.clone().into(), response.clone());
+        assert_eq!(context.conversation.len(), 2);
+    }
+
+    #[test]
+    fn max_history_tokens() {
+        let tokenizer = Arc::new(tiktoken_rs::o200k_base().unwrap());
+        let num_tokens = |m| tokenizer.encode_with_special_tokens(m).len();
+        let system = "to to to to to".to_string();
+        let request = "do do do do do".to_string();
+        let response = "be be be be be".to_string();
+        assert_eq!(num_tokens(&system), 5);
+        assert_eq!(num_tokens(&request), 5);
+        assert_eq!(num_tokens(&response), 5);
+
+        let mut context = Context::new_with_rolling_window(
+            Some(system.to_string()),
+            tokenizer.clone(),
+            None,
+            Some(30),
+        );
+        assert!(context.conversation.is_empty());
+
+        // 15 tokens
+        context.push(request.clone().into(), response.clone());
+        assert_eq!(context.conversation.len(), 1);
+
+        // 25 tokens
+        context.push(request.clone().into(), response.clone());
+        assert_eq!(context.conversation.len(), 2);
+
+        // 25 tokens again: one transaction was discarded
+        context.push(request.clone().into(), response.clone());
+        assert_eq!(context.conversation.len(), 2);
+    }
+
+    #[test]
+    fn max_history_tokens_exact() {
+        let tokenizer = Arc::new(tiktoken_rs::o200k_base().unwrap());
+        let num_tokens = |m| tokenizer.encode_with_special_tokens(m).len();
+        let request = "do do do do do".to_string();
+        let response = "be be be be be".to_string();
+        assert_eq!(num_tokens(&request), 5);
+        assert_eq!(num_tokens(&response), 5);
+
+        let mut context = Context::new_with_rolling_window(None, tokenizer.clone(), None, Some(30));
+        assert!(context.conversation.is_empty());
+
+        // 10 tokens
+        context.push(request.clone().into(), response.clone());
+        assert_eq!(context.conversation.len(), 1);
+
+        // 20 tokens
+        context.push(request.clone().into(), response.clone());
+        assert_eq!(context.conversation.len(), 2);
+
+        // 30 tokens
+        context.push(request.clone().into(), response.clone());
+        assert_eq!(context.conversation.len(), 3);
+
+        // 30 tokens again: one transaction was discarded
+        context.push(request.clone().into(), response.clone());
+        assert_eq!(context.conversation.len(), 3);
+    }
+
+    #[test]
+    fn images_replaced_with_placeholder() {
+        let mut context = Context::default();
+        let image = || ContentPart::image_url(String::from("data:image/png;base64,AAAA"));
+        context.push(
+            Content::Parts(vec![ContentPart::text(String::from("req1")), image()]),
+            String::from("resp1"),
+        );
+        context.push(
+            Content::Parts(vec![image(), image()]),
+            String::from("resp2"),
+        );
+
+        assert_eq!(
+            context
+                .with_request(String::from("req3").into())
+                .collect::<Vec<_>>(),
+            vec![
+                UserMessage::new(String::from("req1\n[1 image omitted]").into()).into(),
+                AssistantMessage::new(String::from("resp1")).into(),
+                UserMessage::new(String::from("[2 images omitted]").into()).into(),
+                AssistantMessage::new(String::from("resp2")).into(),
+                UserMessage::new(String::from("req3").into()).into(),
+            ],
+        );
+    }
+
+    #[test]
+    fn summary_follows_system_message() {
+        let mut context = Context::new(Some(String::from("system")));
+        context.set_summary(Some(String::from("summary")));
+        context.push(String::from("req1").into(), String::from("resp1"));
+
+        assert_eq!(
+            context
+                .with_request(String::from("req2").into())
+                .collect::<Vec<_>>(),
+            vec![
+                SystemMessage::new(String::from("system")).into(),
+                SystemMessage::new(String::from("summary")).into(),
+                UserMessage::new(String::from("req1").into()).into(),
+                AssistantMessage::new(String::from("resp1")).into(),
+                UserMessage::new(String::from("req2").into()).into(),
+            ]
+        );
+    }
+
+    #[test]
+    fn evicted_collected() {
+        let tokenizer = Arc::new(tiktoken_rs::o200k_base().unwrap());
+        let request = "do do do do do".to_string();
+        let response = "be be be be be".to_string();
+
+        let mut context = Context::new_with_rolling_window(None, tokenizer, None, Some(20));
+        context.push(request.clone().into(), response.clone());
+        context.push(request.clone().into(), response.clone());
+        // Not collected before asked to.
+        context.push(request.clone().into(), response.clone());
+        assert!(context.take_evicted().is_empty());
+
+        context.collect_evicted();
+        context.push(String::from("req").into(), response.clone());
+        context.push(request.clone().into(), response.clone());
+        assert_eq!(context.num_turns(), 2);
+        assert_eq!(
+            context.take_evicted(),
+            vec![(request.clone(), response.clone()); 2],
+        );
+        assert!(context.take_evicted().is_empty());
+
+        // A longer system message discards more.
+        context.set_system_message(Some(request.clone()));
+        assert_eq!(context.num_turns(), 1);
+        assert_eq!(
+            context.take_evicted(),
+            vec![(String::from("req"), response.clone())],
+        );
+    }
+
+    #[test]
+    fn summary_not_counted() {
+        let tokenizer = Arc::new(tiktoken_rs::o200k_base().unwrap());
+        let request = "do do do do do".to_string();
+        let response = "be be be be be".to_string();
+
+        let mut context = Context::new_with_rolling_window(None, tokenizer, None, Some(20));
+        context.collect_evicted();
+        context.set_summary(Some("su ".repeat(100)));
+        context.push(request.clone().into(), response.clone());
+        context.push(request.clone().into(), response.clone());
+
+        assert_eq!(context.num_turns(), 2);
+        assert!(context.take_evicted().is_empty());
+    }
+
+    #[test]
+    fn oversized_turn_evicted() {
+        let tokenizer = Arc::new(tiktoken_rs::o200k_base().unwrap());
+        let request = "do do do do do".to_string();
+        let response = "be be be be be".to_string();
+        let long_response = "be ".repeat(30);
+
+        let mut context = Context::new_with_rolling_window(None, tokenizer, None, Some(20));
+        context.collect_evicted();
+        context.push(request.clone().into(), response.clone());
+        // The turn alone exceeds the limit: it is evicted with everything before it.
+        context.push(request.clone().into(), long_response.clone());
+
+        assert_eq!(context.num_turns(), 0);
+        assert_eq!(
+            context.take_evicted(),
+            vec![(request.clone(), response), (request, long_response)],
+        );
+    }
+
+    #[test]
+    fn evicted_with_min_history_tokens() {
+        let tokenizer = Arc::new(tiktoken_rs::o200k_base().unwrap());
+        let request = "do do do do do".to_string();
+        let response = "be be be be be".to_string();
+
+        let mut context = Context::new_with_rolling_window(None, tokenizer, Some(15), None);
+        context.collect_evicted();
+        context.push(String::from("req").into(), response.clone());
+        context.push(request.clone().into(), response.clone());
+        assert!(context.take_evicted().is_empty());
+
+        // 10 + 10 tokens reach the minimum, the oldest turn is no longer needed.
+        context.push(request.clone().into(), response.clone());
+        assert_eq!(context.num_turns(), 2);
+        assert_eq!(
+            context.take_evicted(),
+            vec![(String::from("req"), response)],
+        );
+    }
+}
@@ -0,0 +1,507 @@
+// Copyright (c) 2024 Dmitry Markin
+//
+// SPDX-License-Identifier: MIT
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! OpenAI API Message types.
+
+use serde::{Deserialize, Serialize};
+use serde_json::value::Value;
+
+/// Conversation message.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Message {
+    /// System message.
+    System(SystemMessage),
+    /// User message.
+    User(UserMessage),
+    /// Assistant message.
+    Assistant(AssistantMessage),
+    /// Tool message.
+    Tool(ToolMessage),
+}
+
+/// System message.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SystemMessage {
+    /// The contents of the message.
+    pub content: String,
+    /// An optional name for the participant. Provides the model information
+    /// to differentiate between participants of the same role.
+    pub name: Option<String>,
+}
+
+impl SystemMessage {
+    pub fn new(content: String) -> Self {
+        Self {
+            content,
+            name: None,
+        }
+    }
+}
+
+/// User message content.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(untagged)]
+pub enum Content {
+    /// Text content.
+    Text(String),
+    /// Multipart content, e.g. text with images.
+    Parts(Vec<ContentPart>),
+}
+
+impl Content {
+    /// Text of the content, with the text parts joined by newlines.
+    pub fn text(&self) -> String {
+        match self {
+            Content::Text(text) => text.clone(),
+            Content::Parts(parts) => parts
+                .iter()
+                .filter_map(|part| match part {
+                    ContentPart::Text { text } => Some(text.as_str()),
+                    ContentPart::ImageUrl { .. } => None,
+                })
+                .collect::<Vec<_>>()
+                .join("\n"),
+        }
+    }
+
+    /// Number of images in the content.
+    pub fn num_images(&self) -> usize {
+        match self {
+            Content::Text(_) => 0,
+            Content::Parts(parts) => parts
+                .iter()
+                .filter(|part| matches!(part, ContentPart::ImageUrl { .. }))
+                .count(),
+        }
+    }
+
+    /// Text content, failing on multipart content.
+    fn into_text(self) -> Result<String, Error> {
+        match self {
+            Content::Text(text) => Ok(text),
+            Content::Parts(_) => Err(Error::UnexpectedParts),
+        }
+    }
+}
+
+impl From<String> for Content {
+    fn from(text: String) -> Self {
+        Self::Text(text)
+    }
+}
+
+impl From<&str> for Content {
+    fn from(text: &str) -> Self {
+        Self::Text(text.to_string())
+    }
+}
+
+impl From<Vec<ContentPart>> for Content {
+    fn from(parts: Vec<ContentPart>) -> Self {
+        Self::Parts(parts)
+    }
+}
+
+/// Part of multipart content.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ContentPart {
+    /// Text part.
+    Text {
+        /// The text.
+        text: String,
+    },
+    /// Image part.
+    ImageUrl {
+        /// The image.
+        image_url: ImageUrl,
+    },
+}
+
+impl ContentPart {
+    /// Create a text part.
+    pub fn text(text: String) -> Self {
+        Self::Text { text }
+    }
+
+    /// Create an image part from the URL, either `https:` or `data:` URL with base64 encoded
+    /// image.
+    pub fn image_url(url: String) -> Self {
+        Self::ImageUrl {
+            image_url: ImageUrl { url },
+        }
+    }
+}
+
+/// Image referenced by URL.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct ImageUrl {
+    /// Either `https:` or `data:` URL with base64 encoded image.
+    pub url: String,
+}
+
+/// User message.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UserMessage {
+    /// The contents of the message.
+    pub content: Content,
+    /// An optional name for the participant. Provides the model information
+    /// to differentiate between participants of the same role.
+    pub name: Option<String>,
+}
+
+impl UserMessage {
+    pub fn new(content: Content) -> Self {
+        Self {
+            content,
+            name: None,
+        }
+    }
+}
+
+/// Assistant message.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AssistantMessage {
+    /// The contents of the message.
+    pub content: Option<String>,
+    /// An optional name for the participant. Provides the model information
+    /// to differentiate between participants of the same role.
+    pub name: Option<String>,
+    /// The refusal message by the assistant.
+    pub refusal: Option<String>,
+    /// The tool calls generated by the model, such as function calls.
+    pub tool_calls: Option<Value>,
+    /// Reasoning performaed by model.
+    pub reasoning: Option<String>,
+}
+
+impl AssistantMessage {
+    pub fn new(content: String) -> Self {
+        Self {
+            content: Some(content),
+            name: None,
+            refusal: None,
+            tool_calls: None,
+            reasoning: None,
+        }
+    }
+}
+
+/// Tool message.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ToolMessage {
+    /// The contents of the message.
+    pub content: String,
+    /// Tool call that this message is responding to.
+    pub tool_call_id: String,
+}
+
+impl From<SystemMessage> for Message {
+    fn from(message: SystemMessage) -> Self {
+        Self::System(message)
+    }
+}
+
+impl From<UserMessage> for Message {
+    fn from(message: UserMessage) -> Self {
+        Self::User(message)
+    }
+}
+
+impl From<AssistantMessage> for Message {
+    fn from(message: AssistantMessage) -> Self {
+        Self::Assistant(message)
+    }
+}
+
+impl From<ToolMessage> for Message {
+    fn from(message: ToolMessage) -> Self {
+        Self::Tool(message)
+    }
+}
+
+/// The role of the message author.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    /// System message.
+    System,
+    /// User message.
+    User,
+    /// Assistant message.
+    Assistant,
+    /// Tool message.
+    Tool,
+}
+
+/// Generic message.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct GenericMessage {
+    /// The role of the message author.
+    role: Role,
+    /// The contents of the message.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    content: Option<Content>,
+    /// An optional name for the participant. Provides the model information
+    /// to differentiate between participants of the same role.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    name: Option<String>,
+    /// The refusal message by the assistant.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    refusal: Option<String>,
+    /// The tool calls generated by the model, such as function calls.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tool_calls: Option<Value>,
+    /// Tool call that this message is responding to.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tool_call_id: Option<String>,
+
+    // OpenRouter specific fileds.
+    /// Reasoning performaed by model.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reasoning: Option<String>,
+}
+
+impl From<Message> for GenericMessage {
+    fn from(message: Message) -> Self {
+        match message {
+            Message::System(m) => m.into(),
+            Message::User(m) => m.into(),
+            Message::Assistant(m) => m.into(),
+            Message::Tool(m) => m.into(),
+        }
+    }
+}
+
+impl From<SystemMessage> for GenericMessage {
+    fn from(SystemMessage { content, name }: SystemMessage) -> Self {
+        Self {
+            role: Role::System,
+            content: Some(Content::Text(content)),
+            name,
+            refusal: None,
+            tool_calls: None,
+            tool_call_id: None,
+            reasoning: None,
+        }
+    }
+}
+
+impl From<UserMessage> for GenericMessage {
+    fn from(UserMessage { content, name }: UserMessage) -> Self {
+        Self {
+            role: Role::User,
+            content: Some(content),
+            name,
+            refusal: None,
+            tool_calls: None,
+            tool_call_id: None,
+            reasoning: None,
+        }
+    }
+}
+
+impl From<AssistantMessage> for GenericMessage {
+    fn from(
+        AssistantMessage {
+            content,
+            name,
+            refusal,
+            tool_calls,
+            reasoning,
+        }: AssistantMessage,
+    ) -> Self {
+        Self {
+            role: Role::Assistant,
+            content: content.map(Content::Text),
+            name,
+            refusal,
+            tool_calls,
+            tool_call_id: None,
+            reasoning,
+        }
+    }
+}
+
+impl From<ToolMessage> for GenericMessage {
+    fn from(
+        ToolMessage {
+            content,
+            tool_call_id,
+        }: ToolMessage,
+    ) -> Self {
+        Self {
+            role: Role::Tool,
+            content: Some(Content::Text(content)),
+            name: None,
+            refusal: None,
+            tool_calls: None,
+            tool_call_id: Some(tool_call_id),
+            reasoning: None,
+        }
+    }
+}
+
+impl TryFrom<GenericMessage> for Message {
+    type Error = Error;
+
+    fn try_from(message: GenericMessage) -> Result<Self, Error> {
+        Ok(match message.role {
+            Role::System => Message::System(SystemMessage::try_from(message)?),
+            Role::User => Message::User(UserMessage::try_from(message)?),
+            Role::Assistant => Message::Assistant(AssistantMessage::try_from(message)?),
+            Role::Tool => Message::Tool(ToolMessage::try_from(message)?),
+        })
+    }
+}
+
+/// Error when converting messages
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    /// Missing mandatory field
+    #[error("Missing mandatory field `{0}`")]
+    MissingField(&'static str),
+    /// Invalid role
+    #[error("Expected role {0:?}, got {1:?}")]
+    RoleMismatch(Role, Role),
+    /// Multipart content in a message that only supports text
+    #[error("Expected text content, got multipart content")]
+    UnexpectedParts,
+}
+
+impl TryFrom<GenericMessage> for SystemMessage {
+    type Error = Error;
+
+    fn try_from(m: GenericMessage) -> Result<Self, Error> {
+        if m.role == Role::System {
+            Ok(Self {
+                content: m
+                    .content
+                    .ok_or(Error::MissingField("content"))?
+                    .into_text()?,
+                name: m.name,
+            })
+        } else {
+            Err(Error::RoleMismatch(Role::System, m.role))
+        }
+    }
+}
+
+impl TryFrom<GenericMessage> for UserMessage {
+    type Error = Error;
+
+    fn try_from(m: GenericMessage) -> Result<Self, Error> {
+        if m.role == Role::User {
+            Ok(Self {
+                content: m.content.ok_or(Error::MissingField("content"))?,
+                name: m.name,
+            })
+        } else {
+            Err(Error::RoleMismatch(Role::User, m.role))
+        }
+    }
+}
+
+impl TryFrom<GenericMessage> for AssistantMessage {
+    type Error = Error;
+
+    fn try_from(m: GenericMessage) -> Result<Self, Error> {
+        if m.role == Role::Assistant {
+            Ok(Self {
+                content: m.content.map(Content::into_text).transpose()?,
+                name: m.name,
+                refusal: m.refusal,
+                tool_calls: m.tool_calls,
+                reasoning: m.reasoning,
+            })
+        } else {
+            Err(Error::RoleMismatch(Role::Assistant, m.role))
+        }
+    }
+}
+
+impl TryFrom<GenericMessage> for ToolMessage {
+    type Error = Error;
+
+    fn try_from(m: GenericMessage) -> Result<Self, Error> {
+        if m.role == Role::Tool {
+            Ok(Self {
+                content: m
+                    .content
+                    .ok_or(Error::MissingField("content"))?
+                    .into_text()?,
+                tool_call_id: m.tool_call_id.ok_or(Error::MissingField("tool_call_id"))?,
+            })
+        } else {
+            Err(Error::RoleMismatch(Role::Tool, m.role))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn text_user_message() {
+        let message = GenericMessage::from(UserMessage::new(String::from("req").into()));
+
+        assert_eq!(
+            serde_json::to_value(message).unwrap(),
+            json!({"role": "user", "content": "req"}),
+        );
+    }
+
+    #[test]
+    fn multipart_user_message() {
+        let message = GenericMessage::from(UserMessage::new(Content::Parts(vec![
+            ContentPart::text(String::from("req")),
+            ContentPart::image_url(String::from("data:image/png;base64,AAAA")),
+        ])));
+
+        assert_eq!(
+            serde_json::to_value(message).unwrap(),
+            json!({
+                "role": "user",
+                "content": [
+                    {"type": "text", "text": "req"},
+                    {"type": "image_url", "image_url": {"url": "data:image/png;base64,AAAA"}},
+                ],
+            }),
+        );
+    }
+
+    #[test]
+    fn assistant_message_requires_text() {
+        let message: GenericMessage = serde_json::from_value(json!({
+            "role": "assistant",
+            "content": [{"type": "text", "text": "resp"}],
+        }))
+        .unwrap();
+
+        assert!(matches!(
+            AssistantMessage::try_from(message),
+            Err(Error::UnexpectedParts),
+        ));
+    }
+}
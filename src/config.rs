@@ -22,26 +22,53 @@
 
 //! `jutella-xmpp` configuration.
 
+use crate::engine::{Persona, SummaryConfig};
 use anyhow::{anyhow, Context as _};
 use clap::Parser;
-use std::{fs, path::PathBuf, str::FromStr, time::Duration};
+use sha1::{Digest, Sha1};
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs,
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::Duration,
+};
 use xmpp_parsers::jid::BareJid;
 
 const DEFAULT_HTTP_TIMEOUT: Duration = Duration::from_secs(300);
+const DEFAULT_PING_TIMEOUT: Duration = Duration::from_secs(30);
+const DEFAULT_MAX_ATTACHMENT_SIZE: usize = 10 * 1024 * 1024;
+const DEFAULT_MAM_MAX_AGE: Duration = Duration::from_secs(24 * 60 * 60);
+const DEFAULT_MAX_SUMMARY_TOKENS: usize = 500;
+/// Larger avatars are rejected by many servers.
+const MAX_AVATAR_SIZE: usize = 256 * 1024;
 
 #[derive(Debug, Parser)]
 #[command(version, about, long_about = None)]
 pub struct Args {
     /// Config file location.
     #[arg(short, long, default_value = "/etc/jutellaxmpp.toml")]
-    config: PathBuf,
+    pub config: PathBuf,
 }
 
 #[derive(Debug, serde::Deserialize)]
 struct ConfigFile {
     jid: String,
     password: String,
+    server_host: Option<String>,
+    server_port: Option<u16>,
+    tls: Option<String>,
+    tls_ca_certificate: Option<PathBuf>,
+    tls_pinned_certificate: Option<String>,
+    ping_timeout: Option<u64>,
+    report_os: Option<bool>,
+    nickname: Option<String>,
+    avatar: Option<PathBuf>,
     allowed_users: Vec<String>,
+    denied_users: Option<Vec<String>>,
+    admins: Option<Vec<String>>,
+    admin_approval: Option<bool>,
+    unauthorized_reply: Option<bool>,
     api: Option<String>,
     api_url: String,
     api_version: Option<String>,
@@ -49,12 +76,195 @@ struct ConfigFile {
     api_token: Option<String>,
     http_timeout: Option<u64>,
     model: String,
+    vision_models: Option<Vec<String>>,
+    user_models: Option<Vec<String>>,
     system_message: Option<String>,
+    personas: Option<HashMap<String, PersonaConfig>>,
+    personas_dir: Option<PathBuf>,
+    variables: Option<HashMap<String, String>>,
     reasoning_effort: Option<String>,
     reasoning_budget: Option<i64>,
     verbosity: Option<String>,
     min_history_tokens: Option<usize>,
     max_history_tokens: usize,
+    summarize: Option<bool>,
+    summary_model: Option<String>,
+    max_summary_tokens: Option<usize>,
+    max_attachment_size: Option<usize>,
+    reactions: Option<HashMap<String, String>>,
+    feedback_log: Option<PathBuf>,
+    upload_threshold: Option<usize>,
+    max_message_length: Option<usize>,
+    message_styling: Option<bool>,
+    xhtml_im: Option<bool>,
+    state_dir: Option<PathBuf>,
+    mam_max_age: Option<u64>,
+    restore_history: Option<bool>,
+    component: Option<ComponentConfig>,
+    bots: Option<HashMap<String, BotConfig>>,
+    accounts: Option<HashMap<String, AccountConfig>>,
+}
+
+/// XEP-0114 external component connection.
+#[derive(Debug, Clone, serde::Deserialize)]
+pub struct ComponentConfig {
+    /// Address of the server's component port, e.g. `localhost:5347`.
+    pub address: String,
+}
+
+/// Bot address served by the component, or settings of an additional account.
+#[derive(Debug, Clone, serde::Deserialize)]
+pub struct BotConfig {
+    pub model: Option<String>,
+    pub system_message: Option<String>,
+    pub nickname: Option<String>,
+    pub avatar: Option<PathBuf>,
+}
+
+/// Named persona users can switch to with `/persona`.
+#[derive(Debug, Clone, serde::Deserialize)]
+pub struct PersonaConfig {
+    /// System message, read from `<personas_dir>/<name>.txt` or `.md` if not set.
+    pub system_message: Option<String>,
+    pub model: Option<String>,
+    pub reasoning_effort: Option<String>,
+}
+
+/// Additional client account served by the same process.
+#[derive(Debug, Clone, serde::Deserialize)]
+pub struct AccountConfig {
+    pub jid: String,
+    pub password: String,
+    #[serde(flatten)]
+    pub bot: BotConfig,
+}
+
+/// Action triggered by a reaction to a bot message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReactionAction {
+    /// Record positive feedback.
+    Like,
+    /// Record negative feedback.
+    Dislike,
+    /// Ask the same question again.
+    Regenerate,
+    /// Ask for a shorter version of the answer.
+    Shorter,
+}
+
+impl FromStr for ReactionAction {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "like" => Ok(ReactionAction::Like),
+            "dislike" => Ok(ReactionAction::Dislike),
+            "regenerate" => Ok(ReactionAction::Regenerate),
+            "shorter" => Ok(ReactionAction::Shorter),
+            _ => Err(anyhow!("Unsupported reaction action in config: {}", s)),
+        }
+    }
+}
+
+/// Reactions mapping used if none is configured.
+pub fn default_reactions() -> HashMap<String, ReactionAction> {
+    HashMap::from([
+        ("👍".to_string(), ReactionAction::Like),
+        ("👎".to_string(), ReactionAction::Dislike),
+        ("🔁".to_string(), ReactionAction::Regenerate),
+    ])
+}
+
+/// How the connection to the server is encrypted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TlsMode {
+    /// Plain connection upgraded with STARTTLS.
+    StartTls,
+    /// XEP-0368 TLS from the start.
+    Direct,
+    /// No encryption, only allowed to loopback addresses.
+    NoneForLocalhost,
+}
+
+impl FromStr for TlsMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "starttls" => Ok(TlsMode::StartTls),
+            "direct" => Ok(TlsMode::Direct),
+            "none-for-localhost" => Ok(TlsMode::NoneForLocalhost),
+            _ => Err(anyhow!("Unsupported TLS mode in config: {}", s)),
+        }
+    }
+}
+
+/// XMPP server connection settings.
+#[derive(Debug, Clone)]
+pub struct ServerSettings {
+    /// Server host, resolved via SRV records from the JID domain if not set.
+    pub host: Option<String>,
+    pub port: Option<u16>,
+    pub tls: TlsMode,
+    /// PEM file with CA certificates to trust instead of the bundled Mozilla ones.
+    pub ca_certificate: Option<PathBuf>,
+    /// SHA-256 fingerprint of the only server certificate to accept.
+    pub pinned_certificate: Option<String>,
+}
+
+/// Avatar image.
+#[derive(Debug, Clone)]
+pub struct Avatar {
+    pub data: Vec<u8>,
+    pub content_type: String,
+    /// SHA-1 of the image.
+    pub hash: [u8; 20],
+}
+
+impl Avatar {
+    /// Load PNG, JPEG, GIF or WebP image from the file.
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let content_type = match path.extension().and_then(|ext| ext.to_str()) {
+            Some("png") => "image/png",
+            Some("jpg" | "jpeg") => "image/jpeg",
+            Some("gif") => "image/gif",
+            Some("webp") => "image/webp",
+            _ => {
+                return Err(anyhow!(
+                    "Unsupported avatar format {}, expected png, jpeg, gif or webp",
+                    path.display(),
+                ))
+            }
+        };
+
+        let data =
+            fs::read(path).with_context(|| anyhow!("Failed to read avatar {}", path.display()))?;
+        if data.len() > MAX_AVATAR_SIZE {
+            return Err(anyhow!(
+                "Avatar {} is larger than {MAX_AVATAR_SIZE} bytes",
+                path.display(),
+            ));
+        }
+
+        Ok(Self {
+            hash: Sha1::digest(&data).into(),
+            data,
+            content_type: content_type.to_string(),
+        })
+    }
+}
+
+/// Nickname and avatar of the bot.
+#[derive(Debug, Clone, Default)]
+pub struct Profile {
+    pub nickname: Option<String>,
+    pub avatar: Option<Avatar>,
+}
+
+impl Profile {
+    pub fn is_empty(&self) -> bool {
+        self.nickname.is_none() && self.avatar.is_none()
+    }
 }
 
 impl ConfigFile {
@@ -70,21 +280,58 @@ impl ConfigFile {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Config {
     pub auth_jid: BareJid,
     pub auth_password: String,
+    pub server: ServerSettings,
+    pub ping_timeout: Duration,
+    pub report_os: bool,
+    pub profile: Profile,
     pub allowed_users: Vec<String>,
+    /// Users denied access even if matching `allowed_users`.
+    pub denied_users: Vec<String>,
+    pub admins: Vec<BareJid>,
+    /// Forward access requests from unknown users to admins.
+    pub admin_approval: bool,
+    /// Tell unknown users once that they are not authorised.
+    pub unauthorized_reply: bool,
     pub api_url: String,
     pub api_options: jutella::ApiOptions,
     pub api_version: Option<String>,
     pub api_auth: jutella::Auth,
     pub http_timeout: Duration,
     pub model: String,
+    pub vision_models: Vec<String>,
+    /// Models users may choose in their preferences.
+    pub user_models: Vec<String>,
     pub system_message: Option<String>,
+    /// Named personas, sorted by name.
+    pub personas: BTreeMap<String, Persona>,
+    /// Custom variables of system message templates.
+    pub variables: BTreeMap<String, String>,
     pub verbosity: Option<String>,
     pub min_history_tokens: Option<usize>,
     pub max_history_tokens: usize,
+    /// Summarisation of the turns falling out of the context, if enabled.
+    pub summary: Option<SummaryConfig>,
+    pub max_attachment_size: usize,
+    pub reactions: HashMap<String, ReactionAction>,
+    pub feedback_log: Option<PathBuf>,
+    pub upload_threshold: Option<usize>,
+    pub max_message_length: Option<usize>,
+    pub message_styling: bool,
+    pub xhtml_im: bool,
+    pub state_dir: Option<PathBuf>,
+    pub mam_max_age: Duration,
+    pub restore_history: bool,
+    pub component: Option<ComponentConfig>,
+    /// Bots served by the component, by their local part.
+    pub bots: Vec<(String, BotConfig)>,
+    /// Additional client accounts, sorted by name.
+    pub accounts: Vec<(String, AccountConfig)>,
+    /// Name of the bot or account this config was derived for.
+    pub bot: Option<String>,
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -106,12 +353,24 @@ impl FromStr for ApiType {
 }
 
 impl Config {
-    pub fn load() -> anyhow::Result<Self> {
-        let Args { config } = Args::parse();
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
         let ConfigFile {
             jid,
             password,
+            server_host,
+            server_port,
+            tls,
+            tls_ca_certificate,
+            tls_pinned_certificate,
+            ping_timeout,
+            report_os,
+            nickname,
+            avatar,
             allowed_users,
+            denied_users,
+            admins,
+            admin_approval,
+            unauthorized_reply,
             api,
             api_url,
             api_version,
@@ -119,16 +378,100 @@ impl Config {
             api_token,
             http_timeout,
             model,
+            vision_models,
+            user_models,
             system_message,
+            personas,
+            personas_dir,
+            variables,
             reasoning_effort,
             reasoning_budget,
             verbosity,
             min_history_tokens,
             max_history_tokens,
-        } = ConfigFile::load(config)?;
+            summarize,
+            summary_model,
+            max_summary_tokens,
+            max_attachment_size,
+            reactions,
+            feedback_log,
+            upload_threshold,
+            max_message_length,
+            message_styling,
+            xhtml_im,
+            state_dir,
+            mam_max_age,
+            restore_history,
+            component,
+            bots,
+            accounts,
+        } = ConfigFile::load(path.to_path_buf())?;
 
         let auth_jid = BareJid::new(&jid).context("Invalid auth JID")?;
 
+        let server = ServerSettings {
+            host: server_host,
+            port: server_port,
+            tls: tls
+                .as_deref()
+                .map_or(Ok(TlsMode::StartTls), TlsMode::from_str)?,
+            ca_certificate: tls_ca_certificate,
+            pinned_certificate: tls_pinned_certificate,
+        };
+
+        let mut bots = bots.unwrap_or_default().into_iter().collect::<Vec<_>>();
+        bots.sort_by(|(a, _), (b, _)| a.cmp(b));
+
+        match component {
+            Some(_) => {
+                if auth_jid.node().is_some() {
+                    return Err(anyhow!(
+                        "Component `jid` must be a domain, e.g. bot.example.com"
+                    ));
+                }
+                if bots.is_empty() {
+                    return Err(anyhow!(
+                        "At least one bot must be configured in component mode"
+                    ));
+                }
+                for (name, _) in &bots {
+                    BareJid::new(&format!("{name}@{auth_jid}"))
+                        .with_context(|| anyhow!("Invalid bot name {name}"))?;
+                }
+            }
+            None if !bots.is_empty() => {
+                return Err(anyhow!("`bots` are only supported in component mode"));
+            }
+            None => {}
+        }
+
+        let mut accounts = accounts.unwrap_or_default().into_iter().collect::<Vec<_>>();
+        accounts.sort_by(|(a, _), (b, _)| a.cmp(b));
+
+        if component.is_some() && !accounts.is_empty() {
+            return Err(anyhow!("`accounts` are not supported in component mode"));
+        }
+        for (name, account) in &accounts {
+            let jid = BareJid::new(&account.jid)
+                .with_context(|| anyhow!("Invalid JID of account {name}"))?;
+            if jid.node().is_none() || jid == auth_jid {
+                return Err(anyhow!(
+                    "Account {name} must have its own JID, e.g. bot@example.com"
+                ));
+            }
+        }
+
+        let admins = admins
+            .unwrap_or_default()
+            .iter()
+            .map(|jid| BareJid::new(jid).with_context(|| anyhow!("Invalid admin JID {jid}")))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+
+        let admin_approval = admin_approval.unwrap_or_default();
+        if admin_approval && admins.is_empty() {
+            return Err(anyhow!("`admin_approval` requires `admins`"));
+        }
+
         let api_auth = match (api_key, api_token) {
             (Some(api_key), None) => jutella::Auth::ApiKey(api_key),
             (None, Some(token)) => jutella::Auth::Token(token),
@@ -143,45 +486,365 @@ impl Config {
             .as_deref()
             .map_or(Ok(ApiType::OpenAi), ApiType::from_str)?;
 
-        let api_options = match (api_type, reasoning_effort, reasoning_budget) {
-            (ApiType::OpenAi, effort, None) => jutella::ApiOptions::OpenAi {
-                reasoning_effort: effort,
-            },
-            (ApiType::OpenRouter, None, None) => {
-                jutella::ApiOptions::OpenRouter { reasoning: None }
-            }
-            (ApiType::OpenRouter, Some(effort), None) => jutella::ApiOptions::OpenRouter {
-                reasoning: Some(jutella::ReasoningSettings::Effort(effort)),
-            },
-            (ApiType::OpenRouter, None, Some(budget)) => jutella::ApiOptions::OpenRouter {
-                reasoning: Some(jutella::ReasoningSettings::Budget(budget)),
-            },
-            _ => {
-                return Err(anyhow!(
-                    "Only one of `reasoning_effort` or `reasoning_budget` can be supplied. \
-                     `reasoning_budget` is only supported by OpenRouter API."
-                ))
-            }
-        };
+        let api_options = api_options(api_type, reasoning_effort, reasoning_budget)?;
+        let personas = load_personas(
+            personas.unwrap_or_default(),
+            personas_dir.as_deref(),
+            api_type,
+        )?;
 
         let http_timeout = http_timeout
             .map(Duration::from_secs)
             .unwrap_or(DEFAULT_HTTP_TIMEOUT);
 
+        let reactions = match reactions {
+            Some(reactions) => reactions
+                .into_iter()
+                .map(|(emoji, action)| Ok((emoji, ReactionAction::from_str(&action)?)))
+                .collect::<anyhow::Result<_>>()?,
+            None => default_reactions(),
+        };
+
+        let ping_timeout = ping_timeout
+            .map(Duration::from_secs)
+            .unwrap_or(DEFAULT_PING_TIMEOUT);
+
+        let mam_max_age = mam_max_age
+            .map(Duration::from_secs)
+            .unwrap_or(DEFAULT_MAM_MAX_AGE);
+
+        let summary = summarize.unwrap_or_default().then(|| SummaryConfig {
+            model: summary_model,
+            max_tokens: max_summary_tokens.unwrap_or(DEFAULT_MAX_SUMMARY_TOKENS),
+        });
+
         Ok(Self {
             auth_jid,
             auth_password: password,
+            server,
+            ping_timeout,
+            report_os: report_os.unwrap_or_default(),
+            profile: Profile {
+                nickname,
+                avatar: avatar.as_deref().map(Avatar::load).transpose()?,
+            },
             allowed_users,
+            denied_users: denied_users.unwrap_or_default(),
+            admins,
+            admin_approval,
+            unauthorized_reply: unauthorized_reply.unwrap_or_default(),
             api_url,
             api_options,
             api_version,
             api_auth,
             http_timeout,
             model,
+            vision_models: vision_models.unwrap_or_default(),
+            user_models: user_models.unwrap_or_default(),
             system_message,
+            personas,
+            variables: variables.unwrap_or_default().into_iter().collect(),
             verbosity,
             min_history_tokens,
             max_history_tokens,
+            summary,
+            max_attachment_size: max_attachment_size.unwrap_or(DEFAULT_MAX_ATTACHMENT_SIZE),
+            reactions,
+            feedback_log,
+            upload_threshold,
+            max_message_length,
+            message_styling: message_styling.unwrap_or_default(),
+            xhtml_im: xhtml_im.unwrap_or_default(),
+            state_dir,
+            mam_max_age,
+            restore_history: restore_history.unwrap_or_default(),
+            component,
+            bots,
+            accounts,
+            bot: None,
+        })
+    }
+
+    /// Config of a bot served by the component, with its own JID, model and system message.
+    pub fn for_bot(&self, name: &str) -> anyhow::Result<Self> {
+        let (_, bot) = self
+            .bots
+            .iter()
+            .find(|(bot, _)| bot == name)
+            .ok_or_else(|| anyhow!("Bot {name} is not configured"))?;
+
+        let mut config = self.clone();
+        config.auth_jid = BareJid::new(&format!("{name}@{}", self.auth_jid))
+            .with_context(|| anyhow!("Invalid bot name {name}"))?;
+        config.apply_bot(name, bot)?;
+
+        Ok(config)
+    }
+
+    /// Config of an additional account, with its own JID, password, model and system message.
+    pub fn for_account(&self, name: &str) -> anyhow::Result<Self> {
+        let (_, account) = self
+            .accounts
+            .iter()
+            .find(|(account, _)| account == name)
+            .ok_or_else(|| anyhow!("Account {name} is not configured"))?;
+
+        let mut config = self.clone();
+        config.auth_jid =
+            BareJid::new(&account.jid).with_context(|| anyhow!("Invalid JID of account {name}"))?;
+        config.auth_password = account.password.clone();
+        config.apply_bot(name, &account.bot)?;
+
+        Ok(config)
+    }
+
+    /// Override the settings with the bot ones. Each bot keeps its state in a subdirectory.
+    fn apply_bot(&mut self, name: &str, bot: &BotConfig) -> anyhow::Result<()> {
+        let config = self;
+        config.bot = Some(name.to_string());
+        config.state_dir = config
+            .state_dir
+            .as_ref()
+            .map(|state_dir| state_dir.join(name));
+        if let Some(model) = &bot.model {
+            config.model = model.clone();
+        }
+        if let Some(system_message) = &bot.system_message {
+            config.system_message = Some(system_message.clone());
+        }
+        if let Some(nickname) = &bot.nickname {
+            config.profile.nickname = Some(nickname.clone());
+        }
+        if let Some(avatar) = &bot.avatar {
+            config.profile.avatar = Some(Avatar::load(avatar)?);
+        }
+
+        Ok(())
+    }
+}
+
+fn api_options(
+    api_type: ApiType,
+    reasoning_effort: Option<String>,
+    reasoning_budget: Option<i64>,
+) -> anyhow::Result<jutella::ApiOptions> {
+    match (api_type, reasoning_effort, reasoning_budget) {
+        (ApiType::OpenAi, effort, None) => Ok(jutella::ApiOptions::OpenAi {
+            reasoning_effort: effort,
+        }),
+        (ApiType::OpenRouter, None, None) => {
+            Ok(jutella::ApiOptions::OpenRouter { reasoning: None })
+        }
+        (ApiType::OpenRouter, Some(effort), None) => Ok(jutella::ApiOptions::OpenRouter {
+            reasoning: Some(jutella::ReasoningSettings::Effort(effort)),
+        }),
+        (ApiType::OpenRouter, None, Some(budget)) => Ok(jutella::ApiOptions::OpenRouter {
+            reasoning: Some(jutella::ReasoningSettings::Budget(budget)),
+        }),
+        _ => Err(anyhow!(
+            "Only one of `reasoning_effort` or `reasoning_budget` can be supplied. \
+             `reasoning_budget` is only supported by OpenRouter API."
+        )),
+    }
+}
+
+/// Load the personas from the config and prompt files `<name>.txt` or `<name>.md` in `dir`.
+fn load_personas(
+    personas: HashMap<String, PersonaConfig>,
+    dir: Option<&Path>,
+    api_type: ApiType,
+) -> anyhow::Result<BTreeMap<String, Persona>> {
+    let mut prompts = BTreeMap::new();
+
+    if let Some(dir) = dir {
+        let entries = fs::read_dir(dir)
+            .with_context(|| anyhow!("Failed to read personas directory {}", dir.display()))?;
+
+        for entry in entries {
+            let path = entry
+                .with_context(|| anyhow!("Failed to read personas directory {}", dir.display()))?
+                .path();
+            let is_prompt = path
+                .extension()
+                .is_some_and(|ext| ext == "txt" || ext == "md");
+            let Some(name) = path.file_stem().and_then(|name| name.to_str()) else {
+                continue;
+            };
+            if !is_prompt || !path.is_file() {
+                continue;
+            }
+
+            let prompt = fs::read_to_string(&path)
+                .with_context(|| anyhow!("Failed to read persona {}", path.display()))?;
+            prompts.insert(name.to_string(), prompt.trim().to_string());
+        }
+    }
+
+    let mut names = prompts.keys().cloned().collect::<Vec<_>>();
+    names.extend(personas.keys().cloned());
+
+    names
+        .into_iter()
+        .map(|name| {
+            if name == "default" || name.contains(char::is_whitespace) {
+                return Err(anyhow!("Invalid persona name `{name}`"));
+            }
+
+            let persona = personas.get(&name);
+            let system_message = persona
+                .and_then(|persona| persona.system_message.clone())
+                .or_else(|| prompts.get(&name).cloned());
+            if system_message.is_none() {
+                return Err(anyhow!(
+                    "Persona `{name}` has neither `system_message` nor a prompt file"
+                ));
+            }
+            let api_options = persona
+                .and_then(|persona| persona.reasoning_effort.clone())
+                .map(|effort| api_options(api_type, Some(effort), None))
+                .transpose()?;
+
+            Ok((
+                name,
+                Persona {
+                    system_message,
+                    model: persona.and_then(|persona| persona.model.clone()),
+                    api_options,
+                },
+            ))
         })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::process;
+
+    const BASE: &str = r#"
+        jid = "bot@example.com"
+        password = "secret"
+        allowed_users = ["*@example.com"]
+        api_url = "https://api.example.com/v1/"
+        api_key = "key"
+        model = "base-model"
+        max_history_tokens = 1000
+        state_dir = "/var/lib/jutella-xmpp"
+    "#;
+
+    /// Load the config from a temporary file.
+    fn load(name: &str, config: &str) -> anyhow::Result<Config> {
+        let path = std::env::temp_dir().join(format!("jutella-xmpp-{}-{name}.toml", process::id()));
+        fs::write(&path, config).unwrap();
+        let config = Config::load(&path);
+        fs::remove_file(&path).unwrap();
+        config
+    }
+
+    #[test]
+    fn account_overrides() {
+        let config = load(
+            "account_overrides",
+            &format!(
+                r#"{BASE}
+                [accounts.support]
+                jid = "support@example.com"
+                password = "support-secret"
+                model = "support-model"
+                nickname = "Support"
+                "#
+            ),
+        )
+        .unwrap();
+
+        let account = config.for_account("support").unwrap();
+        assert_eq!(account.auth_jid.as_str(), "support@example.com");
+        assert_eq!(account.auth_password, "support-secret");
+        assert_eq!(account.model, "support-model");
+        assert_eq!(account.profile.nickname.as_deref(), Some("Support"));
+        assert_eq!(account.bot.as_deref(), Some("support"));
+        assert_eq!(account.allowed_users, config.allowed_users);
+        assert_eq!(config.model, "base-model");
+        assert_eq!(config.bot, None);
+    }
+
+    #[test]
+    fn state_dir_per_account() {
+        let config = load(
+            "state_dir_per_account",
+            &format!(
+                r#"{BASE}
+                [accounts.a]
+                jid = "a@example.com"
+                password = "a"
+
+                [accounts.b]
+                jid = "b@example.com"
+                password = "b"
+                "#
+            ),
+        )
+        .unwrap();
+
+        let a = config.for_account("a").unwrap();
+        let b = config.for_account("b").unwrap();
+        assert_eq!(
+            config.state_dir,
+            Some(PathBuf::from("/var/lib/jutella-xmpp"))
+        );
+        assert_eq!(a.state_dir, Some(PathBuf::from("/var/lib/jutella-xmpp/a")));
+        assert_eq!(b.state_dir, Some(PathBuf::from("/var/lib/jutella-xmpp/b")));
+        assert!(config.for_account("c").is_err());
+    }
+
+    #[test]
+    fn state_dir_per_bot() {
+        let config = load(
+            "state_dir_per_bot",
+            r#"
+            jid = "bots.example.com"
+            password = "secret"
+            allowed_users = ["*@example.com"]
+            api_url = "https://api.example.com/v1/"
+            api_key = "key"
+            model = "base-model"
+            max_history_tokens = 1000
+            state_dir = "/var/lib/jutella-xmpp"
+
+            [component]
+            address = "localhost:5347"
+
+            [bots.helper]
+            model = "helper-model"
+            "#,
+        )
+        .unwrap();
+
+        let bot = config.for_bot("helper").unwrap();
+        assert_eq!(bot.auth_jid.as_str(), "helper@bots.example.com");
+        assert_eq!(bot.model, "helper-model");
+        assert_eq!(
+            bot.state_dir,
+            Some(PathBuf::from("/var/lib/jutella-xmpp/helper"))
+        );
+        assert!(config.for_bot("other").is_err());
+    }
+
+    #[test]
+    fn account_jid_validated() {
+        for (name, jid) in [
+            ("account_jid_invalid", "@example.com"),
+            ("account_jid_domain", "example.com"),
+            ("account_jid_main", "bot@example.com"),
+        ] {
+            let config = format!(
+                r#"{BASE}
+                [accounts.other]
+                jid = "{jid}"
+                password = "other"
+                "#
+            );
+            assert!(load(name, &config).is_err(), "{jid} accepted");
+        }
     }
 }
@@ -0,0 +1,321 @@
+// Copyright (c) 2024 Dmitry Markin
+//
+// SPDX-License-Identifier: MIT
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! Downloading of image attachments.
+
+use aes_gcm::{
+    aead::{consts::U16, Aead, KeyInit},
+    aes::Aes256,
+    Aes256Gcm, AesGcm, Nonce,
+};
+use anyhow::{anyhow, Context as _};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine as _};
+use futures::StreamExt;
+use std::{net::IpAddr, time::Duration};
+
+/// Image MIME types accepted by vision models.
+const IMAGE_MIME_TYPES: &[(&str, &[&str])] = &[
+    ("image/png", &["png"]),
+    ("image/jpeg", &["jpg", "jpeg"]),
+    ("image/webp", &["webp"]),
+    ("image/gif", &["gif"]),
+];
+
+// AES-256-GCM with 16-byte IV used by older OMEMO clients.
+type Aes256Gcm16 = AesGcm<Aes256, U16>;
+
+/// Download the image attachment and return it as a `data:` URL.
+///
+/// Supports `https://` URLs and `aesgcm://` URLs of OMEMO encrypted uploads. The URLs come from
+/// the users, so loopback, private and link-local addresses are refused. Host names resolving to
+/// such addresses and redirects to them are not detected: run the bot where the internal
+/// services are not reachable if untrusted users can talk to it.
+pub async fn download_image(
+    client: &reqwest::Client,
+    url: &str,
+    max_size: usize,
+    timeout: Duration,
+) -> anyhow::Result<String> {
+    let (download_url, key) = match url.strip_prefix("aesgcm://") {
+        Some(rest) => {
+            let (location, fragment) = rest
+                .split_once('#')
+                .ok_or_else(|| anyhow!("`aesgcm` URL without a key"))?;
+            (format!("https://{location}"), Some(decode_hex(fragment)?))
+        }
+        None if url.starts_with("https://") => (url.to_string(), None),
+        None => {
+            return Err(anyhow!(
+                "only `https` and `aesgcm` attachment URLs are supported"
+            ))
+        }
+    };
+
+    check_host(&download_url)?;
+
+    let response = client
+        .get(&download_url)
+        .timeout(timeout)
+        .send()
+        .await
+        .and_then(|response| response.error_for_status())
+        .map_err(|error| anyhow!("failed to download attachment: {}", error.without_url()))?;
+
+    if response
+        .content_length()
+        .is_some_and(|length| length as usize > max_size)
+    {
+        return Err(anyhow!("attachment is larger than {max_size} bytes"));
+    }
+
+    // Encrypted uploads are served as `application/octet-stream`, so we have to rely on the file
+    // extension.
+    let mime_type = match key {
+        Some(_) => None,
+        None => response
+            .headers()
+            .get(reqwest::header::CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.split(';').next())
+            .map(|value| value.trim().to_ascii_lowercase()),
+    };
+    let mime_type = mime_type
+        .or_else(|| mime_type_from_path(&download_url))
+        .ok_or_else(|| anyhow!("unknown attachment type"))?;
+
+    if !IMAGE_MIME_TYPES.iter().any(|(mime, _)| *mime == mime_type) {
+        return Err(anyhow!(
+            "unsupported attachment type `{mime_type}`, only images are supported"
+        ));
+    }
+
+    let mut data = Vec::new();
+    let mut stream = response.bytes_stream();
+    while let Some(chunk) = stream.next().await {
+        let chunk = chunk
+            .map_err(|error| anyhow!("failed to download attachment: {}", error.without_url()))?;
+        if data.len() + chunk.len() > max_size {
+            return Err(anyhow!("attachment is larger than {max_size} bytes"));
+        }
+        data.extend_from_slice(&chunk);
+    }
+
+    let data = match key {
+        Some(key) => decrypt(&key, &data)?,
+        None => data,
+    };
+
+    Ok(format!("data:{mime_type};base64,{}", BASE64.encode(data)))
+}
+
+/// Refuse URLs pointing to the local host or the local network.
+fn check_host(url: &str) -> anyhow::Result<()> {
+    let url = reqwest::Url::parse(url).map_err(|_| anyhow!("invalid attachment URL"))?;
+    let host = url
+        .host_str()
+        .ok_or_else(|| anyhow!("attachment URL without a host"))?;
+
+    if host.eq_ignore_ascii_case("localhost") || host.to_ascii_lowercase().ends_with(".localhost") {
+        return Err(anyhow!("attachments from local addresses are not allowed"));
+    }
+
+    let Ok(address) = host
+        .trim_start_matches('[')
+        .trim_end_matches(']')
+        .parse::<IpAddr>()
+    else {
+        return Ok(());
+    };
+
+    let local = match address {
+        IpAddr::V4(address) => {
+            address.is_loopback()
+                || address.is_private()
+                || address.is_link_local()
+                || address.is_unspecified()
+                || address.is_broadcast()
+        }
+        IpAddr::V6(address) => match address.to_ipv4_mapped() {
+            Some(address) => {
+                address.is_loopback() || address.is_private() || address.is_link_local()
+            }
+            None => {
+                address.is_loopback()
+                    || address.is_unspecified()
+                    || address.is_unique_local()
+                    || address.is_unicast_link_local()
+            }
+        },
+    };
+
+    if local {
+        Err(anyhow!("attachments from local addresses are not allowed"))
+    } else {
+        Ok(())
+    }
+}
+
+fn mime_type_from_path(url: &str) -> Option<String> {
+    let path = url.split(['?', '#']).next()?;
+    let extension = path.rsplit_once('.')?.1.to_ascii_lowercase();
+
+    IMAGE_MIME_TYPES
+        .iter()
+        .find(|(_, extensions)| extensions.contains(&extension.as_str()))
+        .map(|(mime, _)| mime.to_string())
+}
+
+/// Decrypt `aesgcm` upload. The key fragment is IV (12 or 16 bytes) followed by 32-byte key.
+fn decrypt(key: &[u8], data: &[u8]) -> anyhow::Result<Vec<u8>> {
+    let failed = |_| anyhow!("failed to decrypt attachment");
+
+    match key.len() {
+        44 => {
+            let (iv, key) = key.split_at(12);
+            let iv: [u8; 12] = iv.try_into().expect("length checked above; qed");
+            Aes256Gcm::new_from_slice(key)
+                .expect("length checked above; qed")
+                .decrypt(&Nonce::from(iv), data)
+                .map_err(failed)
+        }
+        48 => {
+            let (iv, key) = key.split_at(16);
+            let iv: [u8; 16] = iv.try_into().expect("length checked above; qed");
+            Aes256Gcm16::new_from_slice(key)
+                .expect("length checked above; qed")
+                .decrypt(&Nonce::<U16>::from(iv), data)
+                .map_err(failed)
+        }
+        _ => Err(anyhow!("invalid attachment key length")),
+    }
+}
+
+fn decode_hex(hex: &str) -> anyhow::Result<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return Err(anyhow!("invalid attachment key"));
+    }
+
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| {
+            hex.get(i..i + 2)
+                .and_then(|byte| u8::from_str_radix(byte, 16).ok())
+                .context("invalid attachment key")
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hex_key() {
+        assert_eq!(
+            decode_hex("00ff7Fa0").unwrap(),
+            vec![0x00, 0xff, 0x7f, 0xa0]
+        );
+        assert_eq!(decode_hex("").unwrap(), Vec::<u8>::new());
+        assert!(decode_hex("abc").is_err());
+        assert!(decode_hex("zz").is_err());
+        // Multi-byte characters must not panic.
+        assert!(decode_hex("ä0").is_err());
+    }
+
+    #[test]
+    fn decrypt_12_byte_iv() {
+        let iv = [1u8; 12];
+        let key = [2u8; 32];
+        let encrypted = Aes256Gcm::new_from_slice(&key)
+            .unwrap()
+            .encrypt(&Nonce::from(iv), b"image".as_slice())
+            .unwrap();
+
+        let fragment = [iv.as_slice(), key.as_slice()].concat();
+        assert_eq!(decrypt(&fragment, &encrypted).unwrap(), b"image");
+    }
+
+    #[test]
+    fn decrypt_16_byte_iv() {
+        let iv = [3u8; 16];
+        let key = [4u8; 32];
+        let encrypted = Aes256Gcm16::new_from_slice(&key)
+            .unwrap()
+            .encrypt(&Nonce::<U16>::from(iv), b"image".as_slice())
+            .unwrap();
+
+        let fragment = [iv.as_slice(), key.as_slice()].concat();
+        assert_eq!(decrypt(&fragment, &encrypted).unwrap(), b"image");
+    }
+
+    #[test]
+    fn decrypt_failures() {
+        let fragment = [[1u8; 12].as_slice(), [2u8; 32].as_slice()].concat();
+        assert!(decrypt(&fragment, b"not encrypted with this key").is_err());
+        assert!(decrypt(&[0u8; 40], b"data").is_err());
+    }
+
+    #[test]
+    fn mime_type_from_extension() {
+        assert_eq!(
+            mime_type_from_path("https://upload.example.org/a/photo.PNG").as_deref(),
+            Some("image/png"),
+        );
+        assert_eq!(
+            mime_type_from_path("https://upload.example.org/a/photo.jpeg?token=1#key").as_deref(),
+            Some("image/jpeg"),
+        );
+        assert_eq!(
+            mime_type_from_path("https://upload.example.org/a/document.pdf"),
+            None,
+        );
+        assert_eq!(
+            mime_type_from_path("https://upload.example.org/a/photo"),
+            None
+        );
+    }
+
+    #[test]
+    fn local_hosts_refused() {
+        for url in [
+            "https://localhost/a.png",
+            "https://api.LOCALHOST/a.png",
+            "https://127.0.0.1/a.png",
+            "https://10.1.2.3:8443/a.png",
+            "https://192.168.0.1/a.png",
+            "https://169.254.169.254/latest/meta-data",
+            "https://[::1]/a.png",
+            "https://[fd00::1]/a.png",
+            "https://[::ffff:127.0.0.1]/a.png",
+        ] {
+            assert!(check_host(url).is_err(), "{url}");
+        }
+
+        for url in [
+            "https://upload.example.org/a.png",
+            "https://93.184.215.14/a.png",
+            "https://[2001:db8::1]/a.png",
+        ] {
+            assert!(check_host(url).is_ok(), "{url}");
+        }
+    }
+}
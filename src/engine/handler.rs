@@ -22,15 +22,58 @@
 
 //! Chatbot chat handler.
 
-use crate::message::{RequestMessage, ResponseMessage};
+use crate::{
+    engine::{
+        attachments::download_image,
+        personalize,
+        summary::{summary_request, summary_system_message, RunningSummary},
+        template::{render, Variables},
+        transcript::{Transcript, TranscriptTurn},
+        Config, Personalized,
+    },
+    message::{ConversationTurn, RequestMessage, ResponseMessage},
+    preferences::Preferences,
+};
 use anyhow::anyhow;
-use jutella::{ApiOptions, Auth, ChatClient, ChatClientConfig, Completion, TokenUsage};
-use std::{sync::Arc, time::Duration};
-use tokio::sync::mpsc::{Receiver, Sender};
+use jutella::{
+    ApiOptions, Auth, ChatClient, ChatClientConfig, Completion, Content, ContentPart, ModelConfig,
+    TokenUsage,
+};
+use std::{fmt, sync::Arc, time::Duration};
+use tokio::sync::{
+    mpsc::{Receiver, Sender},
+    watch,
+};
 
 // Log target for this file.
 const LOG_TARGET: &str = "jutella::handler";
 
+/// Error answering a request.
+#[derive(Debug)]
+enum RequestError {
+    /// The model doesn't accept images.
+    NoVision(String),
+    /// Failed to download the attachment.
+    Attachment(anyhow::Error),
+    /// The chatbot API request failed.
+    Api(jutella::Error),
+}
+
+impl fmt::Display for RequestError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RequestError::NoVision(model) => write!(
+                f,
+                "Model `{model}` does not support images, only text requests can be processed"
+            ),
+            RequestError::Attachment(error) => write!(f, "{error}"),
+            RequestError::Api(error) => write!(f, "{error}"),
+        }
+    }
+}
+
+impl std::error::Error for RequestError {}
+
 /// Configuration of [`ChatbotHandler`]
 // Can't implement `Debug` due to `tiktoken_rs::CoreBPE` not implementing it.
 pub struct ChatbotHandlerConfig {
@@ -41,10 +84,20 @@ pub struct ChatbotHandlerConfig {
     pub auth: Auth,
     pub http_timeout: Duration,
     pub model: String,
+    pub vision: bool,
     pub system_message: Option<String>,
     pub verbosity: Option<String>,
     pub min_history_tokens: Option<usize>,
     pub max_history_tokens: usize,
+    pub max_attachment_size: usize,
+    pub history: Vec<ConversationTurn>,
+    /// Engine config the handler was created with, to apply changed preferences.
+    pub config: Config,
+    pub preferences: Preferences,
+    /// Publishes the running summary of the conversation to the engine.
+    pub summary_tx: watch::Sender<Option<String>>,
+    /// Publishes the transcript of the conversation to the engine.
+    pub transcript_tx: watch::Sender<Transcript>,
     pub reqwest_client: reqwest::Client,
     pub tokenizer: Arc<tiktoken_rs::CoreBPE>,
     pub response_tx: Sender<ResponseMessage>,
@@ -55,12 +108,24 @@ pub struct ChatbotHandlerConfig {
 pub struct ChatbotHandler {
     jid: String,
     client: ChatClient,
+    vision: bool,
+    /// System message template rendered for every request.
+    system_template: Option<String>,
+    reqwest_client: reqwest::Client,
+    tokenizer: Arc<tiktoken_rs::CoreBPE>,
+    http_timeout: Duration,
+    max_attachment_size: usize,
+    config: Config,
+    preferences: Preferences,
+    summary: RunningSummary,
+    summary_tx: watch::Sender<Option<String>>,
+    transcript_tx: watch::Sender<Transcript>,
     response_tx: Sender<ResponseMessage>,
     request_rx: Receiver<RequestMessage>,
 }
 
 impl ChatbotHandler {
-    pub fn new(config: ChatbotHandlerConfig) -> Result<Self, jutella::Error> {
+    pub fn new(config: ChatbotHandlerConfig) -> anyhow::Result<Self> {
         let ChatbotHandlerConfig {
             jid,
             api_url,
@@ -69,17 +134,31 @@ impl ChatbotHandler {
             auth,
             http_timeout,
             model,
+            vision,
             system_message,
             verbosity,
             min_history_tokens,
             max_history_tokens,
+            max_attachment_size,
+            history,
+            config,
+            preferences,
+            summary_tx,
+            transcript_tx,
             reqwest_client,
             tokenizer,
             response_tx,
             request_rx,
         } = config;
 
-        let client = ChatClient::new_with_client_and_tokenizer(
+        let rendered = system_message.as_deref().map(|template| {
+            render(
+                template,
+                &Variables::new(&jid, None, &model, &config.variables),
+            )
+        });
+
+        let mut client = ChatClient::new_with_client_and_tokenizer(
             ChatClientConfig {
                 api_url,
                 api_options,
@@ -87,25 +166,239 @@ impl ChatbotHandler {
                 auth,
                 http_timeout,
                 model,
-                system_message,
+                system_message: rendered,
                 verbosity,
                 min_history_tokens,
                 max_history_tokens: Some(max_history_tokens),
             },
-            reqwest_client,
-            tokenizer,
+            reqwest_client.clone(),
+            tokenizer.clone(),
         )?;
+        if config.summary.is_some() {
+            client.collect_evicted();
+        }
+
+        let mut restored = Vec::with_capacity(history.len());
+        for ConversationTurn { request, response } in history {
+            restored.push(TranscriptTurn::restored(request.clone(), response.clone()));
+            client.extend_context(request, response);
+        }
+
+        let context_turns = client.context_len();
+        transcript_tx.send_modify(|transcript| {
+            for turn in restored {
+                transcript.push(turn, context_turns);
+            }
+        });
 
         Ok(Self {
             jid,
             client,
+            vision,
+            system_template: system_message,
+            reqwest_client,
+            tokenizer,
+            http_timeout,
+            max_attachment_size,
+            config,
+            preferences,
+            summary: RunningSummary::default(),
+            summary_tx,
+            transcript_tx,
             response_tx,
             request_rx,
         })
     }
 
+    /// Apply the preferences changed by the user, keeping the conversation.
+    fn update_preferences(&mut self, preferences: Preferences) {
+        if preferences == self.preferences {
+            return;
+        }
+
+        let Personalized {
+            model,
+            vision,
+            api_options,
+            verbosity,
+            system_message,
+        } = personalize(&self.config, &preferences);
+
+        tracing::debug!(target: LOG_TARGET, jid = self.jid, model, "preferences updated");
+
+        self.client.set_model_config(ModelConfig {
+            model,
+            api_options,
+            verbosity,
+        });
+        self.vision = vision;
+        self.system_template = system_message;
+        self.preferences = preferences;
+    }
+
+    /// Render the system message template with the current values of the variables.
+    fn render_system_message(&self, user_nick: Option<&str>) -> Option<String> {
+        self.system_template.as_deref().map(|template| {
+            render(
+                template,
+                &Variables::new(
+                    &self.jid,
+                    user_nick,
+                    &self.client.model_config().model,
+                    &self.config.variables,
+                ),
+            )
+        })
+    }
+
+    /// Build the request content, downloading the attachments.
+    async fn request_content(
+        &self,
+        request: &str,
+        attachments: &[String],
+    ) -> Result<Content, RequestError> {
+        if attachments.is_empty() {
+            return Ok(Content::Text(request.to_string()));
+        }
+
+        if !self.vision {
+            return Err(RequestError::NoVision(
+                self.client.model_config().model.clone(),
+            ));
+        }
+
+        let mut parts = Vec::with_capacity(attachments.len() + 1);
+        if !request.is_empty() {
+            parts.push(ContentPart::text(request.to_string()));
+        }
+
+        for url in attachments {
+            let data_url = download_image(
+                &self.reqwest_client,
+                url,
+                self.max_attachment_size,
+                self.http_timeout,
+            )
+            .await
+            .map_err(RequestError::Attachment)?;
+
+            parts.push(ContentPart::image_url(data_url));
+        }
+
+        Ok(Content::Parts(parts))
+    }
+
+    async fn request_completion(
+        &mut self,
+        request: &str,
+        attachments: &[String],
+    ) -> Result<Completion, RequestError> {
+        let content = self.request_content(request, attachments).await?;
+        let completion = self
+            .client
+            .request_completion(content)
+            .await
+            .map_err(RequestError::Api)?;
+
+        let turn = TranscriptTurn::new(
+            self.client.model_config().model.clone(),
+            request.to_string(),
+            attachments.len(),
+            completion.response.clone(),
+            completion.token_usage.tokens_in,
+            completion.token_usage.tokens_out,
+        );
+        let context_turns = self.client.context_len();
+        self.transcript_tx
+            .send_modify(|transcript| transcript.push(turn, context_turns));
+
+        Ok(completion)
+    }
+
+    /// Fold the turns discarded from the context into the running summary.
+    async fn summarize(&mut self) {
+        let Some(config) = &self.config.summary else {
+            return;
+        };
+
+        let turns = self.summary.take_turns(self.client.take_evicted());
+        if turns.is_empty() {
+            return;
+        }
+
+        // Summaries are requested without the conversation context.
+        let max_tokens = config.max_tokens;
+        let ModelConfig {
+            model,
+            api_options,
+            verbosity,
+        } = match &config.model {
+            Some(model) => ModelConfig {
+                model: model.clone(),
+                api_options: self.config.api_options.clone(),
+                verbosity: None,
+            },
+            None => self.client.model_config().clone(),
+        };
+        let client = ChatClient::new_with_client_and_tokenizer(
+            ChatClientConfig {
+                api_url: self.config.api_url.clone(),
+                api_options,
+                api_version: self.config.api_version.clone(),
+                auth: self.config.api_auth.clone(),
+                http_timeout: self.config.http_timeout,
+                model,
+                system_message: Some(summary_system_message(max_tokens)),
+                verbosity,
+                min_history_tokens: None,
+                max_history_tokens: None,
+            },
+            self.reqwest_client.clone(),
+            self.tokenizer.clone(),
+        );
+        let request = summary_request(self.summary.summary(), &turns);
+
+        let result = match client {
+            Ok(mut client) => client.request_completion(request).await,
+            Err(error) => Err(error),
+        };
+
+        match result {
+            Ok(Completion { response, .. }) => {
+                self.summary.set(response, max_tokens, &self.tokenizer);
+                self.client.set_summary(self.summary.system_message());
+                self.summary_tx
+                    .send_replace(self.summary.summary().map(ToOwned::to_owned));
+
+                tracing::debug!(
+                    target: LOG_TARGET,
+                    jid = self.jid,
+                    turns = turns.len(),
+                    "conversation summary updated",
+                );
+            }
+            Err(error) => {
+                tracing::warn!(
+                    target: LOG_TARGET,
+                    jid = self.jid,
+                    turns = turns.len(),
+                    "failed to summarise the conversation: {error}",
+                );
+                self.summary.return_turns(turns);
+            }
+        }
+    }
+
     async fn handle_request(&mut self, req: RequestMessage) -> anyhow::Result<()> {
-        let RequestMessage { jid, request } = req;
+        let RequestMessage {
+            jid,
+            thread,
+            request,
+            attachments,
+            history: _,
+            preferences,
+            user_nick,
+        } = req;
 
         if jid != self.jid {
             tracing::error!(
@@ -118,24 +411,34 @@ impl ChatbotHandler {
             return Err(anyhow!("jid mismatch in request handler"));
         }
 
-        let Completion {
-            response,
-            reasoning: _,
-            token_usage:
-                TokenUsage {
-                    tokens_in,
-                    tokens_in_cached,
-                    tokens_out,
-                    tokens_reasoning,
-                },
-        } = self
-            .client
-            .request_completion(request)
-            .await
-            .unwrap_or_else(|error| {
-                tracing::warn!(target: LOG_TARGET, jid, "error from chatbot API: {error}");
+        self.update_preferences(preferences);
+        let system_message = self.render_system_message(user_nick.as_deref());
+        self.client.set_system_message(system_message);
+
+        let (
+            Completion {
+                response,
+                reasoning: _,
+                token_usage:
+                    TokenUsage {
+                        tokens_in,
+                        tokens_in_cached,
+                        tokens_out,
+                        tokens_reasoning,
+                    },
+            },
+            api_error,
+        ) = match self.request_completion(&request, &attachments).await {
+            Ok(completion) => (completion, false),
+            Err(error) => {
+                let api_error = matches!(error, RequestError::Api(_));
+                if api_error {
+                    tracing::warn!(target: LOG_TARGET, jid, "error from chatbot API: {error}");
+                } else {
+                    tracing::debug!(target: LOG_TARGET, jid, "request not sent: {error}");
+                }
 
-                Completion {
+                let completion = Completion {
                     response: format!("[ERROR] {error}"),
                     reasoning: None,
                     // TODO: return real token count once `jutella` supports it in errors.
@@ -145,18 +448,24 @@ impl ChatbotHandler {
                         tokens_out: 0,
                         tokens_reasoning: None,
                     },
-                }
-            });
+                };
+                (completion, api_error)
+            }
+        };
 
         if self
             .response_tx
             .send(ResponseMessage {
                 jid: jid.clone(),
+                thread,
+                request,
                 response,
+                model: self.client.model_config().model.clone(),
                 tokens_in,
                 tokens_in_cached,
                 tokens_out,
                 tokens_reasoning,
+                api_error,
             })
             .await
             .is_err()
@@ -164,6 +473,8 @@ impl ChatbotHandler {
             return Err(anyhow!("responses channel closed"));
         }
 
+        self.summarize().await;
+
         Ok(())
     }
 
@@ -22,18 +22,37 @@
 
 //! Chatbot Engine.
 
+mod attachments;
 mod handler;
+mod summary;
+mod template;
+mod transcript;
+
+pub use summary::SummaryConfig;
+pub use transcript::ExportFormat;
 
 use crate::{
-    engine::handler::{ChatbotHandler, ChatbotHandlerConfig},
-    message::{RequestMessage, ResponseMessage},
+    engine::{
+        handler::{ChatbotHandler, ChatbotHandlerConfig},
+        transcript::{export, Transcript},
+    },
+    message::{ConversationTurn, EngineCommand, RequestMessage, ResponseMessage},
+    preferences::Preferences,
 };
 use futures::{
     future::{BoxFuture, FutureExt},
     stream::{FuturesUnordered, StreamExt},
 };
-use std::{collections::HashMap, sync::Arc, time::Duration};
-use tokio::sync::mpsc::{channel, error::TrySendError, Receiver, Sender};
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::Arc,
+    time::Duration,
+};
+use tokio::sync::{
+    mpsc::{channel, error::TrySendError, Receiver, Sender},
+    watch,
+};
+use wildmatch::WildMatch;
 
 // Log target for this file.
 const LOG_TARGET: &str = "jutella::engine";
@@ -50,10 +69,27 @@ pub struct Config {
     pub api_auth: jutella::Auth,
     pub http_timeout: Duration,
     pub model: String,
+    /// Wildcard patterns of models accepting image input.
+    pub vision_models: Vec<String>,
     pub system_message: Option<String>,
     pub verbosity: Option<String>,
     pub min_history_tokens: Option<usize>,
     pub max_history_tokens: usize,
+    /// Summarisation of the turns falling out of the context, if enabled.
+    pub summary: Option<SummaryConfig>,
+    pub max_attachment_size: usize,
+    /// Named personas users can switch to.
+    pub personas: BTreeMap<String, Persona>,
+    /// Custom variables of system message templates.
+    pub variables: BTreeMap<String, String>,
+}
+
+/// Named system message with optional model and reasoning overrides.
+#[derive(Debug, Clone)]
+pub struct Persona {
+    pub system_message: Option<String>,
+    pub model: Option<String>,
+    pub api_options: Option<jutella::ApiOptions>,
 }
 
 pub struct ChatbotEngine {
@@ -62,53 +98,66 @@ pub struct ChatbotEngine {
     tokenizer: Arc<tiktoken_rs::CoreBPE>,
     request_rx: Receiver<RequestMessage>,
     response_tx: Sender<ResponseMessage>,
+    command_rx: Receiver<EngineCommand>,
     handlers_futures: FuturesUnordered<BoxFuture<'static, anyhow::Result<()>>>,
-    request_txs: HashMap<String, Sender<RequestMessage>>,
+    /// Chat instances by user and thread.
+    instances: HashMap<(String, Option<String>), ChatInstance>,
+}
+
+/// Channels to a running chat handler.
+struct ChatInstance {
+    request_tx: Sender<RequestMessage>,
+    /// Running summary of the conversation, if summarisation is enabled.
+    summary_rx: watch::Receiver<Option<String>>,
+    transcript_rx: watch::Receiver<Transcript>,
 }
 
 impl ChatbotEngine {
+    /// Create the engine. HTTP client and tokenizer can be shared between the engines.
     pub fn new(
         config: Config,
+        reqwest_client: reqwest::Client,
+        tokenizer: Arc<tiktoken_rs::CoreBPE>,
         request_rx: Receiver<RequestMessage>,
         response_tx: Sender<ResponseMessage>,
-    ) -> anyhow::Result<Self> {
-        let reqwest_client = reqwest::Client::new();
-        let tokenizer = Arc::new(tiktoken_rs::o200k_base()?);
-
-        Ok(Self {
+        command_rx: Receiver<EngineCommand>,
+    ) -> Self {
+        Self {
             config,
             reqwest_client,
             tokenizer,
             request_rx,
             response_tx,
+            command_rx,
             handlers_futures: FuturesUnordered::new(),
-            request_txs: HashMap::new(),
-        })
+            instances: HashMap::new(),
+        }
     }
 
-    fn handle_request(&mut self, request: RequestMessage) {
-        let request_tx = match self.request_txs.get(&request.jid) {
-            Some(request_tx) => request_tx,
+    fn handle_request(&mut self, mut request: RequestMessage) {
+        let key = (request.jid.clone(), request.thread.clone());
+        let instance = match self.instances.get(&key) {
+            Some(instance) => instance,
             None => {
                 match create_handler(
                     self.config.clone(),
                     request.jid.clone(),
+                    std::mem::take(&mut request.history),
+                    request.preferences.clone(),
                     self.reqwest_client.clone(),
                     self.tokenizer.clone(),
                     self.response_tx.clone(),
                 ) {
-                    Ok((handler, request_tx)) => {
+                    Ok((handler, instance)) => {
                         tracing::info!(
                             target: LOG_TARGET,
                             jid = request.jid,
+                            thread = request.thread,
                             "initialized chat instance",
                         );
 
                         self.handlers_futures.push(handler.run().boxed());
-                        self.request_txs.insert(request.jid.clone(), request_tx);
-                        self.request_txs
-                            .get(&request.jid)
-                            .expect("request_tx inserted above")
+                        self.instances.entry(key).or_insert(instance)
                     }
                     Err(error) => {
                         tracing::error!(
@@ -118,6 +167,7 @@ impl ChatbotEngine {
                             "failed to create chat instance"
                         );
 
+                        self.reject_request(request, "Failed to start the conversation.");
                         return;
                     }
                 }
@@ -126,23 +176,116 @@ impl ChatbotEngine {
 
         let jid = request.jid.clone();
 
-        match request_tx.try_send(request) {
+        match instance.request_tx.try_send(request) {
             Ok(()) => (),
-            Err(TrySendError::Full(_)) => {
+            Err(TrySendError::Full(request)) => {
                 tracing::debug!(
                     target: LOG_TARGET,
                     jid,
                     size = REQUESTS_CHANNEL_SIZE,
                     "chat instance requests channel clogged",
                 );
+                self.reject_request(
+                    request,
+                    "Too many requests in progress, please wait for the responses.",
+                );
             }
-            Err(TrySendError::Closed(_)) => {
+            Err(TrySendError::Closed(request)) => {
                 // This should never happen.
                 tracing::error!(
                     target: LOG_TARGET,
                     jid,
                     "chat instance requests channel closed. this is a bug",
                 );
+                self.reject_request(request, "Failed to process the request.");
+            }
+        }
+    }
+
+    /// Answer the request that can't be processed with an error, so that it isn't left pending.
+    fn reject_request(&self, request: RequestMessage, error: &str) {
+        let RequestMessage {
+            jid,
+            thread,
+            request,
+            preferences,
+            ..
+        } = request;
+
+        let response = ResponseMessage {
+            jid,
+            thread,
+            request,
+            response: format!("[ERROR] {error}"),
+            model: preferences
+                .model
+                .unwrap_or_else(|| self.config.model.clone()),
+            tokens_in: 0,
+            tokens_in_cached: None,
+            tokens_out: 0,
+            tokens_reasoning: None,
+            api_error: false,
+        };
+        if let Err(error) = self.response_tx.try_send(response) {
+            tracing::error!(target: LOG_TARGET, ?error, "failed to send error response");
+        }
+    }
+
+    fn handle_command(&mut self, command: EngineCommand) {
+        match command {
+            EngineCommand::ResetConversation { jid, thread } => {
+                // Dropping the requests channel terminates the handler once it finishes
+                // processing pending requests.
+                if self
+                    .instances
+                    .remove(&(jid.clone(), thread.clone()))
+                    .is_some()
+                {
+                    tracing::info!(target: LOG_TARGET, jid, thread, "chat instance reset");
+                }
+            }
+            EngineCommand::Summary { jid, thread, reply } => {
+                let summary = self
+                    .instances
+                    .get(&(jid, thread))
+                    .and_then(|instance| instance.summary_rx.borrow().clone());
+                let _ = reply.send(summary);
+            }
+            EngineCommand::Export {
+                jid,
+                thread,
+                format,
+                full,
+                reply,
+            } => {
+                let Some(instance) = self.instances.get(&(jid.clone(), thread.clone())) else {
+                    let _ = reply.send(Ok(None));
+                    return;
+                };
+
+                let summary = instance.summary_rx.borrow().clone();
+                let exported = export(
+                    &jid,
+                    thread.as_deref(),
+                    &instance.transcript_rx.borrow(),
+                    summary.as_deref(),
+                    format,
+                    full,
+                );
+                let _ = reply.send(exported.map(Some));
+            }
+            EngineCommand::SetModel { model } => {
+                tracing::info!(target: LOG_TARGET, model, "default model changed");
+                self.config.model = model;
+            }
+            EngineCommand::UpdateConfig(config) => {
+                tracing::info!(
+                    target: LOG_TARGET,
+                    api_url = config.api_url,
+                    model = config.model,
+                    "configuration reloaded",
+                );
+                self.config = *config;
             }
         }
     }
@@ -171,48 +314,114 @@ impl ChatbotEngine {
                         return Ok(())
                     }
                 }
+                command = self.command_rx.recv() => {
+                    if let Some(command) = command {
+                        self.handle_command(command);
+                    } else {
+                        tracing::debug!(
+                            target: LOG_TARGET,
+                            "command channel terminated, terminating ChatbotEngine",
+                        );
+                        return Ok(())
+                    }
+                }
             }
         }
     }
 }
 
-fn create_handler(
-    Config {
-        api_url,
-        api_options,
-        api_version,
-        api_auth,
-        http_timeout,
+/// Model, verbosity and system message of a chat instance with the user preferences applied.
+struct Personalized {
+    model: String,
+    vision: bool,
+    api_options: jutella::ApiOptions,
+    verbosity: Option<String>,
+    system_message: Option<String>,
+}
+
+fn personalize(config: &Config, preferences: &Preferences) -> Personalized {
+    let persona = preferences
+        .persona
+        .as_ref()
+        .and_then(|persona| config.personas.get(persona));
+    let model = preferences
+        .model
+        .clone()
+        .or_else(|| persona.and_then(|persona| persona.model.clone()))
+        .unwrap_or_else(|| config.model.clone());
+    let system_message = match persona {
+        Some(persona) => persona.system_message.clone(),
+        None => config.system_message.clone(),
+    };
+    let vision = config
+        .vision_models
+        .iter()
+        .any(|pattern| WildMatch::new(pattern).matches(&model));
+
+    Personalized {
         model,
-        system_message,
-        verbosity,
-        min_history_tokens,
-        max_history_tokens,
-    }: Config,
+        vision,
+        api_options: persona
+            .and_then(|persona| persona.api_options.clone())
+            .unwrap_or_else(|| config.api_options.clone()),
+        // The verbosity preference is an instruction in the system message, as not all the models
+        // support the API parameter.
+        verbosity: config.verbosity.clone(),
+        system_message: preferences.system_message(system_message),
+    }
+}
+
+fn create_handler(
+    config: Config,
     jid: String,
+    history: Vec<ConversationTurn>,
+    preferences: Preferences,
     reqwest_client: reqwest::Client,
     tokenizer: Arc<tiktoken_rs::CoreBPE>,
     response_tx: Sender<ResponseMessage>,
-) -> Result<(ChatbotHandler, Sender<RequestMessage>), jutella::Error> {
+) -> anyhow::Result<(ChatbotHandler, ChatInstance)> {
     let (request_tx, request_rx) = channel(REQUESTS_CHANNEL_SIZE);
+    let (summary_tx, summary_rx) = watch::channel(None);
+    let (transcript_tx, transcript_rx) = watch::channel(Transcript::default());
+    let Personalized {
+        model,
+        vision,
+        api_options,
+        verbosity,
+        system_message,
+    } = personalize(&config, &preferences);
 
     let handler = ChatbotHandler::new(ChatbotHandlerConfig {
         jid,
-        api_url,
+        api_url: config.api_url.clone(),
         api_options,
-        api_version,
-        auth: api_auth,
-        http_timeout,
+        api_version: config.api_version.clone(),
+        auth: config.api_auth.clone(),
+        http_timeout: config.http_timeout,
         model,
+        vision,
         system_message,
         verbosity,
-        min_history_tokens,
-        max_history_tokens,
+        min_history_tokens: config.min_history_tokens,
+        max_history_tokens: config.max_history_tokens,
+        max_attachment_size: config.max_attachment_size,
+        history,
+        config,
+        preferences,
+        summary_tx,
+        transcript_tx,
         reqwest_client,
         tokenizer,
         request_rx,
         response_tx,
     })?;
 
-    Ok((handler, request_tx))
+    Ok((
+        handler,
+        ChatInstance {
+            request_tx,
+            summary_rx,
+            transcript_rx,
+        },
+    ))
 }
@@ -0,0 +1,196 @@
+// Copyright (c) 2024 Dmitry Markin
+//
+// SPDX-License-Identifier: MIT
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! Running summary of the turns that fell out of the context window.
+
+use std::fmt::Write;
+
+/// Number of tokens to try dropping when the truncated summary doesn't decode.
+const MAX_TRUNCATION_BACKOFF: usize = 4;
+
+/// Summarisation settings.
+#[derive(Debug, Clone)]
+pub struct SummaryConfig {
+    /// Model producing the summaries. The conversation model is used if not set.
+    pub model: Option<String>,
+    /// Token budget of the summary.
+    pub max_tokens: usize,
+}
+
+/// Running summary of the conversation.
+#[derive(Debug, Default)]
+pub struct RunningSummary {
+    summary: Option<String>,
+    /// Turns that failed to be summarised, retried with the next ones.
+    pending: Vec<(String, String)>,
+}
+
+impl RunningSummary {
+    /// Current summary.
+    pub fn summary(&self) -> Option<&str> {
+        self.summary.as_deref()
+    }
+
+    /// Turns to summarise: the ones that failed before followed by the newly evicted ones.
+    pub fn take_turns(&mut self, evicted: Vec<(String, String)>) -> Vec<(String, String)> {
+        let mut turns = std::mem::take(&mut self.pending);
+        turns.extend(evicted);
+        turns
+    }
+
+    /// Put back the turns that failed to be summarised, to retry with the next ones.
+    pub fn return_turns(&mut self, turns: Vec<(String, String)>) {
+        self.pending = turns;
+    }
+
+    /// Replace the summary, truncating it to `max_tokens`.
+    pub fn set(&mut self, summary: String, max_tokens: usize, tokenizer: &tiktoken_rs::CoreBPE) {
+        let tokens = tokenizer.encode_with_special_tokens(&summary);
+        if tokens.len() <= max_tokens {
+            self.summary = Some(summary);
+            return;
+        }
+
+        // The cut may split a multi-byte character between tokens, which doesn't decode. Drop
+        // a few more tokens until it does, keeping the previous summary if it never does.
+        if let Some(summary) = (max_tokens.saturating_sub(MAX_TRUNCATION_BACKOFF)..=max_tokens)
+            .rev()
+            .find_map(|end| tokenizer.decode(tokens[..end].to_vec()).ok())
+        {
+            self.summary = Some(summary);
+        }
+    }
+
+    /// System message presenting the summary to the conversation model.
+    pub fn system_message(&self) -> Option<String> {
+        self.summary
+            .as_ref()
+            .map(|summary| format!("Summary of the earlier conversation:\n\n{summary}"))
+    }
+}
+
+/// System message of the summary model.
+pub fn summary_system_message(max_tokens: usize) -> String {
+    format!(
+        "You maintain a running summary of a conversation between a user and an assistant. \
+         Update the summary with the new part of the conversation. Keep the facts, names, \
+         decisions, preferences and open questions that may matter later, drop the small talk. \
+         Answer with the updated summary only, in at most {max_tokens} tokens."
+    )
+}
+
+/// Request asking the model to fold the evicted turns into the running summary.
+pub fn summary_request(summary: Option<&str>, turns: &[(String, String)]) -> String {
+    let mut conversation = String::new();
+    for (request, response) in turns {
+        let _ = write!(conversation, "User: {request}\n\nAssistant: {response}\n\n",);
+    }
+
+    match summary {
+        Some(summary) => {
+            format!("Summary so far:\n\n{summary}\n\nConversation to add:\n\n{conversation}")
+        }
+        None => format!("Conversation:\n\n{conversation}"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn turn(n: usize) -> (String, String) {
+        (format!("request {n}"), format!("response {n}"))
+    }
+
+    #[test]
+    fn summary_within_budget() {
+        let tokenizer = tiktoken_rs::o200k_base().unwrap();
+        let mut summary = RunningSummary::default();
+        assert_eq!(summary.summary(), None);
+        assert_eq!(summary.system_message(), None);
+
+        summary.set("The user likes Rust.".to_string(), 10, &tokenizer);
+        assert_eq!(summary.summary(), Some("The user likes Rust."));
+        assert_eq!(
+            summary.system_message().as_deref(),
+            Some("Summary of the earlier conversation:\n\nThe user likes Rust."),
+        );
+    }
+
+    #[test]
+    fn summary_over_budget_truncated() {
+        let tokenizer = tiktoken_rs::o200k_base().unwrap();
+        let text = "do do do do do do do do do do";
+        let mut summary = RunningSummary::default();
+
+        summary.set(text.to_string(), 4, &tokenizer);
+        assert_eq!(summary.summary(), Some("do do do do"));
+    }
+
+    #[test]
+    fn summary_cut_inside_character() {
+        let tokenizer = tiktoken_rs::o200k_base().unwrap();
+        // Each crab is split over two tokens, a cut after the first one doesn't decode.
+        let text = "Notes: 🦀🦀🦀 done";
+        let tokens = tokenizer.encode_with_special_tokens(text);
+        assert!(tokenizer.decode(tokens[..4].to_vec()).is_err());
+        let mut summary = RunningSummary::default();
+
+        summary.set(text.to_string(), 4, &tokenizer);
+        let truncated = summary.summary().unwrap();
+        assert!(!truncated.is_empty());
+        assert!(text.starts_with(truncated));
+        assert!(tokenizer.encode_with_special_tokens(truncated).len() <= 4);
+
+        summary.set(text.to_string(), 5, &tokenizer);
+        assert_eq!(summary.summary(), Some("Notes: 🦀"));
+    }
+
+    #[test]
+    fn failed_turns_retried() {
+        let mut summary = RunningSummary::default();
+
+        let turns = summary.take_turns(vec![turn(1), turn(2)]);
+        assert_eq!(turns, vec![turn(1), turn(2)]);
+        // The summary request failed.
+        summary.return_turns(turns);
+
+        let turns = summary.take_turns(vec![turn(3)]);
+        assert_eq!(turns, vec![turn(1), turn(2), turn(3)]);
+
+        // The summary request succeeded: nothing left to retry.
+        assert!(summary.take_turns(Vec::new()).is_empty());
+    }
+
+    #[test]
+    fn request_with_previous_summary() {
+        assert_eq!(
+            summary_request(None, &[turn(1)]),
+            "Conversation:\n\nUser: request 1\n\nAssistant: response 1\n\n",
+        );
+        assert_eq!(
+            summary_request(Some("Earlier."), &[turn(2)]),
+            "Summary so far:\n\nEarlier.\n\nConversation to add:\n\n\
+             User: request 2\n\nAssistant: response 2\n\n",
+        );
+    }
+}
@@ -0,0 +1,155 @@
+// Copyright (c) 2024 Dmitry Markin
+//
+// SPDX-License-Identifier: MIT
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! System message templates with `{{variable}}` placeholders.
+
+use chrono::{DateTime, Utc};
+use std::{collections::BTreeMap, time::SystemTime};
+
+/// Values of the template variables for a single request.
+pub struct Variables<'a> {
+    pub now: DateTime<Utc>,
+    pub user_jid: &'a str,
+    pub user_nick: Option<&'a str>,
+    pub model: &'a str,
+    /// Variables defined in the config.
+    pub custom: &'a BTreeMap<String, String>,
+}
+
+impl<'a> Variables<'a> {
+    pub fn new(
+        user_jid: &'a str,
+        user_nick: Option<&'a str>,
+        model: &'a str,
+        custom: &'a BTreeMap<String, String>,
+    ) -> Self {
+        Self {
+            now: SystemTime::now().into(),
+            user_jid,
+            user_nick,
+            model,
+            custom,
+        }
+    }
+
+    fn get(&self, name: &str) -> Option<String> {
+        match name {
+            "date" => Some(self.now.format("%A, %Y-%m-%d").to_string()),
+            "time" => Some(self.now.format("%H:%M UTC").to_string()),
+            "user_jid" => Some(self.user_jid.to_string()),
+            // Fall back to the local part of the JID.
+            "user_nick" => Some(
+                self.user_nick
+                    .unwrap_or_else(|| self.user_jid.split('@').next().unwrap_or_default())
+                    .to_string(),
+            ),
+            "model" => Some(self.model.to_string()),
+            name => self.custom.get(name).cloned(),
+        }
+    }
+}
+
+/// Substitute `{{variable}}` placeholders. Unknown variables are left as is.
+pub fn render(template: &str, variables: &Variables<'_>) -> String {
+    let mut rendered = String::with_capacity(template.len());
+    let mut rest = template;
+
+    while let Some(start) = rest.find("{{") {
+        let Some(end) = rest[start..].find("}}").map(|end| start + end) else {
+            break;
+        };
+
+        rendered.push_str(&rest[..start]);
+        match variables.get(rest[start + 2..end].trim()) {
+            Some(value) => rendered.push_str(&value),
+            None => rendered.push_str(&rest[start..end + 2]),
+        }
+        rest = &rest[end + 2..];
+    }
+
+    rendered.push_str(rest);
+    rendered
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn variables<'a>(
+        user_nick: Option<&'a str>,
+        custom: &'a BTreeMap<String, String>,
+    ) -> Variables<'a> {
+        Variables {
+            now: "2024-05-17T13:45:00Z".parse().unwrap(),
+            user_jid: "alice@example.com",
+            user_nick,
+            model: "gpt-4o",
+            custom,
+        }
+    }
+
+    #[test]
+    fn builtin_variables() {
+        let custom = BTreeMap::new();
+
+        assert_eq!(
+            render(
+                "{{date}} {{time}} {{user_jid}} {{user_nick}} {{model}}",
+                &variables(Some("Alice"), &custom)
+            ),
+            "Friday, 2024-05-17 13:45 UTC alice@example.com Alice gpt-4o"
+        );
+    }
+
+    #[test]
+    fn nick_falls_back_to_local_part() {
+        let custom = BTreeMap::new();
+
+        assert_eq!(
+            render("Hi {{ user_nick }}!", &variables(None, &custom)),
+            "Hi alice!"
+        );
+    }
+
+    #[test]
+    fn custom_variables() {
+        let custom = BTreeMap::from([("company".to_string(), "ACME".to_string())]);
+
+        assert_eq!(
+            render("You work for {{company}}.", &variables(None, &custom)),
+            "You work for ACME."
+        );
+    }
+
+    #[test]
+    fn unknown_and_unterminated_placeholders_are_kept() {
+        let custom = BTreeMap::new();
+        let variables = variables(None, &custom);
+
+        assert_eq!(
+            render("{{unknown}} {{model}}", &variables),
+            "{{unknown}} gpt-4o"
+        );
+        assert_eq!(render("{{model}} {{model", &variables), "gpt-4o {{model");
+        assert_eq!(render("{ {model} }", &variables), "{ {model} }");
+    }
+}
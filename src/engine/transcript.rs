@@ -0,0 +1,343 @@
+// Copyright (c) 2024 Dmitry Markin
+//
+// SPDX-License-Identifier: MIT
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! Conversation transcript and its export.
+
+use anyhow::anyhow;
+use chrono::{DateTime, SecondsFormat, Utc};
+use serde::Serialize;
+use std::{collections::VecDeque, fmt::Write, str::FromStr, time::SystemTime};
+
+// Maximum number of turns kept in the transcript.
+const MAX_TRANSCRIPT_TURNS: usize = 1000;
+
+/// Single round of the conversation with its metadata.
+#[derive(Debug, Clone, Serialize)]
+pub struct TranscriptTurn {
+    /// Time of the response. `None` for turns restored from the archive.
+    pub time: Option<DateTime<Utc>>,
+    pub model: Option<String>,
+    pub request: String,
+    /// Number of attached images.
+    pub attachments: usize,
+    pub response: String,
+    pub tokens_in: Option<usize>,
+    pub tokens_out: Option<usize>,
+}
+
+impl TranscriptTurn {
+    pub fn new(
+        model: String,
+        request: String,
+        attachments: usize,
+        response: String,
+        tokens_in: usize,
+        tokens_out: usize,
+    ) -> Self {
+        Self {
+            time: Some(SystemTime::now().into()),
+            model: Some(model),
+            request,
+            attachments,
+            response,
+            tokens_in: Some(tokens_in),
+            tokens_out: Some(tokens_out),
+        }
+    }
+
+    /// Turn of a previous conversation restored without metadata.
+    pub fn restored(request: String, response: String) -> Self {
+        Self {
+            time: None,
+            model: None,
+            request,
+            attachments: 0,
+            response,
+            tokens_in: None,
+            tokens_out: None,
+        }
+    }
+}
+
+/// Untrimmed transcript of the conversation.
+#[derive(Debug, Clone, Default)]
+pub struct Transcript {
+    turns: VecDeque<TranscriptTurn>,
+    /// Number of the latest turns still in the context.
+    context_turns: usize,
+}
+
+impl Transcript {
+    /// Record the turn. `context_turns` is the number of turns in the context after adding it.
+    pub fn push(&mut self, turn: TranscriptTurn, context_turns: usize) {
+        if self.turns.len() >= MAX_TRANSCRIPT_TURNS {
+            self.turns.pop_front();
+        }
+        self.turns.push_back(turn);
+        self.context_turns = context_turns;
+    }
+
+    /// Turns of the transcript, or only those still in the context.
+    fn turns(&self, full: bool) -> impl Iterator<Item = &TranscriptTurn> {
+        let skip = if full {
+            0
+        } else {
+            self.turns.len().saturating_sub(self.context_turns)
+        };
+
+        self.turns.iter().skip(skip)
+    }
+}
+
+/// Format of the exported conversation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExportFormat {
+    Markdown,
+    Json,
+}
+
+impl ExportFormat {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            ExportFormat::Markdown => "md",
+            ExportFormat::Json => "json",
+        }
+    }
+}
+
+impl FromStr for ExportFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "md" | "markdown" => Ok(ExportFormat::Markdown),
+            "json" => Ok(ExportFormat::Json),
+            _ => Err(anyhow!(
+                "Unknown export format {s}, supported: markdown, json"
+            )),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct Export<'a> {
+    jid: &'a str,
+    thread: Option<&'a str>,
+    exported_at: DateTime<Utc>,
+    full: bool,
+    summary: Option<&'a str>,
+    turns: Vec<&'a TranscriptTurn>,
+}
+
+/// Render the conversation, `full` including the turns no longer in the context.
+pub fn export(
+    jid: &str,
+    thread: Option<&str>,
+    transcript: &Transcript,
+    summary: Option<&str>,
+    format: ExportFormat,
+    full: bool,
+) -> anyhow::Result<String> {
+    let export = Export {
+        jid,
+        thread,
+        exported_at: SystemTime::now().into(),
+        full,
+        // The summary only stands in for the turns missing from the context.
+        summary: summary.filter(|_| !full),
+        turns: transcript.turns(full).collect(),
+    };
+
+    match format {
+        ExportFormat::Markdown => Ok(to_markdown(&export)),
+        ExportFormat::Json => serde_json::to_string_pretty(&export)
+            .map_err(|error| anyhow!("Failed to serialize the conversation: {error}")),
+    }
+}
+
+fn to_markdown(export: &Export<'_>) -> String {
+    let mut markdown = format!("# Conversation with {}", export.jid);
+    if let Some(thread) = export.thread {
+        let _ = write!(markdown, " – {thread}");
+    }
+    let _ = write!(
+        markdown,
+        "\n\nExported {}.\n",
+        export
+            .exported_at
+            .to_rfc3339_opts(SecondsFormat::Secs, true),
+    );
+
+    if let Some(summary) = export.summary {
+        let _ = write!(
+            markdown,
+            "\n## Summary of the earlier conversation\n\n{summary}\n"
+        );
+    }
+
+    for turn in &export.turns {
+        let time = turn
+            .time
+            .map(|time| time.to_rfc3339_opts(SecondsFormat::Secs, true))
+            .unwrap_or_else(|| "Restored from archive".to_string());
+        let _ = write!(markdown, "\n## {time}\n\n");
+
+        if let Some(model) = &turn.model {
+            let _ = write!(markdown, "Model: {model}");
+            if let (Some(tokens_in), Some(tokens_out)) = (turn.tokens_in, turn.tokens_out) {
+                let _ = write!(markdown, ", tokens: {tokens_in} in, {tokens_out} out");
+            }
+            markdown.push_str("\n\n");
+        }
+
+        let _ = write!(markdown, "**User:**\n\n{}\n\n", turn.request);
+        if turn.attachments > 0 {
+            let _ = write!(markdown, "[{} image(s) attached]\n\n", turn.attachments);
+        }
+        let _ = write!(markdown, "**Assistant:**\n\n{}\n", turn.response);
+    }
+
+    markdown
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Transcript of three turns, the last two still in the context.
+    fn transcript() -> Transcript {
+        let mut transcript = Transcript::default();
+        transcript.push(TranscriptTurn::restored("req1".into(), "resp1".into()), 1);
+        transcript.push(
+            TranscriptTurn::new("model".into(), "req2".into(), 0, "resp2".into(), 10, 5),
+            2,
+        );
+        transcript.push(
+            TranscriptTurn::new("model".into(), "req3".into(), 2, "resp3".into(), 20, 7),
+            2,
+        );
+        transcript
+    }
+
+    fn requests(transcript: &Transcript, full: bool) -> Vec<&str> {
+        transcript
+            .turns(full)
+            .map(|turn| turn.request.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn parse_format() {
+        assert_eq!(
+            "md".parse::<ExportFormat>().unwrap(),
+            ExportFormat::Markdown
+        );
+        assert_eq!(
+            "markdown".parse::<ExportFormat>().unwrap(),
+            ExportFormat::Markdown
+        );
+        assert_eq!("json".parse::<ExportFormat>().unwrap(), ExportFormat::Json);
+        assert!("txt".parse::<ExportFormat>().is_err());
+        assert_eq!(ExportFormat::Markdown.extension(), "md");
+        assert_eq!(ExportFormat::Json.extension(), "json");
+    }
+
+    #[test]
+    fn context_turns() {
+        let transcript = transcript();
+
+        assert_eq!(requests(&transcript, true), vec!["req1", "req2", "req3"]);
+        assert_eq!(requests(&transcript, false), vec!["req2", "req3"]);
+    }
+
+    #[test]
+    fn oldest_turns_dropped() {
+        let mut transcript = Transcript::default();
+        for n in 0..MAX_TRANSCRIPT_TURNS + 5 {
+            transcript.push(TranscriptTurn::restored(n.to_string(), String::new()), 1);
+        }
+
+        assert_eq!(transcript.turns.len(), MAX_TRANSCRIPT_TURNS);
+        assert_eq!(transcript.turns.front().unwrap().request, "5");
+    }
+
+    #[test]
+    fn markdown_export() {
+        let markdown = export(
+            "user@example.com",
+            Some("rust"),
+            &transcript(),
+            Some("Earlier."),
+            ExportFormat::Markdown,
+            false,
+        )
+        .unwrap();
+
+        assert!(markdown.starts_with("# Conversation with user@example.com – rust\n\nExported "));
+        assert!(markdown.contains("\n## Summary of the earlier conversation\n\nEarlier.\n"));
+        assert!(markdown.contains("Model: model, tokens: 20 in, 7 out\n\n**User:**\n\nreq3\n\n"));
+        assert!(markdown.contains("[2 image(s) attached]\n\n**Assistant:**\n\nresp3\n"));
+        assert!(!markdown.contains("req1"));
+    }
+
+    #[test]
+    fn full_markdown_export() {
+        let markdown = export(
+            "user@example.com",
+            None,
+            &transcript(),
+            Some("Earlier."),
+            ExportFormat::Markdown,
+            true,
+        )
+        .unwrap();
+
+        assert!(markdown.starts_with("# Conversation with user@example.com\n\n"));
+        assert!(markdown.contains("\n## Restored from archive\n\n**User:**\n\nreq1\n\n"));
+        assert!(!markdown.contains("Summary"));
+    }
+
+    #[test]
+    fn json_export() {
+        let json = export(
+            "user@example.com",
+            None,
+            &transcript(),
+            Some("Earlier."),
+            ExportFormat::Json,
+            false,
+        )
+        .unwrap();
+        let json = serde_json::from_str::<serde_json::Value>(&json).unwrap();
+
+        assert_eq!(json["jid"], "user@example.com");
+        assert_eq!(json["thread"], serde_json::Value::Null);
+        assert_eq!(json["full"], false);
+        assert_eq!(json["summary"], "Earlier.");
+        let turns = json["turns"].as_array().unwrap();
+        assert_eq!(turns.len(), 2);
+        assert_eq!(turns[1]["request"], "req3");
+        assert_eq!(turns[1]["attachments"], 2);
+        assert_eq!(turns[1]["tokens_in"], 20);
+        assert_eq!(turns[1]["model"], "model");
+    }
+}
@@ -25,15 +25,27 @@
 mod config;
 mod engine;
 mod message;
+mod preferences;
+mod state;
 mod xmpp;
 
 use crate::{
-    config::Config,
+    config::{Args, ComponentConfig, Config},
     engine::{ChatbotEngine, Config as ChatbotEngineConfig},
-    xmpp::{Config as XmppConfig, Xmpp, REQUESTS_CHANNEL_SIZE, RESPONSES_CHANNEL_SIZE},
+    xmpp::{
+        ComponentLink, ComponentRouter, Config as XmppConfig, Connection, Connector, Xmpp,
+        COMMANDS_CHANNEL_SIZE, REQUESTS_CHANNEL_SIZE, RESPONSES_CHANNEL_SIZE,
+    },
 };
 use anyhow::{anyhow, Context as _};
+use clap::Parser;
+use futures::{
+    future::{BoxFuture, FutureExt},
+    stream::{FuturesUnordered, StreamExt},
+};
+use std::{path::PathBuf, sync::Arc};
 use tokio::sync::mpsc::channel;
+use tracing::Instrument;
 use tracing_log::LogTracer;
 use tracing_subscriber::{filter::LevelFilter, fmt, prelude::*, EnvFilter};
 
@@ -49,24 +61,116 @@ async fn main() -> anyhow::Result<()> {
 
     install_crypto_provider()?;
 
+    let Args {
+        config: config_path,
+    } = Args::parse();
+    let config = Config::load(&config_path).context("Failed to load config")?;
+
+    // HTTP client, tokenizer and config path are shared by all the bots.
+    let shared = Shared {
+        config_path,
+        http_client: reqwest::Client::new(),
+        tokenizer: Arc::new(tiktoken_rs::o200k_base().context("Failed to load tokenizer")?),
+    };
+
+    let mut tasks = FuturesUnordered::new();
+
+    match config.component.clone() {
+        None => {
+            for (name, _) in &config.accounts {
+                let config = config.for_account(name)?;
+                tasks.extend(create_bot(config, None, &shared)?);
+            }
+            tasks.extend(create_bot(config, None, &shared)?);
+        }
+        Some(ComponentConfig { address }) => {
+            let mut router = ComponentRouter::new(
+                config.auth_jid.clone(),
+                config.auth_password.clone(),
+                address,
+            );
+
+            for (name, _) in &config.bots {
+                let config = config.for_bot(name)?;
+                let link = router.link(config.auth_jid.clone());
+                tasks.extend(create_bot(config, Some(link), &shared)?);
+            }
+
+            tasks.push(
+                async move { router.run().await.context("XMPP component terminated") }.boxed(),
+            );
+        }
+    }
+
+    tasks
+        .next()
+        .await
+        .expect("at least one bot is configured; qed")
+}
+
+/// Resources shared by the bots.
+struct Shared {
+    config_path: PathBuf,
+    http_client: reqwest::Client,
+    tokenizer: Arc<tiktoken_rs::CoreBPE>,
+}
+
+/// Create XMPP agent and chatbot engine of a single bot.
+fn create_bot(
+    config: Config,
+    component: Option<ComponentLink>,
+    shared: &Shared,
+) -> anyhow::Result<[BoxFuture<'static, anyhow::Result<()>>; 2]> {
     let Config {
         auth_jid,
         auth_password,
+        server,
+        ping_timeout,
+        report_os,
+        profile,
         allowed_users,
+        denied_users,
+        admins,
+        admin_approval,
+        unauthorized_reply,
         api_url,
         api_options,
         api_version,
         api_auth,
         http_timeout,
         model,
+        vision_models,
+        user_models,
         system_message,
+        personas,
+        variables,
         verbosity,
         min_history_tokens,
         max_history_tokens,
-    } = Config::load().context("Failed to load config")?;
+        summary,
+        max_attachment_size,
+        reactions,
+        feedback_log,
+        upload_threshold,
+        max_message_length,
+        message_styling,
+        xhtml_im,
+        state_dir,
+        mam_max_age,
+        restore_history,
+        component: _,
+        bots: _,
+        accounts: _,
+        bot,
+    } = config;
+
+    // Label the logs of each bot with its JID.
+    let span = tracing::info_span!("bot", jid = auth_jid.as_str());
 
     tracing::info!(
         target: LOG_TARGET,
+        parent: &span,
+        jid = auth_jid.as_str(),
         api_url,
         api_version,
         model,
@@ -75,8 +179,21 @@ async fn main() -> anyhow::Result<()> {
         "configuration",
     );
 
+    let connection = match component {
+        Some(link) => Connection::Component(link),
+        None => Connection::client(
+            auth_jid.clone(),
+            auth_password,
+            Connector::new(server).context("Invalid server connection settings")?,
+        ),
+    };
+
     let (request_tx, request_rx) = channel(REQUESTS_CHANNEL_SIZE);
     let (response_tx, response_rx) = channel(RESPONSES_CHANNEL_SIZE);
+    let (command_tx, command_rx) = channel(COMMANDS_CHANNEL_SIZE);
+
+    let persona_names = personas.keys().cloned().collect();
+    let summarize = summary.is_some();
 
     let chatbot_engine = ChatbotEngine::new(
         ChatbotEngineConfig {
@@ -85,33 +202,69 @@ async fn main() -> anyhow::Result<()> {
             api_version,
             api_auth,
             http_timeout,
-            model,
+            model: model.clone(),
+            vision_models,
             system_message,
+            personas,
+            variables,
             verbosity,
             min_history_tokens,
             max_history_tokens,
+            summary,
+            max_attachment_size,
         },
+        shared.http_client.clone(),
+        shared.tokenizer.clone(),
         request_rx,
         response_tx,
-    )
-    .context("Failed to initialize chatbot engine")?;
+        command_rx,
+    );
 
     let xmpp = Xmpp::new(XmppConfig {
         auth_jid,
-        auth_password,
+        bot,
+        connection,
+        ping_timeout,
+        report_os,
+        profile,
         allowed_jids: allowed_users,
+        denied_jids: denied_users,
+        admins,
+        admin_approval,
+        unauthorized_reply,
+        model,
+        reactions,
+        feedback_log,
+        upload_threshold,
+        max_message_length,
+        message_styling,
+        user_models,
+        personas: persona_names,
+        summarize,
+        xhtml_im,
+        state_dir,
+        mam_max_age,
+        restore_history,
+        config_path: shared.config_path.clone(),
+        http_client: shared.http_client.clone(),
         request_tx,
         response_rx,
+        command_tx,
     });
 
-    tokio::select! {
-        result = xmpp.run() => {
-            return result.context("XMPP agent terminated")
+    Ok([
+        async move { xmpp.run().await.context("XMPP agent terminated") }
+            .instrument(span.clone())
+            .boxed(),
+        async move {
+            chatbot_engine
+                .run()
+                .await
+                .context("chatbot engine terminated")
         }
-        result = chatbot_engine.run() => {
-            return result.context("chatbot engine terminated")
-        }
-    }
+        .instrument(span)
+        .boxed(),
+    ])
 }
 
 fn setup_logging() -> anyhow::Result<()> {
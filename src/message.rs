@@ -20,20 +20,74 @@
 // OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
 // SOFTWARE.
 
+use crate::{
+    engine::{Config as EngineConfig, ExportFormat},
+    preferences::Preferences,
+};
+use tokio::sync::oneshot;
+
 /// Message passed from XMPP engine to chatbot.
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct RequestMessage {
     pub jid: String,
+    /// Conversation thread, `None` for the default one.
+    pub thread: Option<String>,
     pub request: String,
+    /// URLs of attached images.
+    pub attachments: Vec<String>,
+    /// Previous conversation to restore the context from if the chat instance is new.
+    pub history: Vec<ConversationTurn>,
+    /// Preferences of the user.
+    pub preferences: Preferences,
+    /// Nickname of the user from the roster or vCard.
+    pub user_nick: Option<String>,
+}
+
+/// Request-response pair of a previous conversation.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConversationTurn {
+    pub request: String,
+    pub response: String,
 }
 
 /// Message passed from chatbot back to XMPP engine.
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct ResponseMessage {
     pub jid: String,
+    pub thread: Option<String>,
+    pub request: String,
     pub response: String,
+    pub model: String,
     pub tokens_in: usize,
     pub tokens_in_cached: Option<usize>,
     pub tokens_out: usize,
     pub tokens_reasoning: Option<usize>,
+    /// Whether the chatbot API request failed and the response is the error.
+    pub api_error: bool,
+}
+
+/// Administrative command passed from XMPP engine to chatbot.
+#[derive(Debug)]
+pub enum EngineCommand {
+    /// Drop the conversation with the user in the thread.
+    ResetConversation { jid: String, thread: Option<String> },
+    /// Get the running summary of the conversation with the user in the thread.
+    Summary {
+        jid: String,
+        thread: Option<String>,
+        reply: oneshot::Sender<Option<String>>,
+    },
+    /// Render the conversation with the user in the thread, `full` including the turns no
+    /// longer in the context. `None` if there is no conversation.
+    Export {
+        jid: String,
+        thread: Option<String>,
+        format: ExportFormat,
+        full: bool,
+        reply: oneshot::Sender<anyhow::Result<Option<String>>>,
+    },
+    /// Use another model for new conversations.
+    SetModel { model: String },
+    /// Replace the configuration used for new conversations.
+    UpdateConfig(Box<EngineConfig>),
 }
@@ -0,0 +1,369 @@
+// Copyright (c) 2024 Dmitry Markin
+//
+// SPDX-License-Identifier: MIT
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! Per-user preferences.
+
+use std::{fmt, str::FromStr};
+
+/// State file with the preferences of all users.
+pub const PREFERENCES_FILE: &str = "preferences.json";
+
+/// Maximum length of free-text preferences.
+const MAX_TEXT_LENGTH: usize = 1000;
+
+/// Supported values of the verbosity preference.
+const VERBOSITY_LEVELS: [&str; 3] = ["low", "medium", "high"];
+
+/// Defaults set by the user. Unset preferences fall back to the bot config.
+#[derive(Debug, Clone, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct Preferences {
+    /// Language of the answers.
+    pub language: Option<String>,
+    /// Answer verbosity, turned into an instruction in the system message.
+    pub verbosity: Option<String>,
+    /// Addendum to the system message.
+    pub prompt: Option<String>,
+    /// Model from the list allowed by admins.
+    pub model: Option<String>,
+    /// XEP-0393 message styling of responses.
+    pub styling: Option<bool>,
+    /// Persona replacing the default system message, chosen with `/persona`.
+    pub persona: Option<String>,
+}
+
+/// Preference that can be changed with `/set`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Preference {
+    Language,
+    Verbosity,
+    Prompt,
+    Model,
+    Styling,
+}
+
+impl FromStr for Preference {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "language" => Ok(Preference::Language),
+            "verbosity" => Ok(Preference::Verbosity),
+            "prompt" => Ok(Preference::Prompt),
+            "model" => Ok(Preference::Model),
+            "styling" => Ok(Preference::Styling),
+            _ => Err(format!(
+                "Unknown preference {s}, expected language, verbosity, prompt, model or styling"
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Preference {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Preference::Language => "language",
+            Preference::Verbosity => "verbosity",
+            Preference::Prompt => "prompt",
+            Preference::Model => "model",
+            Preference::Styling => "styling",
+        };
+        f.write_str(name)
+    }
+}
+
+impl Preferences {
+    pub fn is_empty(&self) -> bool {
+        *self == Preferences::default()
+    }
+
+    /// Set or, if `value` is `None`, reset the preference. `models` are the models users may
+    /// choose.
+    pub fn set(
+        &mut self,
+        preference: Preference,
+        value: Option<String>,
+        models: &[String],
+    ) -> Result<(), String> {
+        if let Some(value) = &value {
+            if value.chars().count() > MAX_TEXT_LENGTH {
+                return Err(format!(
+                    "The value is too long, at most {MAX_TEXT_LENGTH} characters are allowed."
+                ));
+            }
+        }
+
+        match preference {
+            Preference::Language => self.language = value,
+            Preference::Verbosity => match value {
+                Some(value) if !VERBOSITY_LEVELS.contains(&value.as_str()) => {
+                    return Err(format!(
+                        "Unknown verbosity {value}, expected {}.",
+                        VERBOSITY_LEVELS.join(", "),
+                    ))
+                }
+                value => self.verbosity = value,
+            },
+            Preference::Prompt => self.prompt = value,
+            Preference::Model => match value {
+                Some(value) if !models.contains(&value) => {
+                    return Err(match models.is_empty() {
+                        true => "Choosing the model is not enabled on this bot.".to_string(),
+                        false => {
+                            format!("Unknown model {value}, available: {}.", models.join(", "))
+                        }
+                    })
+                }
+                value => self.model = value,
+            },
+            Preference::Styling => match value.as_deref() {
+                None => self.styling = None,
+                Some("on") => self.styling = Some(true),
+                Some("off") => self.styling = Some(false),
+                Some(value) => return Err(format!("Unknown styling {value}, expected on or off.")),
+            },
+        }
+
+        Ok(())
+    }
+
+    /// Preferences without the model and persona if they are no longer available.
+    pub fn allowed(&self, models: &[String], personas: &[String]) -> Self {
+        Self {
+            model: self.model.clone().filter(|model| models.contains(model)),
+            persona: self
+                .persona
+                .clone()
+                .filter(|persona| personas.contains(persona)),
+            ..self.clone()
+        }
+    }
+
+    /// System message with the prompt addendum, the language and the verbosity.
+    pub fn system_message(&self, system_message: Option<String>) -> Option<String> {
+        let language = self
+            .language
+            .as_ref()
+            .map(|language| format!("Answer in {language}."));
+        let verbosity = self
+            .verbosity
+            .as_deref()
+            .and_then(|verbosity| match verbosity {
+                "low" => Some("Keep the answers brief and to the point."),
+                "medium" => Some("Give answers of moderate length."),
+                "high" => Some("Give detailed and thorough answers."),
+                _ => None,
+            })
+            .map(ToString::to_string);
+        let parts = system_message
+            .into_iter()
+            .chain(self.prompt.clone())
+            .chain(language)
+            .chain(verbosity)
+            .collect::<Vec<_>>();
+
+        (!parts.is_empty()).then(|| parts.join("\n\n"))
+    }
+
+    /// Human-readable listing of the preferences.
+    pub fn describe(&self) -> String {
+        let value = |value: &Option<String>| value.clone().unwrap_or_else(|| "default".to_string());
+        let styling = match self.styling {
+            None => "default",
+            Some(true) => "on",
+            Some(false) => "off",
+        };
+
+        format!(
+            "Preferences:\n\
+             language: {}\n\
+             verbosity: {}\n\
+             prompt: {}\n\
+             model: {}\n\
+             styling: {styling}\n\
+             persona: {}\n\
+             Change with \"/set <preference> <value>\", reset with \"/set <preference>\".",
+            value(&self.language),
+            value(&self.verbosity),
+            value(&self.prompt),
+            value(&self.model),
+            value(&self.persona),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::StateDir;
+    use std::{collections::HashMap, fs, process};
+
+    fn models() -> Vec<String> {
+        vec!["small".to_string(), "large".to_string()]
+    }
+
+    #[test]
+    fn parse_preference() {
+        for preference in [
+            Preference::Language,
+            Preference::Verbosity,
+            Preference::Prompt,
+            Preference::Model,
+            Preference::Styling,
+        ] {
+            assert_eq!(preference.to_string().parse(), Ok(preference));
+        }
+        assert!("persona".parse::<Preference>().is_err());
+        assert!("Language".parse::<Preference>().is_err());
+    }
+
+    #[test]
+    fn set_and_reset() {
+        let mut preferences = Preferences::default();
+
+        preferences
+            .set(Preference::Language, Some("French".to_string()), &[])
+            .unwrap();
+        preferences
+            .set(Preference::Verbosity, Some("low".to_string()), &[])
+            .unwrap();
+        preferences
+            .set(Preference::Model, Some("large".to_string()), &models())
+            .unwrap();
+        preferences
+            .set(Preference::Styling, Some("off".to_string()), &[])
+            .unwrap();
+        assert_eq!(preferences.language.as_deref(), Some("French"));
+        assert_eq!(preferences.verbosity.as_deref(), Some("low"));
+        assert_eq!(preferences.model.as_deref(), Some("large"));
+        assert_eq!(preferences.styling, Some(false));
+
+        for preference in [
+            Preference::Language,
+            Preference::Verbosity,
+            Preference::Model,
+            Preference::Styling,
+        ] {
+            preferences.set(preference, None, &models()).unwrap();
+        }
+        assert!(preferences.is_empty());
+    }
+
+    #[test]
+    fn invalid_values() {
+        let mut preferences = Preferences::default();
+
+        assert!(preferences
+            .set(Preference::Verbosity, Some("extreme".to_string()), &[])
+            .is_err());
+        assert!(preferences
+            .set(Preference::Model, Some("huge".to_string()), &models())
+            .is_err());
+        assert!(preferences
+            .set(Preference::Model, Some("small".to_string()), &[])
+            .is_err());
+        assert!(preferences
+            .set(Preference::Styling, Some("yes".to_string()), &[])
+            .is_err());
+        assert!(preferences
+            .set(
+                Preference::Prompt,
+                Some("a".repeat(MAX_TEXT_LENGTH + 1)),
+                &[]
+            )
+            .is_err());
+        assert!(preferences.is_empty());
+    }
+
+    #[test]
+    fn unavailable_model_and_persona_dropped() {
+        let preferences = Preferences {
+            model: Some("large".to_string()),
+            persona: Some("pirate".to_string()),
+            language: Some("French".to_string()),
+            ..Default::default()
+        };
+
+        let allowed = preferences.allowed(&models(), &["pirate".to_string()]);
+        assert_eq!(allowed, preferences);
+
+        let allowed = preferences.allowed(&[], &[]);
+        assert_eq!(allowed.model, None);
+        assert_eq!(allowed.persona, None);
+        assert_eq!(allowed.language.as_deref(), Some("French"));
+    }
+
+    #[test]
+    fn system_message_parts() {
+        let preferences = Preferences {
+            language: Some("French".to_string()),
+            verbosity: Some("low".to_string()),
+            prompt: Some("Be polite.".to_string()),
+            ..Default::default()
+        };
+
+        assert_eq!(
+            preferences.system_message(Some("You are a bot.".to_string())),
+            Some(
+                "You are a bot.\n\nBe polite.\n\nAnswer in French.\n\n\
+                 Keep the answers brief and to the point."
+                    .to_string()
+            ),
+        );
+        assert_eq!(Preferences::default().system_message(None), None);
+    }
+
+    #[test]
+    fn persisted() {
+        let path = std::env::temp_dir().join(format!("jutella-xmpp-preferences-{}", process::id()));
+        let state_dir = StateDir::new(path.clone());
+        let preferences = HashMap::from([(
+            "user@example.com".to_string(),
+            Preferences {
+                language: Some("French".to_string()),
+                styling: Some(true),
+                persona: Some("pirate".to_string()),
+                ..Default::default()
+            },
+        )]);
+
+        state_dir.save(PREFERENCES_FILE, &preferences).unwrap();
+        let loaded = state_dir
+            .load::<HashMap<String, Preferences>>(PREFERENCES_FILE)
+            .unwrap();
+        fs::remove_dir_all(&path).unwrap();
+
+        assert_eq!(loaded, Some(preferences));
+    }
+
+    #[test]
+    fn missing_fields_default() {
+        let preferences = serde_json::from_str::<Preferences>(r#"{"language": "French"}"#).unwrap();
+
+        assert_eq!(
+            preferences,
+            Preferences {
+                language: Some("French".to_string()),
+                ..Default::default()
+            },
+        );
+    }
+}
@@ -0,0 +1,70 @@
+// Copyright (c) 2024 Dmitry Markin
+//
+// SPDX-License-Identifier: MIT
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! Persistent state.
+
+use anyhow::{anyhow, Context as _};
+use serde::{de::DeserializeOwned, Serialize};
+use std::{fs, io::ErrorKind, path::PathBuf};
+
+/// Directory with persistent state stored as JSON files.
+#[derive(Debug, Clone)]
+pub struct StateDir {
+    path: PathBuf,
+}
+
+impl StateDir {
+    pub fn new(path: PathBuf) -> Self {
+        Self { path }
+    }
+
+    /// Load the state from file `name`. Returns `None` if the file doesn't exist yet.
+    pub fn load<T: DeserializeOwned>(&self, name: &str) -> anyhow::Result<Option<T>> {
+        let path = self.path.join(name);
+
+        let data = match fs::read_to_string(&path) {
+            Ok(data) => data,
+            Err(error) if error.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(error) => {
+                return Err(error)
+                    .with_context(|| anyhow!("Failed to read state file {}", path.display()))
+            }
+        };
+
+        serde_json::from_str(&data)
+            .map(Some)
+            .with_context(|| anyhow!("Invalid state file {}", path.display()))
+    }
+
+    /// Save the state to file `name`. The file is replaced atomically.
+    pub fn save<T: Serialize>(&self, name: &str, state: &T) -> anyhow::Result<()> {
+        let path = self.path.join(name);
+        let tmp_path = self.path.join(format!("{name}.tmp"));
+
+        let data = serde_json::to_string_pretty(state).context("Failed to serialize state")?;
+
+        fs::create_dir_all(&self.path)
+            .and_then(|()| fs::write(&tmp_path, data))
+            .and_then(|()| fs::rename(&tmp_path, &path))
+            .with_context(|| anyhow!("Failed to write state file {}", path.display()))
+    }
+}
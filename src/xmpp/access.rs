@@ -0,0 +1,86 @@
+// Copyright (c) 2024 Dmitry Markin
+//
+// SPDX-License-Identifier: MIT
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! Runtime access list managed by admins.
+
+use std::collections::BTreeSet;
+use wildmatch::WildMatch;
+use xmpp_parsers::jid::Jid;
+
+/// State file with the access list.
+pub const ACCESS_LIST_FILE: &str = "access_list.json";
+
+/// Reply sent once to users not allowed to use the bot.
+pub const NOT_AUTHORIZED_REPLY: &str =
+    "Sorry, you are not authorised to use this bot. Please contact its administrator.";
+
+/// Persisted allow and deny patterns. Deny patterns take precedence.
+#[derive(Debug, Default, serde::Serialize, serde::Deserialize)]
+pub struct AccessList {
+    pub allow: BTreeSet<String>,
+    pub deny: BTreeSet<String>,
+}
+
+impl AccessList {
+    pub fn allows(&self, jid: &str) -> bool {
+        self.allow
+            .iter()
+            .any(|pattern| WildMatch::new(pattern).matches(jid))
+    }
+
+    pub fn denies(&self, jid: &str) -> bool {
+        self.deny
+            .iter()
+            .any(|pattern| WildMatch::new(pattern).matches(jid))
+    }
+
+    /// Human-readable listing of the patterns.
+    pub fn describe(&self) -> String {
+        let list = |patterns: &BTreeSet<String>| match patterns.is_empty() {
+            true => "none".to_string(),
+            false => patterns.iter().cloned().collect::<Vec<_>>().join(", "),
+        };
+
+        format!(
+            "Allowed: {}\nDenied: {}",
+            list(&self.allow),
+            list(&self.deny)
+        )
+    }
+}
+
+/// JID blocking everything matched by the pattern on the server (XEP-0191), if expressible.
+/// Exact JIDs are blocked as is and `*@domain` patterns as the whole domain.
+pub fn blockable_jid(pattern: &str) -> Option<Jid> {
+    let is_wildcard = |s: &str| s.contains(['*', '?']);
+
+    if !is_wildcard(pattern) {
+        return Jid::new(pattern).ok();
+    }
+
+    match pattern.split_once('@') {
+        Some(("*", domain)) if !is_wildcard(domain) && !domain.contains('/') => {
+            Jid::new(domain).ok()
+        }
+        _ => None,
+    }
+}
@@ -0,0 +1,121 @@
+// Copyright (c) 2024 Dmitry Markin
+//
+// SPDX-License-Identifier: MIT
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! XEP-0280 message carbons.
+
+use std::collections::{HashSet, VecDeque};
+use xmpp_parsers::{
+    carbons::{Received, Sent},
+    jid::BareJid,
+    message::Message as XmppMessage,
+    ns,
+};
+
+// Log target for this file.
+const LOG_TARGET: &str = "jutella::carbons";
+
+// Number of recently processed message ids to remember for deduplication.
+const PROCESSED_IDS_CAPACITY: usize = 1024;
+
+/// Unwrap the message if it is a carbon copy.
+///
+/// Returns the forwarded message for `received` carbons, `None` for `sent` carbons (copies
+/// of messages sent from other resources of our account) and invalid or forged carbons, and
+/// the message itself if it is not a carbon.
+pub fn unwrap_carbon(own_jid: &BareJid, mut message: XmppMessage) -> Option<XmppMessage> {
+    let is_carbon = message
+        .payloads
+        .iter()
+        .any(|p| p.is("sent", ns::CARBONS) || p.is("received", ns::CARBONS));
+
+    if !is_carbon {
+        return Some(message);
+    }
+
+    // Only our own server is allowed to send us carbons. Anything else is an attempt to forge
+    // a message from another user.
+    if message
+        .from
+        .as_ref()
+        .is_some_and(|from| from.to_bare() != *own_jid)
+    {
+        tracing::warn!(
+            target: LOG_TARGET,
+            from = ?message.from,
+            "carbon copy from foreign JID, ignoring",
+        );
+        return None;
+    }
+
+    match message.extract_payload::<Sent>() {
+        Ok(Some(_)) => {
+            tracing::trace!(target: LOG_TARGET, "ignoring carbon copy of sent message");
+            return None;
+        }
+        Ok(None) => {}
+        Err(error) => {
+            tracing::debug!(target: LOG_TARGET, ?error, "invalid `sent` carbon");
+            return None;
+        }
+    }
+
+    match message.extract_payload::<Received>() {
+        Ok(Some(received)) => received.forwarded.stanza,
+        Ok(None) => None,
+        Err(error) => {
+            tracing::debug!(target: LOG_TARGET, ?error, "invalid `received` carbon");
+            None
+        }
+    }
+}
+
+/// Recently processed messages.
+///
+/// Used to make sure every request is processed exactly once, even if it is delivered both
+/// directly and as a carbon copy.
+#[derive(Debug, Default)]
+pub struct ProcessedMessages {
+    ids: HashSet<(String, String)>,
+    order: VecDeque<(String, String)>,
+}
+
+impl ProcessedMessages {
+    /// Remember the message. Returns `false` if the message was already processed.
+    pub fn insert(&mut self, jid: &str, id: &str) -> bool {
+        let key = (jid.to_owned(), id.to_owned());
+
+        if self.ids.contains(&key) {
+            return false;
+        }
+
+        if self.order.len() >= PROCESSED_IDS_CAPACITY {
+            if let Some(oldest) = self.order.pop_front() {
+                self.ids.remove(&oldest);
+            }
+        }
+
+        self.order.push_back(key.clone());
+        self.ids.insert(key);
+
+        true
+    }
+}
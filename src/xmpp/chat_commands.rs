@@ -0,0 +1,202 @@
+// Copyright (c) 2024 Dmitry Markin
+//
+// SPDX-License-Identifier: MIT
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! Commands sent by users as chat messages.
+
+use crate::{engine::ExportFormat, preferences::Preference};
+
+/// Help text listing the commands.
+pub const HELP: &str = "Commands:\n\
+    /help – show this help\n\
+    /styling [on|off] – show or change formatting of responses\n\
+    /prefs – show your preferences\n\
+    /set <preference> [value] – change or reset language, verbosity, prompt, model or styling\n\
+    /persona [<name>|default] [reset] – show or switch the persona, optionally starting over\n\
+    /summary – show the summary of the earlier conversation\n\
+    /threads – list your conversation threads\n\
+    /new <topic> – start a new thread\n\
+    /switch <topic>|default – continue another thread\n\
+    /export [markdown|json] [full] – export the conversation, optionally the full transcript";
+
+/// Help text listing the admin commands.
+pub const ADMIN_HELP: &str = "Admin commands:\n\
+    /approve <jid> – approve access request\n\
+    /deny <jid> – deny access request or revoke approval\n\
+    /access – show the access list\n\
+    /access allow|deny <pattern> – allow or deny matching users, deny wins\n\
+    /access remove <pattern> – remove the pattern from the access list";
+
+/// Command sent as a chat message starting with `/`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ChatCommand {
+    /// Show the list of commands.
+    Help,
+    /// Show or change XEP-0393 message styling of responses.
+    Styling(Option<bool>),
+    /// Show the preferences of the user.
+    Preferences,
+    /// Change the preference of the user, or reset it if no value is given.
+    Set(Preference, Option<String>),
+    /// Show the personas, or switch to the persona (`None` for default), optionally resetting
+    /// the conversation.
+    Persona(Option<(Option<String>, bool)>),
+    /// Show the running summary of the conversation.
+    Summary,
+    /// List the threads of the user.
+    Threads,
+    /// Start a new thread and switch to it.
+    NewThread(String),
+    /// Switch to the thread.
+    SwitchThread(String),
+    /// Export the conversation, optionally including the turns no longer in the context.
+    Export(ExportFormat, bool),
+    /// Approve access request of the user (admins only).
+    Approve(String),
+    /// Deny access request or revoke approval of the user (admins only).
+    Deny(String),
+    /// Show or edit the access list (admins only).
+    Access(AccessChange),
+}
+
+/// Change of the access list.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AccessChange {
+    /// Show the access list.
+    List,
+    /// Add the allow pattern.
+    Allow(String),
+    /// Add the deny pattern.
+    Deny(String),
+    /// Remove the pattern from both lists.
+    Remove(String),
+}
+
+impl ChatCommand {
+    /// Parse the command. Returns `None` if the message is not a known command, and an error
+    /// with the usage if the arguments are invalid.
+    pub fn parse(body: &str) -> Option<Result<Self, String>> {
+        let body = body.trim().strip_prefix('/')?;
+        let (name, args) = body
+            .split_once(char::is_whitespace)
+            .map(|(name, args)| (name, args.trim()))
+            .unwrap_or((body, ""));
+
+        let command = match name {
+            "help" => Ok(ChatCommand::Help),
+            "styling" => match args {
+                "" => Ok(ChatCommand::Styling(None)),
+                "on" => Ok(ChatCommand::Styling(Some(true))),
+                "off" => Ok(ChatCommand::Styling(Some(false))),
+                _ => Err("Usage: /styling [on|off]".to_string()),
+            },
+            "prefs" => Ok(ChatCommand::Preferences),
+            "set" => {
+                let (name, value) = args
+                    .split_once(char::is_whitespace)
+                    .map(|(name, value)| (name, Some(value.trim().to_string())))
+                    .unwrap_or((args, None));
+
+                match name {
+                    "" => Err("Usage: /set <preference> [value]".to_string()),
+                    name => name
+                        .parse::<Preference>()
+                        .map(|preference| ChatCommand::Set(preference, value)),
+                }
+            }
+            "persona" => {
+                let (name, reset) = match args.split_once(char::is_whitespace) {
+                    Some((name, "reset")) => (name, true),
+                    Some(_) => return Some(Err("Usage: /persona [<name>] [reset]".to_string())),
+                    None => (args, false),
+                };
+
+                match name {
+                    "" => Ok(ChatCommand::Persona(None)),
+                    "default" => Ok(ChatCommand::Persona(Some((None, reset)))),
+                    name => Ok(ChatCommand::Persona(Some((Some(name.to_string()), reset)))),
+                }
+            }
+            "summary" => Ok(ChatCommand::Summary),
+            "threads" => Ok(ChatCommand::Threads),
+            "new" => match args {
+                "" => Err("Usage: /new <topic>".to_string()),
+                topic => Ok(ChatCommand::NewThread(topic.to_string())),
+            },
+            "switch" => match args {
+                "" => Err("Usage: /switch <topic>|default".to_string()),
+                topic => Ok(ChatCommand::SwitchThread(topic.to_string())),
+            },
+            "export" => {
+                let mut format = ExportFormat::Markdown;
+                let mut full = false;
+                for arg in args.split_whitespace() {
+                    match arg {
+                        "full" => full = true,
+                        arg => match arg.parse() {
+                            Ok(parsed) => format = parsed,
+                            Err(_) => {
+                                return Some(Err(
+                                    "Usage: /export [markdown|json] [full]".to_string()
+                                ))
+                            }
+                        },
+                    }
+                }
+
+                Ok(ChatCommand::Export(format, full))
+            }
+            "approve" => match args {
+                "" => Err("Usage: /approve <jid>".to_string()),
+                jid => Ok(ChatCommand::Approve(jid.to_string())),
+            },
+            "deny" => match args {
+                "" => Err("Usage: /deny <jid>".to_string()),
+                jid => Ok(ChatCommand::Deny(jid.to_string())),
+            },
+            "access" => {
+                let usage = "Usage: /access [allow|deny|remove <pattern>]".to_string();
+                let (action, pattern) = args
+                    .split_once(char::is_whitespace)
+                    .map(|(action, pattern)| (action, pattern.trim()))
+                    .unwrap_or((args, ""));
+
+                match (action, pattern) {
+                    ("", _) => Ok(ChatCommand::Access(AccessChange::List)),
+                    (_, "") => Err(usage),
+                    ("allow", pattern) => Ok(ChatCommand::Access(AccessChange::Allow(
+                        pattern.to_string(),
+                    ))),
+                    ("deny", pattern) => {
+                        Ok(ChatCommand::Access(AccessChange::Deny(pattern.to_string())))
+                    }
+                    ("remove", pattern) => Ok(ChatCommand::Access(AccessChange::Remove(
+                        pattern.to_string(),
+                    ))),
+                    _ => Err(usage),
+                }
+            }
+            _ => return None,
+        };
+
+        Some(command)
+    }
+}
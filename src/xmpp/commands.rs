@@ -0,0 +1,293 @@
+// Copyright (c) 2024 Dmitry Markin
+//
+// SPDX-License-Identifier: MIT
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! XEP-0050 ad-hoc commands for administration.
+
+use xmpp_parsers::{
+    data_forms::{DataForm, DataFormType, Field, FieldType, Option_},
+    disco::{DiscoInfoResult, DiscoItemsResult, Feature, Identity, Item},
+    jid::Jid,
+    minidom::Element,
+    ns,
+};
+
+/// Ad-hoc commands namespace.
+pub const NS_COMMANDS: &str = "http://jabber.org/protocol/commands";
+
+/// Administrative command.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AdminCommand {
+    /// List active chats with their token usage.
+    ListChats,
+    /// Reset the conversation with a user.
+    ResetConversation,
+    /// Reload the config file.
+    ReloadConfig,
+    /// Change the model used for new conversations.
+    SetModel,
+    /// Show token usage totals.
+    Usage,
+    /// Send a message to all active users.
+    Broadcast,
+    /// List pending access requests.
+    AccessRequests,
+    /// Approve access request of a user.
+    ApproveUser,
+    /// Deny access request or revoke approval of a user.
+    DenyUser,
+    /// Send the conversation of a user to the admin.
+    ExportConversation,
+}
+
+impl AdminCommand {
+    pub const ALL: [AdminCommand; 10] = [
+        AdminCommand::ListChats,
+        AdminCommand::ResetConversation,
+        AdminCommand::ReloadConfig,
+        AdminCommand::SetModel,
+        AdminCommand::Usage,
+        AdminCommand::Broadcast,
+        AdminCommand::AccessRequests,
+        AdminCommand::ApproveUser,
+        AdminCommand::DenyUser,
+        AdminCommand::ExportConversation,
+    ];
+
+    pub fn node(&self) -> &'static str {
+        match self {
+            AdminCommand::ListChats => "list-chats",
+            AdminCommand::ResetConversation => "reset-conversation",
+            AdminCommand::ReloadConfig => "reload-config",
+            AdminCommand::SetModel => "set-model",
+            AdminCommand::Usage => "usage",
+            AdminCommand::Broadcast => "broadcast",
+            AdminCommand::AccessRequests => "access-requests",
+            AdminCommand::ApproveUser => "approve-user",
+            AdminCommand::DenyUser => "deny-user",
+            AdminCommand::ExportConversation => "export-conversation",
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            AdminCommand::ListChats => "List active chats",
+            AdminCommand::ResetConversation => "Reset conversation",
+            AdminCommand::ReloadConfig => "Reload config",
+            AdminCommand::SetModel => "Change default model",
+            AdminCommand::Usage => "Show usage",
+            AdminCommand::Broadcast => "Broadcast message",
+            AdminCommand::AccessRequests => "List access requests",
+            AdminCommand::ApproveUser => "Approve user",
+            AdminCommand::DenyUser => "Deny user",
+            AdminCommand::ExportConversation => "Export conversation",
+        }
+    }
+
+    pub fn from_node(node: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|command| command.node() == node)
+    }
+
+    /// Input form of the command, if the command takes any input.
+    pub fn input_form(&self, model: &str) -> Option<DataForm> {
+        let jid_field = Field {
+            label: Some("User JID".to_string()),
+            required: true,
+            ..Field::new("jid", FieldType::JidSingle)
+        };
+
+        let fields = match self {
+            AdminCommand::ResetConversation
+            | AdminCommand::ApproveUser
+            | AdminCommand::DenyUser => vec![jid_field],
+            AdminCommand::SetModel => vec![Field {
+                label: Some("Model".to_string()),
+                required: true,
+                ..Field::text_single("model", model)
+            }],
+            AdminCommand::Broadcast => vec![Field {
+                label: Some("Message".to_string()),
+                required: true,
+                ..Field::new("message", FieldType::TextMulti)
+            }],
+            AdminCommand::ExportConversation => vec![
+                jid_field,
+                Field {
+                    label: Some("Format".to_string()),
+                    options: ["markdown", "json"]
+                        .into_iter()
+                        .map(|value| Option_ {
+                            label: None,
+                            value: value.to_string(),
+                        })
+                        .collect(),
+                    ..Field::new("format", FieldType::ListSingle).with_value("markdown")
+                },
+                Field {
+                    label: Some("Full transcript".to_string()),
+                    ..Field::new("full", FieldType::Boolean).with_value("0")
+                },
+            ],
+            AdminCommand::ListChats
+            | AdminCommand::ReloadConfig
+            | AdminCommand::Usage
+            | AdminCommand::AccessRequests => return None,
+        };
+
+        Some(DataForm {
+            type_: DataFormType::Form,
+            form_type: None,
+            title: Some(self.name().to_string()),
+            instructions: None,
+            fields,
+        })
+    }
+}
+
+/// Parsed `<command/>` request.
+#[derive(Debug)]
+pub struct CommandRequest {
+    pub node: String,
+    pub sessionid: Option<String>,
+    pub action: Option<String>,
+    pub form: Option<DataForm>,
+}
+
+impl CommandRequest {
+    pub fn parse(element: &Element) -> Option<Self> {
+        if !element.is("command", NS_COMMANDS) {
+            return None;
+        }
+
+        Some(Self {
+            node: element.attr("node")?.to_string(),
+            sessionid: element.attr("sessionid").map(ToString::to_string),
+            action: element.attr("action").map(ToString::to_string),
+            form: element
+                .get_child("x", ns::DATA_FORMS)
+                .and_then(|x| DataForm::try_from(x.clone()).ok()),
+        })
+    }
+
+    /// Value of the submitted form field.
+    pub fn value(&self, var: &str) -> Option<String> {
+        let form = self.form.as_ref()?;
+        let field = form
+            .fields
+            .iter()
+            .find(|field| field.var.as_deref() == Some(var))?;
+
+        let value = field.values.join("\n");
+        (!value.trim().is_empty()).then_some(value)
+    }
+}
+
+/// Command response with status `executing`, asking to fill in the input form.
+pub fn executing_response(node: &str, sessionid: &str, form: DataForm) -> Element {
+    Element::builder("command", NS_COMMANDS)
+        .attr("node", node)
+        .attr("sessionid", sessionid)
+        .attr("status", "executing")
+        .append(
+            Element::builder("actions", NS_COMMANDS)
+                .attr("execute", "complete")
+                .append(Element::builder("complete", NS_COMMANDS)),
+        )
+        .append(Element::from(form))
+        .build()
+}
+
+/// Command response with status `completed` and a note describing the outcome.
+pub fn completed_response(node: &str, sessionid: &str, outcome: Result<String, String>) -> Element {
+    let (note_type, note) = match outcome {
+        Ok(note) => ("info", note),
+        Err(note) => ("error", note),
+    };
+
+    Element::builder("command", NS_COMMANDS)
+        .attr("node", node)
+        .attr("sessionid", sessionid)
+        .attr("status", "completed")
+        .append(
+            Element::builder("note", NS_COMMANDS)
+                .attr("type", note_type)
+                .append(note),
+        )
+        .build()
+}
+
+/// Command response with status `canceled`.
+pub fn canceled_response(node: &str, sessionid: &str) -> Element {
+    Element::builder("command", NS_COMMANDS)
+        .attr("node", node)
+        .attr("sessionid", sessionid)
+        .attr("status", "canceled")
+        .build()
+}
+
+/// Command list returned in response to `disco#items` query on the commands node.
+pub fn command_list(jid: &Jid, commands: &[AdminCommand]) -> DiscoItemsResult {
+    DiscoItemsResult {
+        node: Some(NS_COMMANDS.to_string()),
+        items: commands
+            .iter()
+            .map(|command| Item {
+                jid: jid.clone(),
+                node: Some(command.node().to_string()),
+                name: Some(command.name().to_string()),
+            })
+            .collect(),
+        rsm: None,
+    }
+}
+
+/// `disco#info` of a single command node.
+pub fn command_info(command: AdminCommand) -> DiscoInfoResult {
+    DiscoInfoResult {
+        node: Some(command.node().to_string()),
+        identities: vec![Identity::new(
+            "automation",
+            "command-node",
+            "en",
+            command.name(),
+        )],
+        features: vec![Feature::new(NS_COMMANDS), Feature::new(ns::DATA_FORMS)],
+        extensions: Vec::new(),
+    }
+}
+
+/// Token usage of a single chat.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ChatUsage {
+    pub requests: usize,
+    pub tokens_in: usize,
+    pub tokens_in_cached: usize,
+    pub tokens_out: usize,
+}
+
+impl ChatUsage {
+    pub fn add(&mut self, other: &ChatUsage) {
+        self.requests += other.requests;
+        self.tokens_in += other.tokens_in;
+        self.tokens_in_cached += other.tokens_in_cached;
+        self.tokens_out += other.tokens_out;
+    }
+}
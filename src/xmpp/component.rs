@@ -0,0 +1,454 @@
+// Copyright (c) 2024 Dmitry Markin
+//
+// SPDX-License-Identifier: MIT
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! XEP-0114 external component serving multiple bot addresses.
+
+use crate::xmpp::disco::component_info;
+use futures::StreamExt;
+use std::{collections::HashMap, time::Duration};
+use tokio::sync::mpsc::{channel, error::TrySendError, Receiver, Sender};
+use tokio_xmpp::{tcp::TcpComponent, Error, Event};
+use xmpp_parsers::{
+    disco::{DiscoInfoQuery, DiscoItemsQuery, DiscoItemsResult, Item},
+    iq::{Iq, IqType},
+    jid::{BareJid, Jid},
+    minidom::{Element, Node},
+    ns,
+    stanza_error::{DefinedCondition, ErrorType, StanzaError},
+};
+
+// Log target for this file.
+const LOG_TARGET: &str = "jutella::component";
+
+// Delay before reconnecting to XMPP server.
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+
+// Events channel size of a single bot.
+const EVENTS_CHANNEL_SIZE: usize = 1024;
+
+// Outgoing stanzas channel size shared by all bots.
+const STANZAS_CHANNEL_SIZE: usize = 1024;
+
+/// Component connection shared by the bots, routing the stanzas by their address.
+pub struct ComponentRouter {
+    domain: BareJid,
+    secret: String,
+    address: String,
+    bots: HashMap<BareJid, Sender<Event>>,
+    stanza_tx: Sender<Element>,
+    stanza_rx: Receiver<Element>,
+}
+
+impl ComponentRouter {
+    pub fn new(domain: BareJid, secret: String, address: String) -> Self {
+        let (stanza_tx, stanza_rx) = channel(STANZAS_CHANNEL_SIZE);
+
+        Self {
+            domain,
+            secret,
+            address,
+            bots: HashMap::new(),
+            stanza_tx,
+            stanza_rx,
+        }
+    }
+
+    /// Register bot address and return its end of the connection.
+    pub fn link(&mut self, jid: BareJid) -> ComponentLink {
+        let (event_tx, event_rx) = channel(EVENTS_CHANNEL_SIZE);
+        self.bots.insert(jid.clone(), event_tx);
+
+        ComponentLink {
+            jid,
+            event_rx,
+            stanza_tx: self.stanza_tx.clone(),
+        }
+    }
+
+    pub async fn run(self) -> anyhow::Result<()> {
+        let Self {
+            domain,
+            secret,
+            address,
+            bots,
+            stanza_tx,
+            mut stanza_rx,
+        } = self;
+        // Only the links keep the channel open.
+        drop(stanza_tx);
+
+        let mut online = true;
+
+        loop {
+            let mut component =
+                match TcpComponent::new(domain.as_str(), &secret, address.clone()).await {
+                    Ok(component) => component,
+                    Err(error) => {
+                        // Make sure to not spam with error during every reconnection attemp.
+                        if online {
+                            tracing::error!(
+                                target: LOG_TARGET,
+                                ?error,
+                                "failed to connect to XMPP server, reconnecting",
+                            );
+                            online = false;
+                        }
+                        tokio::time::sleep(RECONNECT_DELAY).await;
+                        continue;
+                    }
+                };
+
+            tracing::info!(target: LOG_TARGET, domain = domain.as_str(), "component connected");
+            online = true;
+
+            for (jid, event_tx) in &bots {
+                let event = Event::Online {
+                    bound_jid: jid.clone().into(),
+                    resumed: false,
+                };
+                deliver(jid, event_tx, event);
+            }
+
+            loop {
+                tokio::select! {
+                    stanza = component.next() => {
+                        let Some(stanza) = stanza else {
+                            break
+                        };
+                        let stanza = rename_ns(stanza, ns::COMPONENT_ACCEPT, ns::JABBER_CLIENT);
+
+                        if let Some(reply) = route(&domain, &bots, stanza) {
+                            let reply = rename_ns(reply, ns::JABBER_CLIENT, ns::COMPONENT_ACCEPT);
+                            if component.send_stanza(reply).await.is_err() {
+                                break
+                            }
+                        }
+                    }
+                    stanza = stanza_rx.recv() => {
+                        let Some(stanza) = stanza else {
+                            tracing::debug!(target: LOG_TARGET, "all bots terminated");
+                            return Ok(())
+                        };
+                        let stanza = rename_ns(stanza, ns::JABBER_CLIENT, ns::COMPONENT_ACCEPT);
+
+                        if let Err(error) = component.send_stanza(stanza).await {
+                            tracing::debug!(target: LOG_TARGET, ?error, "failed to send stanza");
+                            break
+                        }
+                    }
+                }
+            }
+
+            tracing::error!(target: LOG_TARGET, "component disconnected, reconnecting");
+
+            for (jid, event_tx) in &bots {
+                deliver(jid, event_tx, Event::Disconnected(Error::Disconnected));
+            }
+
+            tokio::time::sleep(RECONNECT_DELAY).await;
+        }
+    }
+}
+
+/// Pass the event to the bot without waiting.
+///
+/// The bot may itself be waiting for room in the outgoing stanzas channel, which only the router
+/// drains, so waiting here could deadlock both. The event is dropped if the bot is that far behind.
+fn deliver(jid: &BareJid, event_tx: &Sender<Event>, event: Event) {
+    match event_tx.try_send(event) {
+        Ok(()) => {}
+        Err(TrySendError::Full(_)) => {
+            tracing::warn!(
+                target: LOG_TARGET,
+                jid = jid.as_str(),
+                size = EVENTS_CHANNEL_SIZE,
+                "bot events channel clogged, dropping event",
+            );
+        }
+        Err(TrySendError::Closed(_)) => {
+            tracing::debug!(
+                target: LOG_TARGET,
+                jid = jid.as_str(),
+                "bot terminated, dropping event",
+            );
+        }
+    }
+}
+
+/// Deliver stanza to the bot it is addressed to. Returns the reply to send back if the stanza is
+/// addressed to the component domain or to an unknown address.
+fn route(
+    domain: &BareJid,
+    bots: &HashMap<BareJid, Sender<Event>>,
+    stanza: Element,
+) -> Option<Element> {
+    let to = stanza.attr("to").and_then(|to| Jid::new(to).ok());
+
+    if let Some((jid, event_tx)) = to.as_ref().and_then(|to| bots.get_key_value(&to.to_bare())) {
+        deliver(jid, event_tx, Event::Stanza(stanza));
+        return None;
+    }
+
+    if to.as_ref().is_some_and(|to| to == domain) {
+        return domain_reply(domain, bots, stanza);
+    }
+
+    tracing::debug!(target: LOG_TARGET, ?to, "stanza to unknown address");
+
+    let is_request = stanza.is("iq", ns::JABBER_CLIENT)
+        && matches!(stanza.attr("type"), Some("get") | Some("set"));
+    let from = stanza.attr("from").and_then(|from| Jid::new(from).ok());
+
+    match (is_request, from, to, stanza.attr("id")) {
+        (true, Some(from), Some(to), Some(id)) => {
+            Some(iq_error(id, to, from, DefinedCondition::ServiceUnavailable))
+        }
+        _ => None,
+    }
+}
+
+/// Answer service discovery queries to the component domain, listing the bots as its items.
+fn domain_reply(
+    domain: &BareJid,
+    bots: &HashMap<BareJid, Sender<Event>>,
+    stanza: Element,
+) -> Option<Element> {
+    let Ok(Iq {
+        from: Some(from),
+        id,
+        payload,
+        ..
+    }) = Iq::try_from(stanza)
+    else {
+        return None;
+    };
+
+    let payload = match payload {
+        IqType::Get(payload) => payload,
+        IqType::Set(_) => {
+            return Some(iq_error(
+                &id,
+                domain.clone().into(),
+                from,
+                DefinedCondition::ServiceUnavailable,
+            ))
+        }
+        IqType::Result(_) | IqType::Error(_) => return None,
+    };
+
+    let result: Element = match (
+        DiscoInfoQuery::try_from(payload.clone()),
+        DiscoItemsQuery::try_from(payload),
+    ) {
+        (Ok(DiscoInfoQuery { node: None }), _) => component_info().into(),
+        (_, Ok(DiscoItemsQuery { node: None, .. })) => {
+            let mut jids = bots.keys().collect::<Vec<_>>();
+            jids.sort();
+
+            DiscoItemsResult {
+                node: None,
+                items: jids
+                    .into_iter()
+                    .map(|jid| Item {
+                        jid: jid.clone().into(),
+                        node: None,
+                        name: None,
+                    })
+                    .collect(),
+                rsm: None,
+            }
+            .into()
+        }
+        (Ok(_), _) | (_, Ok(_)) => {
+            return Some(iq_error(
+                &id,
+                domain.clone().into(),
+                from,
+                DefinedCondition::ItemNotFound,
+            ))
+        }
+        (Err(_), Err(_)) => {
+            return Some(iq_error(
+                &id,
+                domain.clone().into(),
+                from,
+                DefinedCondition::ServiceUnavailable,
+            ))
+        }
+    };
+
+    let iq = Iq {
+        from: Some(domain.clone().into()),
+        to: Some(from),
+        id,
+        payload: IqType::Result(Some(result)),
+    };
+
+    Some(iq.into())
+}
+
+/// Error reply to the `iq` request.
+fn iq_error(id: &str, from: Jid, to: Jid, condition: DefinedCondition) -> Element {
+    let mut error = StanzaError::new(ErrorType::Cancel, condition, "en", "");
+    error.texts.clear();
+
+    Iq::from_error(id, error).with_from(from).with_to(to).into()
+}
+
+/// Change the namespace of the stanza and its children in the same namespace.
+fn rename_ns(mut element: Element, from: &str, to: &str) -> Element {
+    if element.ns() != from {
+        return element;
+    }
+
+    let nodes = element.take_nodes();
+    let builder = element.attrs().fold(
+        Element::builder(element.name(), to),
+        |builder, (name, value)| builder.attr(name, value),
+    );
+
+    builder
+        .append_all(nodes.into_iter().map(|node| match node {
+            Node::Element(child) => Node::Element(rename_ns(child, from, to)),
+            node => node,
+        }))
+        .build()
+}
+
+/// Bot's end of the component connection.
+#[derive(Debug)]
+pub struct ComponentLink {
+    jid: BareJid,
+    event_rx: Receiver<Event>,
+    stanza_tx: Sender<Element>,
+}
+
+impl ComponentLink {
+    /// Send stanza from the bot address.
+    pub async fn send_stanza(&mut self, mut stanza: Element) -> Result<(), Error> {
+        if stanza.attr("from").is_none() {
+            stanza.set_attr("from", self.jid.as_str());
+        }
+
+        self.stanza_tx
+            .send(stanza)
+            .await
+            .map_err(|_| Error::Disconnected)
+    }
+
+    pub async fn next(&mut self) -> Option<Event> {
+        self.event_rx.recv().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use xmpp_parsers::disco::DiscoInfoResult;
+
+    fn bots(jids: &[&str]) -> (HashMap<BareJid, Sender<Event>>, Vec<Receiver<Event>>) {
+        jids.iter()
+            .map(|jid| {
+                let (event_tx, event_rx) = channel(1);
+                ((BareJid::new(jid).unwrap(), event_tx), event_rx)
+            })
+            .unzip()
+    }
+
+    fn iq_get(to: &str, query: &str) -> Element {
+        format!(
+            "<iq xmlns='jabber:client' type='get' id='q1' from='user@example.org/res' \
+             to='{to}'><query xmlns='{query}'/></iq>"
+        )
+        .parse()
+        .unwrap()
+    }
+
+    #[test]
+    fn domain_disco_info() {
+        let domain = BareJid::new("bots.example.org").unwrap();
+        let (bots, _event_rxs) = bots(&["a@bots.example.org"]);
+
+        let reply = route(&domain, &bots, iq_get("bots.example.org", ns::DISCO_INFO)).unwrap();
+        let Iq {
+            from, to, payload, ..
+        } = Iq::try_from(reply).unwrap();
+        assert_eq!(from, Some(domain.into()));
+        assert_eq!(to, Some(Jid::new("user@example.org/res").unwrap()));
+
+        let IqType::Result(Some(payload)) = payload else {
+            panic!("expected result, got {payload:?}");
+        };
+        let info = DiscoInfoResult::try_from(payload).unwrap();
+        assert_eq!(info.identities[0].category, "component");
+    }
+
+    #[test]
+    fn domain_disco_items_list_bots() {
+        let domain = BareJid::new("bots.example.org").unwrap();
+        let (bots, _event_rxs) = bots(&["b@bots.example.org", "a@bots.example.org"]);
+
+        let reply = route(&domain, &bots, iq_get("bots.example.org", ns::DISCO_ITEMS)).unwrap();
+        let IqType::Result(Some(payload)) = Iq::try_from(reply).unwrap().payload else {
+            panic!("expected result");
+        };
+        let items = DiscoItemsResult::try_from(payload).unwrap();
+        assert_eq!(
+            items
+                .items
+                .iter()
+                .map(|item| item.jid.to_string())
+                .collect::<Vec<_>>(),
+            vec!["a@bots.example.org", "b@bots.example.org"],
+        );
+    }
+
+    #[test]
+    fn unknown_address_unavailable() {
+        let domain = BareJid::new("bots.example.org").unwrap();
+        let (bots, _event_rxs) = bots(&["a@bots.example.org"]);
+
+        let reply = route(&domain, &bots, iq_get("c@bots.example.org", ns::DISCO_INFO)).unwrap();
+        let IqType::Error(error) = Iq::try_from(reply).unwrap().payload else {
+            panic!("expected error");
+        };
+        assert_eq!(
+            error.defined_condition,
+            DefinedCondition::ServiceUnavailable
+        );
+    }
+
+    #[test]
+    fn clogged_bot_does_not_block() {
+        let domain = BareJid::new("bots.example.org").unwrap();
+        let (bots, mut event_rxs) = bots(&["a@bots.example.org"]);
+
+        // The channel holds one event, the second one is dropped instead of waiting.
+        for _ in 0..2 {
+            let stanza = iq_get("a@bots.example.org/res", ns::PING);
+            assert!(route(&domain, &bots, stanza).is_none());
+        }
+
+        assert!(matches!(event_rxs[0].try_recv(), Ok(Event::Stanza(_))));
+        assert!(event_rxs[0].try_recv().is_err());
+    }
+}
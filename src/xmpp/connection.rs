@@ -0,0 +1,102 @@
+// Copyright (c) 2024 Dmitry Markin
+//
+// SPDX-License-Identifier: MIT
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! Connection to the XMPP server as a client or through an external component.
+
+use crate::xmpp::{component::ComponentLink, connector::Connector};
+use futures::StreamExt;
+use std::fmt;
+use tokio_xmpp::{AsyncClient as XmppClient, AsyncConfig, Error, Event};
+use xmpp_parsers::{jid::BareJid, minidom::Element};
+
+/// Client connection with the parameters to reconnect with.
+pub struct ClientConnection {
+    client: XmppClient<Connector>,
+    jid: BareJid,
+    password: String,
+    connector: Connector,
+}
+
+/// XMPP connection of a single bot.
+pub enum Connection {
+    /// Client connection to a regular account.
+    Client(Box<ClientConnection>),
+    /// Bot address served by XEP-0114 external component.
+    Component(ComponentLink),
+}
+
+impl Connection {
+    pub fn client(jid: BareJid, password: String, connector: Connector) -> Self {
+        Connection::Client(Box::new(ClientConnection {
+            client: new_client(&jid, &password, &connector),
+            jid,
+            password,
+            connector,
+        }))
+    }
+
+    /// Start a new client connection. Component connection is reestablished by
+    /// `ComponentRouter`.
+    pub fn reconnect(&mut self) {
+        if let Connection::Client(connection) = self {
+            connection.client =
+                new_client(&connection.jid, &connection.password, &connection.connector);
+        }
+    }
+
+    pub async fn send_stanza(&mut self, stanza: Element) -> Result<(), Error> {
+        match self {
+            Connection::Client(connection) => connection.client.send_stanza(stanza).await,
+            Connection::Component(link) => link.send_stanza(stanza).await,
+        }
+    }
+
+    pub async fn next(&mut self) -> Option<Event> {
+        match self {
+            Connection::Client(connection) => connection.client.next().await,
+            Connection::Component(link) => link.next().await,
+        }
+    }
+
+    pub fn is_component(&self) -> bool {
+        matches!(self, Connection::Component(_))
+    }
+}
+
+impl fmt::Debug for Connection {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Connection::Client(connection) => {
+                f.debug_tuple("Client").field(&connection.jid).finish()
+            }
+            Connection::Component(link) => f.debug_tuple("Component").field(link).finish(),
+        }
+    }
+}
+
+fn new_client(jid: &BareJid, password: &str, connector: &Connector) -> XmppClient<Connector> {
+    XmppClient::new_with_config(AsyncConfig {
+        jid: jid.clone().into(),
+        password: password.to_string(),
+        server: connector.clone(),
+    })
+}
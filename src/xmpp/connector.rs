@@ -0,0 +1,549 @@
+// Copyright (c) 2024 Dmitry Markin
+//
+// SPDX-License-Identifier: MIT
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! Client connection setup: server address, TLS mode and server certificate verification.
+
+use crate::config::{ServerSettings, TlsMode};
+use anyhow::{anyhow, Context as _};
+use futures::{SinkExt, StreamExt};
+use hickory_resolver::{IntoName, TokioAsyncResolver};
+use rustls::{
+    client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+    crypto::{verify_tls12_signature, verify_tls13_signature, CryptoProvider},
+    pki_types::{pem::PemObject, CertificateDer, ServerName, UnixTime},
+    ClientConfig, DigitallySignedStruct, RootCertStore, SignatureScheme,
+};
+use sasl::common::ChannelBinding;
+use sha2::{Digest, Sha256};
+use std::{
+    collections::hash_map::RandomState,
+    fmt,
+    hash::BuildHasher,
+    io,
+    net::SocketAddr,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    net::TcpStream,
+};
+use tokio_rustls::{client::TlsStream, TlsConnector};
+use tokio_xmpp::{
+    connect::{ServerConnector, ServerConnectorError},
+    xmpp_stream::XMPPStream,
+    Packet,
+};
+use xmpp_parsers::{jid::Jid, minidom::Element, ns};
+
+// Log target for this file.
+const LOG_TARGET: &str = "jutella::connector";
+
+// Default ports for STARTTLS and direct TLS connections.
+const STARTTLS_PORT: u16 = 5222;
+const DIRECT_TLS_PORT: u16 = 5223;
+
+// ALPN protocol of XEP-0368 direct TLS connections.
+const DIRECT_TLS_ALPN: &[u8] = b"xmpp-client";
+
+/// Connection error.
+#[derive(Debug)]
+pub enum ConnectorError {
+    Resolve(String),
+    Io(io::Error),
+    Xmpp(tokio_xmpp::Error),
+    NoTls,
+    NotLocalhost(SocketAddr),
+}
+
+impl fmt::Display for ConnectorError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConnectorError::Resolve(error) => write!(f, "failed to resolve server: {error}"),
+            ConnectorError::Io(error) => write!(f, "IO error: {error}"),
+            ConnectorError::Xmpp(error) => write!(f, "XMPP error: {error}"),
+            ConnectorError::NoTls => write!(f, "server doesn't support STARTTLS"),
+            ConnectorError::NotLocalhost(addr) => {
+                write!(
+                    f,
+                    "refusing unencrypted connection to non-loopback address {addr}"
+                )
+            }
+        }
+    }
+}
+
+impl std::error::Error for ConnectorError {}
+
+impl ServerConnectorError for ConnectorError {}
+
+impl From<io::Error> for ConnectorError {
+    fn from(error: io::Error) -> Self {
+        ConnectorError::Io(error)
+    }
+}
+
+impl From<tokio_xmpp::Error> for ConnectorError {
+    fn from(error: tokio_xmpp::Error) -> Self {
+        ConnectorError::Xmpp(error)
+    }
+}
+
+/// `tokio_xmpp` connector honoring [`ServerSettings`].
+#[derive(Debug, Clone)]
+pub struct Connector {
+    host: Option<String>,
+    port: Option<u16>,
+    tls: TlsMode,
+    tls_config: Arc<ClientConfig>,
+}
+
+impl Connector {
+    pub fn new(settings: ServerSettings) -> anyhow::Result<Self> {
+        let ServerSettings {
+            host,
+            port,
+            tls,
+            ca_certificate,
+            pinned_certificate,
+        } = settings;
+
+        let builder = ClientConfig::builder();
+        let mut tls_config = match (ca_certificate, pinned_certificate) {
+            (None, None) => builder
+                .with_root_certificates(RootCertStore {
+                    roots: webpki_roots::TLS_SERVER_ROOTS.into(),
+                })
+                .with_no_client_auth(),
+            (Some(path), None) => {
+                let mut roots = RootCertStore::empty();
+                for certificate in CertificateDer::pem_file_iter(&path)
+                    .with_context(|| anyhow!("Failed to read CA certificate {}", path.display()))?
+                {
+                    let certificate = certificate
+                        .with_context(|| anyhow!("Invalid CA certificate {}", path.display()))?;
+                    roots
+                        .add(certificate)
+                        .with_context(|| anyhow!("Invalid CA certificate {}", path.display()))?;
+                }
+                builder.with_root_certificates(roots).with_no_client_auth()
+            }
+            (None, Some(fingerprint)) => {
+                let verifier = PinnedCertificateVerifier {
+                    fingerprint: parse_fingerprint(&fingerprint)?,
+                    provider: builder.crypto_provider().clone(),
+                };
+                builder
+                    .dangerous()
+                    .with_custom_certificate_verifier(Arc::new(verifier))
+                    .with_no_client_auth()
+            }
+            (Some(_), Some(_)) => {
+                return Err(anyhow!(
+                    "Only one of `tls_ca_certificate` or `tls_pinned_certificate` can be supplied"
+                ))
+            }
+        };
+
+        if tls == TlsMode::Direct {
+            tls_config.alpn_protocols = vec![DIRECT_TLS_ALPN.to_vec()];
+        }
+
+        Ok(Self {
+            host,
+            port,
+            tls,
+            tls_config: Arc::new(tls_config),
+        })
+    }
+
+    async fn connect_tcp(&self, domain: &str) -> Result<TcpStream, ConnectorError> {
+        let (srv, default_port) = match self.tls {
+            TlsMode::Direct => ("_xmpps-client._tcp", DIRECT_TLS_PORT),
+            TlsMode::StartTls | TlsMode::NoneForLocalhost => ("_xmpp-client._tcp", STARTTLS_PORT),
+        };
+
+        if let Some(host) = &self.host {
+            return connect_to_host(host, self.port.unwrap_or(default_port)).await;
+        }
+
+        let resolver = TokioAsyncResolver::tokio_from_system_conf()
+            .map_err(|error| ConnectorError::Resolve(error.to_string()))?;
+        let srv_name = format!("{srv}.{domain}.")
+            .into_name()
+            .map_err(|error| ConnectorError::Resolve(error.to_string()))?;
+
+        match resolver.srv_lookup(srv_name).await {
+            Ok(lookup) => {
+                let records = lookup
+                    .iter()
+                    .map(|record| SrvTarget {
+                        priority: record.priority(),
+                        weight: record.weight(),
+                        host: record.target().to_ascii(),
+                        port: record.port(),
+                    })
+                    .collect();
+                let random_state = RandomState::new();
+                let mut counter = 0u64;
+                let records = srv_order(records, || {
+                    counter += 1;
+                    random_state.hash_one(counter)
+                });
+
+                let mut last_error = ConnectorError::Resolve("no SRV records".to_string());
+                for SrvTarget { host, port, .. } in records {
+                    match connect_to_host(host.trim_end_matches('.'), port).await {
+                        Ok(stream) => return Ok(stream),
+                        Err(error) => {
+                            tracing::debug!(target: LOG_TARGET, host, ?error, "connection failed");
+                            last_error = error;
+                        }
+                    }
+                }
+                Err(last_error)
+            }
+            Err(_) => connect_to_host(domain, self.port.unwrap_or(default_port)).await,
+        }
+    }
+
+    async fn connect_tls(
+        &self,
+        stream: TcpStream,
+        jid: &Jid,
+    ) -> Result<TlsStream<TcpStream>, ConnectorError> {
+        let domain = ServerName::try_from(jid.domain().to_string())
+            .map_err(|error| ConnectorError::Resolve(error.to_string()))?;
+
+        Ok(TlsConnector::from(self.tls_config.clone())
+            .connect(domain, stream)
+            .await?)
+    }
+}
+
+impl ServerConnector for Connector {
+    type Stream = ServerStream;
+    type Error = ConnectorError;
+
+    async fn connect(&self, jid: &Jid, ns: &str) -> Result<XMPPStream<Self::Stream>, Self::Error> {
+        let stream = self.connect_tcp(jid.domain().as_str()).await?;
+
+        let stream = match self.tls {
+            TlsMode::StartTls => {
+                let xmpp_stream = XMPPStream::start(stream, jid.clone(), ns.to_owned()).await?;
+                let stream = starttls(xmpp_stream).await?;
+                ServerStream::Tls(Box::new(self.connect_tls(stream, jid).await?))
+            }
+            TlsMode::Direct => ServerStream::Tls(Box::new(self.connect_tls(stream, jid).await?)),
+            TlsMode::NoneForLocalhost => {
+                let addr = stream.peer_addr()?;
+                if !addr.ip().is_loopback() {
+                    return Err(ConnectorError::NotLocalhost(addr));
+                }
+                ServerStream::Plain(stream)
+            }
+        };
+
+        Ok(XMPPStream::start(stream, jid.clone(), ns.to_owned()).await?)
+    }
+
+    fn channel_binding(stream: &Self::Stream) -> Result<ChannelBinding, Self::Error> {
+        let ServerStream::Tls(stream) = stream else {
+            return Ok(ChannelBinding::None);
+        };
+        let (_, connection) = stream.get_ref();
+
+        match connection.protocol_version() {
+            Some(rustls::ProtocolVersion::TLSv1_3) => {
+                let data = connection
+                    .export_keying_material(vec![0u8; 32], b"EXPORTER-Channel-Binding", None)
+                    .map_err(|error| ConnectorError::Io(io::Error::other(error)))?;
+                Ok(ChannelBinding::TlsExporter(data))
+            }
+            _ => Ok(ChannelBinding::None),
+        }
+    }
+}
+
+/// Server address from the SRV record.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct SrvTarget {
+    priority: u16,
+    weight: u16,
+    host: String,
+    port: u16,
+}
+
+/// Order SRV records as per RFC 2782: by priority, and randomly weighted within the same
+/// priority. `random` returns uniformly distributed numbers.
+fn srv_order(mut records: Vec<SrvTarget>, mut random: impl FnMut() -> u64) -> Vec<SrvTarget> {
+    // Zero weight records go first to have a small chance to be selected.
+    records.sort_by_key(|record| (record.priority, record.weight != 0));
+
+    let mut ordered = Vec::with_capacity(records.len());
+    while !records.is_empty() {
+        let priority = records[0].priority;
+        let same_priority = records
+            .iter()
+            .take_while(|record| record.priority == priority)
+            .count();
+        let total = records[..same_priority]
+            .iter()
+            .map(|record| u64::from(record.weight))
+            .sum::<u64>();
+
+        let threshold = random() % (total + 1);
+        let mut running_sum = 0;
+        let selected = records[..same_priority]
+            .iter()
+            .position(|record| {
+                running_sum += u64::from(record.weight);
+                running_sum >= threshold
+            })
+            .expect("running sum reaches total >= threshold; qed");
+
+        ordered.push(records.remove(selected));
+    }
+
+    ordered
+}
+
+async fn connect_to_host(host: &str, port: u16) -> Result<TcpStream, ConnectorError> {
+    tracing::debug!(target: LOG_TARGET, host, port, "connecting to XMPP server");
+
+    Ok(TcpStream::connect((host, port)).await?)
+}
+
+/// Negotiate STARTTLS and return the underlying stream ready for the TLS handshake.
+async fn starttls(mut xmpp_stream: XMPPStream<TcpStream>) -> Result<TcpStream, ConnectorError> {
+    if !xmpp_stream.stream_features.can_starttls() {
+        return Err(ConnectorError::NoTls);
+    }
+
+    xmpp_stream
+        .send(Packet::Stanza(
+            Element::builder("starttls", ns::TLS).build(),
+        ))
+        .await?;
+
+    loop {
+        match xmpp_stream.next().await {
+            Some(Ok(Packet::Stanza(stanza))) if stanza.is("proceed", ns::TLS) => break,
+            Some(Ok(Packet::Text(_))) => {}
+            Some(Err(error)) => return Err(error.into()),
+            _ => return Err(ConnectorError::NoTls),
+        }
+    }
+
+    Ok(xmpp_stream.into_inner())
+}
+
+/// Plain or TLS-encrypted server connection.
+pub enum ServerStream {
+    Plain(TcpStream),
+    Tls(Box<TlsStream<TcpStream>>),
+}
+
+impl AsyncRead for ServerStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            ServerStream::Plain(stream) => Pin::new(stream).poll_read(cx, buf),
+            ServerStream::Tls(stream) => Pin::new(stream.as_mut()).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for ServerStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            ServerStream::Plain(stream) => Pin::new(stream).poll_write(cx, buf),
+            ServerStream::Tls(stream) => Pin::new(stream.as_mut()).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            ServerStream::Plain(stream) => Pin::new(stream).poll_flush(cx),
+            ServerStream::Tls(stream) => Pin::new(stream.as_mut()).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            ServerStream::Plain(stream) => Pin::new(stream).poll_shutdown(cx),
+            ServerStream::Tls(stream) => Pin::new(stream.as_mut()).poll_shutdown(cx),
+        }
+    }
+}
+
+/// Accept only the server certificate with the given SHA-256 fingerprint.
+#[derive(Debug)]
+struct PinnedCertificateVerifier {
+    fingerprint: Vec<u8>,
+    provider: Arc<CryptoProvider>,
+}
+
+/// Parse SHA-256 fingerprint in hex, optionally with colons between the bytes.
+fn parse_fingerprint(fingerprint: &str) -> anyhow::Result<Vec<u8>> {
+    let hex = fingerprint.replace(':', "");
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| {
+            hex.get(i..i + 2)
+                .and_then(|byte| u8::from_str_radix(byte, 16).ok())
+        })
+        .collect::<Option<Vec<_>>>()
+        .filter(|fingerprint| fingerprint.len() == 32)
+        .ok_or_else(|| anyhow!("`tls_pinned_certificate` must be a SHA-256 fingerprint"))
+}
+
+impl ServerCertVerifier for PinnedCertificateVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        if Sha256::digest(end_entity.as_ref())[..] == self.fingerprint[..] {
+            Ok(ServerCertVerified::assertion())
+        } else {
+            Err(rustls::Error::General(
+                "server certificate doesn't match the pinned fingerprint".to_string(),
+            ))
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider
+            .signature_verification_algorithms
+            .supported_schemes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn target(priority: u16, weight: u16, host: &str) -> SrvTarget {
+        SrvTarget {
+            priority,
+            weight,
+            host: host.to_string(),
+            port: 5222,
+        }
+    }
+
+    fn hosts(records: &[SrvTarget]) -> Vec<&str> {
+        records.iter().map(|record| record.host.as_str()).collect()
+    }
+
+    #[test]
+    fn fingerprint_formats() {
+        let hex = "AB".repeat(32);
+        let colons = vec!["ab"; 32].join(":");
+
+        assert_eq!(parse_fingerprint(&hex).unwrap(), vec![0xab; 32]);
+        assert_eq!(parse_fingerprint(&colons).unwrap(), vec![0xab; 32]);
+    }
+
+    #[test]
+    fn invalid_fingerprints() {
+        // SHA-1 length.
+        assert!(parse_fingerprint(&"ab".repeat(20)).is_err());
+        // Odd number of digits.
+        assert!(parse_fingerprint(&format!("{}a", "ab".repeat(32))).is_err());
+        assert!(parse_fingerprint(&"zz".repeat(32)).is_err());
+        assert!(parse_fingerprint(&"ä".repeat(32)).is_err());
+        assert!(parse_fingerprint("").is_err());
+    }
+
+    #[test]
+    fn srv_by_priority() {
+        let records = vec![
+            target(20, 0, "c"),
+            target(10, 5, "a"),
+            target(30, 100, "d"),
+            target(15, 0, "b"),
+        ];
+
+        assert_eq!(hosts(&srv_order(records, || 0)), vec!["a", "b", "c", "d"]);
+    }
+
+    #[test]
+    fn srv_weighted_within_priority() {
+        let records = vec![target(10, 1, "a"), target(10, 3, "b"), target(10, 0, "z")];
+
+        // Zero weight record is only selected with zero threshold.
+        assert_eq!(
+            hosts(&srv_order(records.clone(), || 0)),
+            vec!["z", "a", "b"]
+        );
+        // Thresholds 1 and 2..=4 select the first and the second weighted records.
+        assert_eq!(
+            hosts(&srv_order(records.clone(), || 1)),
+            vec!["a", "b", "z"]
+        );
+        assert_eq!(
+            hosts(&srv_order(records.clone(), || 4)),
+            vec!["b", "z", "a"]
+        );
+    }
+}
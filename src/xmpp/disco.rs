@@ -0,0 +1,117 @@
+// Copyright (c) 2024 Dmitry Markin
+//
+// SPDX-License-Identifier: MIT
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! XEP-0030 service discovery and XEP-0115 entity capabilities.
+
+use crate::xmpp::commands::NS_COMMANDS;
+use xmpp_parsers::{
+    caps::{compute_disco, hash_caps, query_caps, Caps},
+    disco::{DiscoInfoResult, Feature, Identity},
+    hashes::Algo,
+    ns,
+};
+
+/// Entity capabilities node identifying the software.
+const CAPS_NODE: &str = "https://github.com/dmitry-markin/jutella-xmpp";
+
+/// Name of the bot advertised in service discovery.
+const IDENTITY_NAME: &str = "jutellaxmpp";
+
+/// Features supported by the bot.
+const FEATURES: &[&str] = &[
+    ns::CAPS,
+    ns::CARBONS,
+    ns::CHATSTATES,
+    ns::DISCO_INFO,
+    ns::DISCO_ITEMS,
+    ns::OOB,
+    ns::PING,
+    ns::RECEIPTS,
+    ns::REACTIONS,
+    ns::VERSION,
+    NS_COMMANDS,
+    "urn:xmpp:chat-markers:0",
+];
+
+/// `disco#info` of the component domain serving the bots.
+pub fn component_info() -> DiscoInfoResult {
+    DiscoInfoResult {
+        node: None,
+        identities: vec![Identity::new("component", "generic", "en", IDENTITY_NAME)],
+        features: [ns::DISCO_INFO, ns::DISCO_ITEMS]
+            .into_iter()
+            .map(Feature::new)
+            .collect(),
+        extensions: Vec::new(),
+    }
+}
+
+/// Service discovery info and the entity capabilities derived from it.
+#[derive(Debug, Clone)]
+pub struct DiscoInfo {
+    info: DiscoInfoResult,
+    caps: Caps,
+    caps_node: String,
+}
+
+impl DiscoInfo {
+    pub fn new() -> Self {
+        let info = DiscoInfoResult {
+            node: None,
+            identities: vec![Identity::new("client", "bot", "en", IDENTITY_NAME)],
+            features: FEATURES.iter().copied().map(Feature::new).collect(),
+            extensions: Vec::new(),
+        };
+
+        let hash = hash_caps(&compute_disco(&info), Algo::Sha_1)
+            .expect("SHA-1 is a supported caps hash algorithm; qed");
+        let caps = Caps::new(CAPS_NODE, hash);
+        let caps_node = query_caps(caps.clone())
+            .node
+            .expect("caps query always has a node; qed");
+
+        Self {
+            info,
+            caps,
+            caps_node,
+        }
+    }
+
+    /// Entity capabilities to attach to presence.
+    pub fn caps(&self) -> Caps {
+        self.caps.clone()
+    }
+
+    /// `disco#info` response for the requested node, if the node is known.
+    ///
+    /// Clients query either the bare entity or the `node#ver` node from the caps.
+    pub fn info(&self, node: Option<String>) -> Option<DiscoInfoResult> {
+        match node {
+            None => Some(self.info.clone()),
+            Some(node) if node == self.caps_node => Some(DiscoInfoResult {
+                node: Some(node),
+                ..self.info.clone()
+            }),
+            Some(_) => None,
+        }
+    }
+}
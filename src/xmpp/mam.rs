@@ -0,0 +1,324 @@
+// Copyright (c) 2024 Dmitry Markin
+//
+// SPDX-License-Identifier: MIT
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! XEP-0313 message archive management: catch-up and conversation history.
+
+use crate::{
+    message::{ConversationTurn, RequestMessage},
+    xmpp::chat_commands::ChatCommand,
+};
+use std::{
+    collections::{HashMap, VecDeque},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tokio::time::Instant;
+use xmpp_parsers::{
+    data_forms::{DataForm, DataFormType, Field},
+    delay::Delay,
+    jid::BareJid,
+    mam::{Query, QueryId, Result_},
+    message::{Message as XmppMessage, MessageType},
+    ns,
+    rsm::SetQuery,
+    stanza_id::StanzaId,
+};
+
+// Log target for this file.
+const LOG_TARGET: &str = "jutella::mam";
+
+/// State file with the last processed stanza id.
+pub const MAM_STATE_FILE: &str = "mam.json";
+
+// Number of archived messages to request at once.
+const PAGE_SIZE: usize = 50;
+
+/// Time the archive catch-up may take before live messages are processed regardless.
+pub const CATCH_UP_TIMEOUT: Duration = Duration::from_secs(60);
+
+// Number of the most recent messages to restore the conversation history from.
+const HISTORY_SIZE: usize = 100;
+
+/// Time to wait for the conversation history before submitting the request without it.
+pub const HISTORY_QUERY_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Persisted archive position and the responses to restore the history from.
+#[derive(Debug, Default, serde::Serialize, serde::Deserialize)]
+pub struct MamState {
+    pub last_stanza_id: Option<String>,
+    #[serde(default)]
+    pub responses: SentResponses,
+}
+
+/// Chatbot response as returned by the model, with the ids of the messages it was sent in.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+struct SentResponse {
+    ids: Vec<String>,
+    response: String,
+}
+
+/// Recent chatbot responses per user.
+///
+/// The history is restored from these instead of the archived bodies, which are split, styled,
+/// or replaced by upload links, and can't be told apart from the other messages of the bot.
+#[derive(Debug, Default, Clone, serde::Serialize, serde::Deserialize)]
+pub struct SentResponses(HashMap<String, VecDeque<SentResponse>>);
+
+impl SentResponses {
+    pub fn insert(&mut self, jid: &str, ids: Vec<String>, response: String) {
+        let responses = self.0.entry(jid.to_string()).or_default();
+        if responses.len() >= HISTORY_SIZE {
+            responses.pop_front();
+        }
+        responses.push_back(SentResponse { ids, response });
+    }
+
+    /// Response sent in message `id` with the id of its first message.
+    fn get(&self, jid: &str, id: &str) -> Option<(&str, &str)> {
+        self.0.get(jid)?.iter().find_map(|sent| {
+            sent.ids.iter().any(|sent_id| sent_id == id).then(|| {
+                (
+                    sent.ids.first().map(String::as_str).unwrap_or_default(),
+                    sent.response.as_str(),
+                )
+            })
+        })
+    }
+}
+
+/// Ongoing archive catch-up.
+#[derive(Debug)]
+pub struct CatchUp {
+    pub query_id: String,
+    pub iq_id: String,
+    /// Live messages received during the catch-up, processed after the archived ones.
+    pub deferred: Vec<XmppMessage>,
+    /// Time to abort the catch-up at, over all the pages.
+    pub deadline: Instant,
+}
+
+/// Archive query for messages after `after` stanza id.
+pub fn archive_query(query_id: &str, after: String) -> Query {
+    Query {
+        queryid: Some(QueryId(query_id.to_string())),
+        node: None,
+        form: None,
+        set: Some(SetQuery {
+            max: Some(PAGE_SIZE),
+            after: Some(after),
+            before: None,
+            index: None,
+        }),
+        flip_page: false,
+    }
+}
+
+/// Archive query for the most recent messages exchanged with `with`.
+pub fn history_query(query_id: &str, with: &BareJid) -> Query {
+    Query {
+        queryid: Some(QueryId(query_id.to_string())),
+        node: None,
+        form: Some(DataForm::new(
+            DataFormType::Submit,
+            ns::MAM,
+            vec![Field::text_single("with", with.as_str())],
+        )),
+        // Empty `before` requests the last page.
+        set: Some(SetQuery {
+            max: Some(HISTORY_SIZE),
+            after: None,
+            before: Some(String::new()),
+            index: None,
+        }),
+        flip_page: false,
+    }
+}
+
+/// Ongoing conversation history query.
+#[derive(Debug)]
+pub struct HistoryQuery {
+    pub bare_jid: BareJid,
+    pub query_id: String,
+    pub iq_id: String,
+    pub history: History,
+    /// Requests waiting for the history to be restored.
+    pub pending: Vec<RequestMessage>,
+    /// Time to give up on the query at.
+    pub deadline: Instant,
+}
+
+/// Conversation turns reconstructed from the archived messages.
+#[derive(Debug, Default)]
+pub struct History {
+    turns: Vec<ConversationTurn>,
+    request: Vec<String>,
+    response: Vec<String>,
+    // Id of the first message of the last response added, to skip its other parts.
+    response_id: Option<String>,
+}
+
+impl History {
+    /// Add the next archived message of the conversation with `jid`.
+    ///
+    /// Our own messages are only taken into account if they carry a response from `responses`,
+    /// other messages of the bot (command replies, broadcasts, errors) are skipped.
+    pub fn push(
+        &mut self,
+        own_jid: &BareJid,
+        jid: &str,
+        responses: &SentResponses,
+        message: XmppMessage,
+    ) {
+        if message.type_ != MessageType::Chat {
+            return;
+        }
+        let Some(body) = message.bodies.get("").map(|body| body.0.as_str()) else {
+            return;
+        };
+
+        if message
+            .from
+            .as_ref()
+            .is_some_and(|from| from.to_bare() == *own_jid)
+        {
+            let Some((id, response)) = message.id.as_ref().and_then(|id| responses.get(jid, id))
+            else {
+                return;
+            };
+            if !self.request.is_empty() && self.response_id.as_deref() != Some(id) {
+                self.response.push(response.to_string());
+                self.response_id = Some(id.to_string());
+            }
+        } else {
+            if !self.response.is_empty() {
+                self.complete_turn();
+            }
+            if ChatCommand::parse(body).is_none() {
+                self.request.push(body.to_string());
+            }
+        }
+    }
+
+    /// Finish the history, dropping the trailing requests without a response.
+    pub fn finish(mut self) -> Vec<ConversationTurn> {
+        if !self.response.is_empty() {
+            self.complete_turn();
+        }
+
+        self.turns
+    }
+
+    fn complete_turn(&mut self) {
+        let request = std::mem::take(&mut self.request).join("\n\n");
+        let response = std::mem::take(&mut self.response).join("\n\n");
+
+        if !request.is_empty() && !response.starts_with("[ERROR]") {
+            self.turns.push(ConversationTurn { request, response });
+        }
+    }
+}
+
+/// Archived message with its stanza id and the time it was stored.
+#[derive(Debug)]
+pub struct ArchivedMessage {
+    pub query_id: String,
+    pub stanza_id: String,
+    pub message: Option<XmppMessage>,
+    pub timestamp: Option<i64>,
+}
+
+/// Extract archived message from the query result.
+///
+/// Returns `None` if the message is not a valid query result or was not sent by our own server.
+pub fn archived_message(own_jid: &BareJid, message: &mut XmppMessage) -> Option<ArchivedMessage> {
+    if message
+        .from
+        .as_ref()
+        .is_some_and(|from| from.to_bare() != *own_jid)
+    {
+        tracing::warn!(
+            target: LOG_TARGET,
+            from = ?message.from,
+            "archive result from foreign JID, ignoring",
+        );
+        return None;
+    }
+
+    let result = match message.extract_payload::<Result_>() {
+        Ok(Some(result)) => result,
+        Ok(None) => return None,
+        Err(error) => {
+            tracing::debug!(target: LOG_TARGET, ?error, "invalid archive result");
+            return None;
+        }
+    };
+
+    let Some(QueryId(query_id)) = result.queryid else {
+        tracing::debug!(target: LOG_TARGET, "archive result without query id");
+        return None;
+    };
+
+    Some(ArchivedMessage {
+        query_id,
+        stanza_id: result.id,
+        timestamp: result
+            .forwarded
+            .delay
+            .map(|delay| delay.stamp.0.timestamp()),
+        message: result.forwarded.stanza,
+    })
+}
+
+/// Whether the message is an archive query result.
+pub fn is_archive_result(message: &XmppMessage) -> bool {
+    message.payloads.iter().any(|p| p.is("result", ns::MAM))
+}
+
+/// Stanza id assigned to the message by our server's archive.
+pub fn stanza_id(own_jid: &BareJid, message: &XmppMessage) -> Option<String> {
+    message
+        .payloads
+        .iter()
+        .filter(|p| p.is("stanza-id", ns::SID))
+        .filter_map(|p| StanzaId::try_from(p.clone()).ok())
+        .find(|stanza_id| stanza_id.by.to_bare() == *own_jid)
+        .map(|stanza_id| stanza_id.id)
+}
+
+/// Time the message was originally sent at, if it was delivered with a delay.
+pub fn delay_timestamp(message: &XmppMessage) -> Option<i64> {
+    message
+        .payloads
+        .iter()
+        .filter(|p| p.is("delay", ns::DELAY))
+        .find_map(|p| Delay::try_from(p.clone()).ok())
+        .map(|delay| delay.stamp.0.timestamp())
+}
+
+/// Whether the message sent at `timestamp` is older than `max_age`.
+pub fn is_too_old(timestamp: Option<i64>, max_age: Duration) -> bool {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs() as i64;
+
+    timestamp.is_some_and(|timestamp| now.saturating_sub(timestamp) > max_age.as_secs() as i64)
+}
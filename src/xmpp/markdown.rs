@@ -0,0 +1,713 @@
+// Copyright (c) 2024 Dmitry Markin
+//
+// SPDX-License-Identifier: MIT
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! Markdown processing of responses.
+
+use xmpp_parsers::{
+    minidom::{Element, ElementBuilder, Node},
+    ns,
+};
+
+/// Part of Markdown text.
+pub enum Block<'a> {
+    Text(&'a str),
+    Code {
+        /// The block including the fences.
+        fenced: &'a str,
+        language: &'a str,
+        content: &'a str,
+    },
+}
+
+/// Split Markdown text into fenced code blocks and text between them.
+pub fn split_code_blocks(text: &str) -> Vec<Block<'_>> {
+    let mut blocks = Vec::new();
+    let mut text_start = 0;
+    let mut code: Option<(usize, usize, &str)> = None;
+    let mut offset = 0;
+
+    for line in text.split_inclusive('\n') {
+        let line_start = offset;
+        offset += line.len();
+        let trimmed = line.trim();
+
+        match code {
+            None => {
+                if let Some(language) = trimmed.strip_prefix("```") {
+                    if line_start > text_start {
+                        blocks.push(Block::Text(&text[text_start..line_start]));
+                    }
+                    text_start = line_start;
+                    code = Some((line_start, offset, language.trim()));
+                }
+            }
+            Some((start, content_start, language)) => {
+                if trimmed == "```" {
+                    blocks.push(Block::Code {
+                        fenced: &text[start..offset],
+                        language,
+                        content: &text[content_start..line_start],
+                    });
+                    code = None;
+                    text_start = offset;
+                }
+            }
+        }
+    }
+
+    // Unterminated code block is treated as text.
+    if text_start < text.len() {
+        blocks.push(Block::Text(&text[text_start..]));
+    }
+
+    blocks
+}
+
+/// Inline Markdown element.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Inline {
+    Text(String),
+    Code(String),
+    Link { label: Vec<Inline>, url: String },
+    Strong(Vec<Inline>),
+    Emphasis(Vec<Inline>),
+    Strikethrough(Vec<Inline>),
+}
+
+/// Markdown line outside code blocks and tables.
+enum Line<'a> {
+    Blank,
+    Heading(&'a str),
+    Rule,
+    Bullet { indent: &'a str, item: &'a str },
+    Numbered(&'a str),
+    Quote(&'a str),
+    Text { indent: &'a str, text: &'a str },
+}
+
+impl<'a> Line<'a> {
+    fn parse(line: &'a str) -> Self {
+        let indent = &line[..line.len() - line.trim_start().len()];
+        let trimmed = line.trim();
+
+        if trimmed.is_empty() {
+            return Line::Blank;
+        }
+
+        if let Some(heading) = heading(trimmed) {
+            return Line::Heading(heading);
+        }
+
+        if trimmed.len() >= 3
+            && (trimmed.chars().all(|c| c == '-')
+                || trimmed.chars().all(|c| c == '*')
+                || trimmed.chars().all(|c| c == '_'))
+        {
+            return Line::Rule;
+        }
+
+        for marker in ["- ", "* ", "+ "] {
+            if let Some(item) = trimmed.strip_prefix(marker) {
+                return Line::Bullet { indent, item };
+            }
+        }
+
+        let digits = trimmed.chars().take_while(char::is_ascii_digit).count();
+        if digits > 0 && trimmed[digits..].starts_with(". ") {
+            return Line::Numbered(&trimmed[digits + 2..]);
+        }
+
+        if let Some(quote) = trimmed.strip_prefix('>') {
+            return Line::Quote(quote.trim_start());
+        }
+
+        Line::Text {
+            indent,
+            text: trimmed,
+        }
+    }
+}
+
+/// Convert Markdown to XEP-0393 message styling.
+///
+/// Emphasis, strikethrough and code are mapped to their styling equivalents, headings are made
+/// bold, links are rendered as `text (url)`, and tables are rendered as preformatted blocks.
+/// Fenced code blocks and quotes are kept as is.
+pub fn to_message_styling(markdown: &str) -> String {
+    let mut output = Vec::new();
+
+    for block in split_code_blocks(markdown) {
+        match block {
+            Block::Code { fenced, .. } => output.push(fenced.trim_end_matches('\n').to_string()),
+            Block::Text(text) => {
+                let lines = text.lines().collect::<Vec<_>>();
+                let mut index = 0;
+
+                while index < lines.len() {
+                    if is_table_row(lines[index]) {
+                        let start = index;
+                        while index < lines.len() && is_table_row(lines[index]) {
+                            index += 1;
+                        }
+                        output.push(format!("```\n{}\n```", render_table(&lines[start..index])));
+                        continue;
+                    }
+
+                    let line = lines[index];
+                    index += 1;
+
+                    output.push(match Line::parse(line) {
+                        Line::Blank => String::new(),
+                        Line::Heading(heading) => format!("*{}*", styling(&parse_inline(heading))),
+                        Line::Rule => "――――――――".to_string(),
+                        Line::Bullet { indent, item } => {
+                            format!("{indent}• {}", styling(&parse_inline(item)))
+                        }
+                        Line::Numbered(_) | Line::Quote(_) => {
+                            let indent = &line[..line.len() - line.trim_start().len()];
+                            let (marker, text) = split_line_marker(line.trim_start());
+                            format!("{indent}{marker}{}", styling(&parse_inline(text)))
+                        }
+                        Line::Text { indent, text } => {
+                            format!("{indent}{}", styling(&parse_inline(text)))
+                        }
+                    });
+                }
+            }
+        }
+    }
+
+    output.join("\n")
+}
+
+/// Convert Markdown to XEP-0071 XHTML-IM `<html>` payload.
+///
+/// Only elements of the XHTML-IM profile are produced, with code blocks and tables rendered as
+/// `<pre>`. Text is escaped by the XML serializer, and only `http`, `https`, `xmpp` and `mailto`
+/// links are made clickable.
+pub fn to_xhtml_im(markdown: &str) -> Element {
+    let mut body = Element::builder("body", ns::XHTML);
+
+    for block in split_code_blocks(markdown) {
+        match block {
+            Block::Code { content, .. } => {
+                body = body.append(xhtml_element("pre").append(content.trim_end_matches('\n')));
+            }
+            Block::Text(text) => {
+                let lines = text.lines().collect::<Vec<_>>();
+                let mut index = 0;
+                let mut paragraph: Vec<Node> = Vec::new();
+                let mut list: Option<(&str, Vec<Element>)> = None;
+                let mut quote: Vec<Node> = Vec::new();
+
+                while index <= lines.len() {
+                    let line = lines
+                        .get(index)
+                        .map_or(Line::Blank, |line| Line::parse(line));
+                    let is_table = lines.get(index).is_some_and(|line| is_table_row(line));
+
+                    // Close the open containers not continued by this line.
+                    let continues_paragraph = matches!(line, Line::Text { .. }) && !is_table;
+                    if !continues_paragraph && !paragraph.is_empty() {
+                        body = body.append(xhtml_element("p").append_all(paragraph.drain(..)));
+                    }
+                    if !matches!(
+                        (&line, &list),
+                        (Line::Bullet { .. }, Some(("ul", _)))
+                            | (Line::Numbered(_), Some(("ol", _)))
+                    ) {
+                        if let Some((name, items)) = list.take() {
+                            body = body.append(xhtml_element(name).append_all(items));
+                        }
+                    }
+                    if !matches!(line, Line::Quote(_)) && !quote.is_empty() {
+                        body = body.append(xhtml_element("blockquote").append_all(quote.drain(..)));
+                    }
+
+                    if index == lines.len() {
+                        break;
+                    }
+
+                    if is_table {
+                        let start = index;
+                        while index < lines.len() && is_table_row(lines[index]) {
+                            index += 1;
+                        }
+                        body = body.append(
+                            xhtml_element("pre").append(render_table(&lines[start..index])),
+                        );
+                        continue;
+                    }
+                    index += 1;
+
+                    match line {
+                        Line::Blank => {}
+                        Line::Heading(heading) => {
+                            body = body.append(
+                                xhtml_element("p").append(
+                                    xhtml_element("strong")
+                                        .append_all(xhtml_nodes(&parse_inline(heading)))
+                                        .build(),
+                                ),
+                            );
+                        }
+                        Line::Rule => {
+                            body = body.append(xhtml_element("p").append("――――――――"));
+                        }
+                        Line::Bullet { item, .. } | Line::Numbered(item) => {
+                            let name = if matches!(line, Line::Numbered(_)) {
+                                "ol"
+                            } else {
+                                "ul"
+                            };
+                            let item = xhtml_element("li")
+                                .append_all(xhtml_nodes(&parse_inline(item)))
+                                .build();
+                            list.get_or_insert((name, Vec::new())).1.push(item);
+                        }
+                        Line::Quote(text) => {
+                            if !quote.is_empty() {
+                                quote.push(Node::Element(xhtml_element("br").build()));
+                            }
+                            quote.extend(xhtml_nodes(&parse_inline(text)));
+                        }
+                        Line::Text { text, .. } => {
+                            if !paragraph.is_empty() {
+                                paragraph.push(Node::Element(xhtml_element("br").build()));
+                            }
+                            paragraph.extend(xhtml_nodes(&parse_inline(text)));
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    Element::builder("html", ns::XHTML_IM)
+        .append(body.build())
+        .build()
+}
+
+fn xhtml_element(name: &str) -> ElementBuilder {
+    Element::builder(name, ns::XHTML)
+}
+
+fn xhtml_nodes(inlines: &[Inline]) -> Vec<Node> {
+    inlines
+        .iter()
+        .map(|inline| match inline {
+            Inline::Text(text) => Node::Text(text.clone()),
+            Inline::Code(code) => {
+                Node::Element(xhtml_element("code").append(code.as_str()).build())
+            }
+            Inline::Link { label, url } if is_safe_url(url) => {
+                let label = if label.is_empty() {
+                    vec![Node::Text(url.clone())]
+                } else {
+                    xhtml_nodes(label)
+                };
+                Node::Element(
+                    xhtml_element("a")
+                        .attr("href", url.as_str())
+                        .append_all(label)
+                        .build(),
+                )
+            }
+            Inline::Link { .. } => Node::Text(styling(std::slice::from_ref(inline))),
+            Inline::Strong(children) => Node::Element(
+                xhtml_element("strong")
+                    .append_all(xhtml_nodes(children))
+                    .build(),
+            ),
+            Inline::Emphasis(children) => Node::Element(
+                xhtml_element("em")
+                    .append_all(xhtml_nodes(children))
+                    .build(),
+            ),
+            Inline::Strikethrough(children) => Node::Element(
+                xhtml_element("span")
+                    .attr("style", "text-decoration: line-through")
+                    .append_all(xhtml_nodes(children))
+                    .build(),
+            ),
+        })
+        .collect()
+}
+
+fn is_safe_url(url: &str) -> bool {
+    ["http://", "https://", "xmpp:", "mailto:"]
+        .iter()
+        .any(|scheme| url.starts_with(scheme))
+}
+
+/// Split the list item number or quote marker from the rest of the line.
+fn split_line_marker(line: &str) -> (&str, &str) {
+    let digits = line.chars().take_while(char::is_ascii_digit).count();
+    let marker_length = if digits > 0 {
+        digits + 2
+    } else {
+        line.len() - line[1..].trim_start().len()
+    };
+
+    line.split_at(marker_length.min(line.len()))
+}
+
+fn heading(line: &str) -> Option<&str> {
+    let level = line.chars().take_while(|&c| c == '#').count();
+
+    if (1..=6).contains(&level) {
+        line[level..]
+            .strip_prefix(' ')
+            .map(|heading| heading.trim().trim_end_matches('#').trim_end())
+    } else {
+        None
+    }
+}
+
+/// Render inline elements with XEP-0393 message styling.
+fn styling(inlines: &[Inline]) -> String {
+    inlines
+        .iter()
+        .map(|inline| match inline {
+            Inline::Text(text) => text.clone(),
+            Inline::Code(code) => format!("`{code}`"),
+            Inline::Link { label, url } => {
+                let label = styling(label);
+                if label.is_empty() || label == *url {
+                    url.clone()
+                } else {
+                    format!("{label} ({url})")
+                }
+            }
+            Inline::Strong(children) => format!("*{}*", styling(children)),
+            Inline::Emphasis(children) => format!("_{}_", styling(children)),
+            Inline::Strikethrough(children) => format!("~{}~", styling(children)),
+        })
+        .collect()
+}
+
+/// Inline elements as plain text.
+fn plain_text(inlines: &[Inline]) -> String {
+    inlines
+        .iter()
+        .map(|inline| match inline {
+            Inline::Text(text) | Inline::Code(text) => text.clone(),
+            Inline::Link { .. } => styling(std::slice::from_ref(inline)),
+            Inline::Strong(children)
+            | Inline::Emphasis(children)
+            | Inline::Strikethrough(children) => plain_text(children),
+        })
+        .collect()
+}
+
+/// Parse inline Markdown formatting of a single line.
+fn parse_inline(text: &str) -> Vec<Inline> {
+    let chars = text.char_indices().collect::<Vec<_>>();
+    let mut inlines = Vec::new();
+    let mut plain = String::new();
+    let mut i = 0;
+
+    while i < chars.len() {
+        let (position, c) = chars[i];
+        let rest = &text[position..];
+        let previous = i.checked_sub(1).map(|j| chars[j].1);
+
+        let parsed = if c == '`' {
+            rest[1..]
+                .find('`')
+                .map(|end| (Inline::Code(rest[1..end + 1].to_string()), end + 2))
+        } else if c == '[' || (c == '!' && rest[1..].starts_with('[')) {
+            // Links and images.
+            let label_start = if c == '!' { 2 } else { 1 };
+            link(&rest[label_start..]).map(|(label, url, length)| {
+                (
+                    Inline::Link {
+                        label: parse_inline(label),
+                        url: url.to_string(),
+                    },
+                    label_start + length,
+                )
+            })
+        } else {
+            // Spans: `**bold**`, `__bold__`, `~~strike~~`, `*italic*`, `_italic_`.
+            [("**", 0), ("__", 0), ("~~", 2), ("*", 1), ("_", 1)]
+                .into_iter()
+                .find_map(|(marker, kind)| {
+                    if !rest.starts_with(marker) || previous.is_some_and(char::is_alphanumeric) {
+                        return None;
+                    }
+                    let inner_text = &rest[marker.len()..];
+                    if inner_text.starts_with(char::is_whitespace) {
+                        return None;
+                    }
+                    let end = closing_marker(inner_text, marker)?;
+                    let children = parse_inline(&inner_text[..end]);
+                    let inline = match kind {
+                        0 => Inline::Strong(children),
+                        1 => Inline::Emphasis(children),
+                        _ => Inline::Strikethrough(children),
+                    };
+                    Some((inline, 2 * marker.len() + end))
+                })
+        };
+
+        match parsed {
+            Some((inline, length)) => {
+                if !plain.is_empty() {
+                    inlines.push(Inline::Text(std::mem::take(&mut plain)));
+                }
+                inlines.push(inline);
+                i += rest[..length].chars().count();
+            }
+            None => {
+                plain.push(c);
+                i += 1;
+            }
+        }
+    }
+
+    if !plain.is_empty() {
+        inlines.push(Inline::Text(plain));
+    }
+
+    inlines
+}
+
+/// Find the closing emphasis marker not preceded by whitespace and not followed by
+/// an alphanumeric character.
+fn closing_marker(text: &str, marker: &str) -> Option<usize> {
+    let mut from = 0;
+
+    while let Some(offset) = text[from..].find(marker) {
+        let end = from + offset;
+        let after = &text[end + marker.len()..];
+        let preceded_by_space = text[..end].ends_with(char::is_whitespace);
+        let followed_by_word = after.starts_with(char::is_alphanumeric);
+        // `*` of `**` is not a closing marker of `*`.
+        let doubled = after.starts_with(marker) || text[..end].ends_with(marker);
+
+        if end > 0 && !preceded_by_space && !followed_by_word && !doubled {
+            return Some(end);
+        }
+        from = end + marker.len();
+    }
+
+    None
+}
+
+/// Parse `label](url)` returning label, url and the length of the parsed text.
+fn link(text: &str) -> Option<(&str, &str, usize)> {
+    let label_end = text.find("](")?;
+    let url_start = label_end + 2;
+    let url_end = url_start + text[url_start..].find(')')?;
+    let url = text[url_start..url_end].trim();
+
+    if text[..label_end].contains('[') || url.is_empty() || url.contains(char::is_whitespace) {
+        return None;
+    }
+
+    Some((&text[..label_end], url, url_end + 1))
+}
+
+fn is_table_row(line: &str) -> bool {
+    let line = line.trim();
+
+    line.len() > 1 && line.starts_with('|') && line.ends_with('|')
+}
+
+fn table_cells(row: &str) -> Vec<String> {
+    row.trim()
+        .trim_matches('|')
+        .split('|')
+        .map(|cell| plain_text(&parse_inline(cell.trim())))
+        .collect()
+}
+
+/// Render Markdown table as text with aligned columns.
+fn render_table(rows: &[&str]) -> String {
+    let rows = rows
+        .iter()
+        .filter(|row| {
+            !row.chars()
+                .all(|c| matches!(c, '|' | '-' | ':' | ' ' | '\t'))
+        })
+        .map(|row| table_cells(row))
+        .collect::<Vec<_>>();
+
+    let columns = rows.iter().map(Vec::len).max().unwrap_or_default();
+    let widths = (0..columns)
+        .map(|column| {
+            rows.iter()
+                .filter_map(|row| row.get(column))
+                .map(|cell| cell.chars().count())
+                .max()
+                .unwrap_or_default()
+        })
+        .collect::<Vec<_>>();
+
+    let mut lines = Vec::new();
+    for (index, row) in rows.iter().enumerate() {
+        let line = widths
+            .iter()
+            .enumerate()
+            .map(|(column, width)| {
+                let cell = row.get(column).map(String::as_str).unwrap_or_default();
+                format!("{cell:<width$}")
+            })
+            .collect::<Vec<_>>()
+            .join("  ");
+        lines.push(line.trim_end().to_string());
+
+        if index == 0 && rows.len() > 1 {
+            let separator = widths
+                .iter()
+                .map(|width| "-".repeat(*width))
+                .collect::<Vec<_>>()
+                .join("  ");
+            lines.push(separator);
+        }
+    }
+
+    lines.join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn styling_of_inline_spans() {
+        assert_eq!(
+            to_message_styling("**bold**, *italic*, _italic_, ~~strike~~ and `code`"),
+            "*bold*, _italic_, _italic_, ~strike~ and `code`"
+        );
+    }
+
+    #[test]
+    fn styling_ignores_markers_inside_words() {
+        assert_eq!(to_message_styling("snake_case_name"), "snake_case_name");
+        assert_eq!(to_message_styling("2 * 3 * 4"), "2 * 3 * 4");
+    }
+
+    #[test]
+    fn styling_of_headings_lists_and_rules() {
+        let markdown = "# Title\n\n- one\n  * two\n1. first\n\n---";
+
+        assert_eq!(
+            to_message_styling(markdown),
+            "*Title*\n\n• one\n  • two\n1. first\n\n――――――――"
+        );
+    }
+
+    #[test]
+    fn styling_of_links() {
+        assert_eq!(
+            to_message_styling("[docs](https://example.com) and [https://a.b](https://a.b)"),
+            "docs (https://example.com) and https://a.b"
+        );
+    }
+
+    #[test]
+    fn styling_keeps_code_blocks_and_quotes() {
+        let markdown = "> quoted *text*\n\n```rust\nlet a = **b;\n```";
+
+        assert_eq!(
+            to_message_styling(markdown),
+            "> quoted _text_\n\n```rust\nlet a = **b;\n```"
+        );
+    }
+
+    #[test]
+    fn styling_renders_tables_as_preformatted() {
+        let markdown = "| Name | Value |\n|------|-------|\n| **a** | 1 |";
+
+        assert_eq!(
+            to_message_styling(markdown),
+            "```\nName  Value\n----  -----\na     1\n```"
+        );
+    }
+
+    /// Contents of the XHTML-IM `<body>`.
+    fn xhtml(markdown: &str) -> String {
+        let mut buffer = Vec::new();
+        to_xhtml_im(markdown).write_to(&mut buffer).unwrap();
+        let xhtml = String::from_utf8(buffer).unwrap();
+
+        xhtml
+            .strip_prefix(
+                "<html xmlns='http://jabber.org/protocol/xhtml-im'>\
+                 <body xmlns='http://www.w3.org/1999/xhtml'>",
+            )
+            .and_then(|xhtml| xhtml.strip_suffix("</body></html>"))
+            .unwrap()
+            .to_string()
+    }
+
+    #[test]
+    fn xhtml_of_inline_spans() {
+        assert_eq!(
+            xhtml("**bold**, ~~strike~~, *italic* and `code`"),
+            "<p><strong>bold</strong>, \
+             <span style=\"text-decoration: line-through\">strike</span>, \
+             <em>italic</em> and <code>code</code></p>"
+        );
+    }
+
+    #[test]
+    fn xhtml_escapes_text() {
+        assert_eq!(
+            xhtml("Hello <b>&</b>"),
+            "<p>Hello &lt;b&gt;&amp;&lt;/b&gt;</p>"
+        );
+        assert_eq!(
+            xhtml("```rust\nlet a = 1 < 2;\n```"),
+            "<pre>let a = 1 &lt; 2;</pre>"
+        );
+    }
+
+    #[test]
+    fn xhtml_of_paragraphs_lists_and_quotes() {
+        assert_eq!(xhtml("a\nb\n\nc"), "<p>a<br/>b</p><p>c</p>");
+        assert_eq!(
+            xhtml("- one\n- two\n1. first"),
+            "<ul><li>one</li><li>two</li></ul><ol><li>first</li></ol>"
+        );
+        assert_eq!(xhtml("> q1\n> q2"), "<blockquote>q1<br/>q2</blockquote>");
+        assert_eq!(xhtml("# Title"), "<p><strong>Title</strong></p>");
+    }
+
+    #[test]
+    fn xhtml_links_only_safe_urls() {
+        assert_eq!(
+            xhtml("[x](https://example.com) [y](javascript:alert(1))"),
+            "<p><a href=\"https://example.com\">x</a> y (javascript:alert(1))</p>"
+        );
+    }
+
+    #[test]
+    fn xhtml_renders_tables_as_preformatted() {
+        assert_eq!(
+            xhtml("| a | b |\n|---|---|\n| 1 | 2 |"),
+            "<pre>a  b\n-  -\n1  2</pre>"
+        );
+    }
+}
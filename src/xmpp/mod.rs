@@ -0,0 +1,3360 @@
+// Copyright (c) 2024 Dmitry Markin
+//
+// SPDX-License-Identifier: MIT
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! XMPP agent.
+
+mod access;
+mod carbons;
+mod chat_commands;
+mod commands;
+mod component;
+mod connection;
+mod connector;
+mod disco;
+mod mam;
+mod markdown;
+mod oob;
+mod profile;
+mod reactions;
+mod roster;
+mod split;
+mod threads;
+mod upload;
+
+pub use component::{ComponentLink, ComponentRouter};
+pub use connection::Connection;
+pub use connector::Connector;
+
+use crate::{
+    config::{Config as FileConfig, Profile, ReactionAction},
+    engine::{Config as EngineConfig, ExportFormat},
+    message::{EngineCommand, RequestMessage, ResponseMessage},
+    preferences::{Preference, Preferences, PREFERENCES_FILE},
+    state::StateDir,
+    xmpp::{
+        access::{blockable_jid, AccessList, ACCESS_LIST_FILE, NOT_AUTHORIZED_REPLY},
+        carbons::{unwrap_carbon, ProcessedMessages},
+        chat_commands::{AccessChange, ChatCommand, ADMIN_HELP, HELP},
+        commands::{
+            canceled_response, command_info, command_list, completed_response, executing_response,
+            AdminCommand, ChatUsage, CommandRequest, NS_COMMANDS,
+        },
+        disco::DiscoInfo,
+        mam::{
+            archive_query, archived_message, delay_timestamp, history_query, is_archive_result,
+            is_too_old, stanza_id, CatchUp, History, HistoryQuery, MamState, SentResponses,
+            CATCH_UP_TIMEOUT, HISTORY_QUERY_TIMEOUT, MAM_STATE_FILE,
+        },
+        markdown::{to_message_styling, to_xhtml_im},
+        oob::extract_attachments,
+        profile::vcard_nickname,
+        reactions::{FeedbackLog, FeedbackRecord, SentMessage, SentMessages},
+        roster::{
+            access_request_message, remove_item, roster_query, AccessRequests, ApprovedUsers,
+            RosterContacts, ACCESS_REQUESTS_FILE, APPROVED_USERS_FILE, ROSTER_CONTACTS_FILE,
+        },
+        split::{number_parts, split_message, strip_part_number, PART_NUMBER_RESERVE},
+        threads::{UserThreads, DEFAULT_THREAD},
+        upload::{upload, UploadPlan, UploadService, UPLOAD_TIMEOUT},
+    },
+};
+use anyhow::anyhow;
+use futures::{
+    future::{BoxFuture, FutureExt},
+    stream::{BoxStream, FuturesUnordered, StreamExt},
+};
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    path::PathBuf,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tokio::{
+    sync::{
+        mpsc::{Receiver, Sender},
+        oneshot,
+    },
+    time::{Instant, MissedTickBehavior},
+};
+use tokio_stream::StreamMap;
+use tokio_xmpp::Event;
+use wildmatch::WildMatch;
+use xmpp_parsers::{
+    blocking::{Block, Unblock},
+    carbons::Enable as EnableCarbons,
+    disco::{DiscoInfoQuery, DiscoInfoResult, DiscoItemsQuery, DiscoItemsResult},
+    http_upload::{SlotRequest, SlotResult},
+    iq::{Iq, IqType},
+    jid::{BareJid, Jid},
+    mam::{Complete, Fin},
+    message::{Message as XmppMessage, MessageType, Thread},
+    minidom::Element,
+    ns,
+    oob::Oob,
+    ping::Ping,
+    presence::{Presence, Show as PresenceShow, Type as PresenceType},
+    reactions::Reactions,
+    receipts::{Received, Request as ReceiptRequest},
+    roster::{Item as RosterItem, Roster, Subscription},
+    stanza_error::{DefinedCondition, ErrorType, StanzaError},
+    vcard::VCard,
+    version::{VersionQuery, VersionResult},
+};
+
+// Log target for this file.
+const LOG_TARGET: &str = "jutella::xmpp";
+
+// Delay before reconnecting to XMPP server. Built-in `tokio_xmpp` reconnect is too agressive
+// and wastes up to 50% of a CPU core by reconnecting without a delay.
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+
+// Period to ping the server with to detect dropped connections.
+const PING_INTERVAL: Duration = Duration::from_secs(60);
+
+// Software name reported in response to version queries.
+const SOFTWARE_NAME: &str = "jutellaxmpp";
+
+// Delay before sending back a composing notification.
+const COMPOSING_DELAY: Duration = Duration::from_secs(1);
+
+// Number of consecutive API errors after which presence reports degraded API.
+const DEGRADED_AFTER_ERRORS: usize = 3;
+
+// Number of pending requests after which presence reports the bot busy.
+const BUSY_REQUESTS: usize = 10;
+
+// Minimum interval between presence updates reporting only the queue size change.
+const PRESENCE_UPDATE_INTERVAL: Duration = Duration::from_secs(30);
+
+// Pending access requests limit, so that spam does not flood admins.
+const MAX_ACCESS_REQUESTS: usize = 100;
+
+// Requests channel size.
+pub const REQUESTS_CHANNEL_SIZE: usize = 1024;
+
+// Responses channel size.
+pub const RESPONSES_CHANNEL_SIZE: usize = 1024;
+
+// Admin commands channel size.
+pub const COMMANDS_CHANNEL_SIZE: usize = 16;
+
+#[derive(Debug)]
+pub struct Config {
+    pub auth_jid: BareJid,
+    /// Name of the component bot or additional account, used to reload its config.
+    pub bot: Option<String>,
+    pub connection: Connection,
+    pub ping_timeout: Duration,
+    pub report_os: bool,
+    pub profile: Profile,
+    pub allowed_jids: Vec<String>,
+    pub denied_jids: Vec<String>,
+    pub admins: Vec<BareJid>,
+    pub admin_approval: bool,
+    pub unauthorized_reply: bool,
+    pub model: String,
+    pub reactions: HashMap<String, ReactionAction>,
+    pub feedback_log: Option<PathBuf>,
+    pub upload_threshold: Option<usize>,
+    pub max_message_length: Option<usize>,
+    pub message_styling: bool,
+    /// Models users may choose in their preferences.
+    pub user_models: Vec<String>,
+    /// Names of the personas users can switch to.
+    pub personas: Vec<String>,
+    /// Whether the turns falling out of the context are summarised.
+    pub summarize: bool,
+    pub xhtml_im: bool,
+    pub state_dir: Option<PathBuf>,
+    pub mam_max_age: Duration,
+    pub restore_history: bool,
+    /// Config file to reload the settings from.
+    pub config_path: PathBuf,
+    /// HTTP client shared with other bots.
+    pub http_client: reqwest::Client,
+    pub request_tx: Sender<RequestMessage>,
+    pub response_rx: Receiver<ResponseMessage>,
+    pub command_tx: Sender<EngineCommand>,
+}
+
+/// Response waiting for the upload in progress for the same user.
+struct Outgoing {
+    sent_message: Option<SentMessage>,
+    plan: Option<UploadPlan>,
+    inline: String,
+}
+
+/// Response with its parts uploaded, or failed to be.
+struct CompletedUpload {
+    bare_jid: BareJid,
+    plan: UploadPlan,
+    /// Chatbot response to remember for reactions, `None` for other documents.
+    sent_message: Option<SentMessage>,
+    /// Message sent instead if the upload fails.
+    inline: String,
+    result: anyhow::Result<Vec<String>>,
+}
+
+/// XMPP agent
+pub struct Xmpp {
+    auth_jid: BareJid,
+    bot: Option<String>,
+    client: Connection,
+    bound_jid: Option<Jid>,
+    disco_info: DiscoInfo,
+    ping_timeout: Duration,
+    pending_ping: Option<(String, Instant)>,
+    report_os: bool,
+    profile: Profile,
+    /// Requests sent to the engine and not yet answered.
+    pending_requests: usize,
+    /// Consecutive responses with chatbot API errors.
+    api_errors: usize,
+    /// Last presence availability, status and the time it was sent.
+    presence_sent: Option<(PresenceShow, String, Instant)>,
+    allowed_jids: Vec<WildMatch>,
+    denied_jids: Vec<WildMatch>,
+    /// Allow and deny patterns managed by admins at runtime.
+    access_list: AccessList,
+    unauthorized_reply: bool,
+    /// Unknown users already told they are not authorised.
+    notified_jids: HashSet<String>,
+    active_jids: HashSet<String>,
+    processed_messages: ProcessedMessages,
+    admins: Vec<BareJid>,
+    admin_approval: bool,
+    /// Users approved by admins in addition to `allowed_jids`.
+    approved_jids: HashSet<String>,
+    /// Pending access requests from unknown users.
+    access_requests: HashSet<String>,
+    /// Users whose presence subscription the bot approved, i.e. the roster entries it added.
+    roster_contacts: HashSet<String>,
+    roster_query_id: Option<String>,
+    /// Nicknames of the users from the roster or vCards.
+    nicknames: HashMap<String, String>,
+    /// Pending vCard queries by iq id.
+    vcard_queries: HashMap<String, BareJid>,
+    model: String,
+    usage: HashMap<String, ChatUsage>,
+    reactions: HashMap<String, ReactionAction>,
+    feedback_log: Option<FeedbackLog>,
+    sent_messages: SentMessages,
+    config_path: PathBuf,
+    http_client: reqwest::Client,
+    upload_threshold: Option<usize>,
+    max_message_length: Option<usize>,
+    message_styling: bool,
+    user_models: Vec<String>,
+    personas: Vec<String>,
+    summarize: bool,
+    preferences: HashMap<String, Preferences>,
+    /// Parallel conversations of the users.
+    threads: HashMap<String, UserThreads>,
+    xhtml_im: bool,
+    state_dir: Option<StateDir>,
+    mam_max_age: Duration,
+    last_stanza_id: Option<String>,
+    /// Chatbot responses to restore the conversation history from.
+    sent_responses: SentResponses,
+    /// Whether `last_stanza_id` or `sent_responses` changed since they were last saved.
+    mam_state_dirty: bool,
+    catch_up: Option<CatchUp>,
+    restore_history: bool,
+    /// Users whose conversation history was already restored (or attempted to).
+    history_restored: HashSet<String>,
+    history_queries: HashMap<String, HistoryQuery>,
+    upload_service: Option<UploadService>,
+    upload_discovery: HashMap<String, Jid>,
+    pending_slots: HashMap<String, (Jid, oneshot::Sender<anyhow::Result<SlotResult>>)>,
+    pending_uploads: FuturesUnordered<BoxFuture<'static, CompletedUpload>>,
+    /// Users with an upload in progress. Their further responses are queued to keep the order.
+    uploading: HashSet<BareJid>,
+    queued_responses: HashMap<BareJid, VecDeque<Outgoing>>,
+    message_id_prefix: String,
+    message_id_counter: u64,
+    request_tx: Sender<RequestMessage>,
+    response_rx: Receiver<ResponseMessage>,
+    command_tx: Sender<EngineCommand>,
+    pending_composing: StreamMap<BareJid, BoxStream<'static, ()>>,
+    online: bool,
+}
+
+impl Xmpp {
+    pub fn new(config: Config) -> Self {
+        let Config {
+            auth_jid,
+            bot,
+            connection,
+            ping_timeout,
+            report_os,
+            profile,
+            allowed_jids,
+            denied_jids,
+            admins,
+            admin_approval,
+            unauthorized_reply,
+            model,
+            reactions,
+            feedback_log,
+            upload_threshold,
+            max_message_length,
+            message_styling,
+            user_models,
+            personas,
+            summarize,
+            xhtml_im,
+            state_dir,
+            mam_max_age,
+            restore_history,
+            config_path,
+            http_client,
+            request_tx,
+            response_rx,
+            command_tx,
+        } = config;
+
+        let state_dir = state_dir.map(StateDir::new);
+        let MamState {
+            last_stanza_id,
+            responses: sent_responses,
+        } = state_dir
+            .as_ref()
+            .and_then(|state_dir| {
+                state_dir
+                    .load::<MamState>(MAM_STATE_FILE)
+                    .inspect_err(|error| {
+                        tracing::error!(target: LOG_TARGET, ?error, "failed to load archive state");
+                    })
+                    .ok()
+                    .flatten()
+            })
+            .unwrap_or_default();
+
+        let approved_jids = state_dir
+            .as_ref()
+            .and_then(|state_dir| {
+                state_dir
+                    .load::<ApprovedUsers>(APPROVED_USERS_FILE)
+                    .inspect_err(|error| {
+                        tracing::error!(target: LOG_TARGET, ?error, "failed to load approved users");
+                    })
+                    .ok()
+                    .flatten()
+            })
+            .map(|approved| approved.jids.into_iter().collect())
+            .unwrap_or_default();
+
+        let access_requests = state_dir
+            .as_ref()
+            .and_then(|state_dir| {
+                state_dir
+                    .load::<AccessRequests>(ACCESS_REQUESTS_FILE)
+                    .inspect_err(|error| {
+                        tracing::error!(target: LOG_TARGET, ?error, "failed to load access requests");
+                    })
+                    .ok()
+                    .flatten()
+            })
+            .map(|requests| requests.jids.into_iter().collect())
+            .unwrap_or_default();
+
+        let roster_contacts = state_dir
+            .as_ref()
+            .and_then(|state_dir| {
+                state_dir
+                    .load::<RosterContacts>(ROSTER_CONTACTS_FILE)
+                    .inspect_err(|error| {
+                        tracing::error!(target: LOG_TARGET, ?error, "failed to load roster contacts");
+                    })
+                    .ok()
+                    .flatten()
+            })
+            .map(|contacts| contacts.jids.into_iter().collect())
+            .unwrap_or_default();
+
+        let access_list = state_dir
+            .as_ref()
+            .and_then(|state_dir| {
+                state_dir
+                    .load::<AccessList>(ACCESS_LIST_FILE)
+                    .inspect_err(|error| {
+                        tracing::error!(target: LOG_TARGET, ?error, "failed to load access list");
+                    })
+                    .ok()
+                    .flatten()
+            })
+            .unwrap_or_default();
+
+        let preferences = state_dir
+            .as_ref()
+            .and_then(|state_dir| {
+                state_dir
+                    .load::<HashMap<String, Preferences>>(PREFERENCES_FILE)
+                    .inspect_err(|error| {
+                        tracing::error!(target: LOG_TARGET, ?error, "failed to load preferences");
+                    })
+                    .ok()
+                    .flatten()
+            })
+            .unwrap_or_default();
+
+        Self {
+            auth_jid,
+            bot,
+            client: connection,
+            bound_jid: None,
+            disco_info: DiscoInfo::new(),
+            ping_timeout,
+            pending_ping: None,
+            report_os,
+            profile,
+            pending_requests: 0,
+            api_errors: 0,
+            presence_sent: None,
+            allowed_jids: allowed_jids
+                .into_iter()
+                .map(|p| WildMatch::new(&p))
+                .collect(),
+            denied_jids: denied_jids
+                .into_iter()
+                .map(|p| WildMatch::new(&p))
+                .collect(),
+            access_list,
+            unauthorized_reply,
+            notified_jids: HashSet::new(),
+            active_jids: HashSet::new(),
+            processed_messages: ProcessedMessages::default(),
+            admins,
+            admin_approval,
+            approved_jids,
+            access_requests,
+            roster_contacts,
+            roster_query_id: None,
+            nicknames: HashMap::new(),
+            vcard_queries: HashMap::new(),
+            model,
+            usage: HashMap::new(),
+            reactions,
+            feedback_log: feedback_log.map(FeedbackLog::new),
+            sent_messages: SentMessages::default(),
+            config_path,
+            http_client,
+            upload_threshold,
+            max_message_length,
+            message_styling,
+            user_models,
+            personas,
+            summarize,
+            preferences,
+            xhtml_im,
+            state_dir,
+            mam_max_age,
+            last_stanza_id,
+            sent_responses,
+            mam_state_dirty: false,
+            catch_up: None,
+            restore_history,
+            history_restored: HashSet::new(),
+            threads: HashMap::new(),
+            history_queries: HashMap::new(),
+            upload_service: None,
+            upload_discovery: HashMap::new(),
+            pending_slots: HashMap::new(),
+            pending_uploads: FuturesUnordered::new(),
+            uploading: HashSet::new(),
+            queued_responses: HashMap::new(),
+            // Make message ids unique across restarts, so that reactions to messages sent before
+            // the restart are not attributed to the wrong messages.
+            message_id_prefix: format!(
+                "jutella-{}",
+                SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .as_millis()
+            ),
+            message_id_counter: 0,
+            request_tx,
+            response_rx,
+            command_tx,
+            pending_composing: StreamMap::new(),
+            online: false,
+        }
+    }
+
+    fn reconnect(&mut self) {
+        self.client.reconnect();
+    }
+
+    fn next_message_id(&mut self) -> String {
+        self.message_id_counter += 1;
+
+        format!("{}-{}", self.message_id_prefix, self.message_id_counter)
+    }
+
+    /// Send a chat message and return its id.
+    async fn send_xmpp_message(&mut self, bare_jid: BareJid, message: String) -> String {
+        self.send_xmpp_message_with_payloads(bare_jid, message, Vec::new(), None)
+            .await
+    }
+
+    /// Send a chat message with extra payloads in the thread and return its id.
+    async fn send_xmpp_message_with_payloads(
+        &mut self,
+        bare_jid: BareJid,
+        message: String,
+        payloads: Vec<Element>,
+        thread: Option<String>,
+    ) -> String {
+        let jid = bare_jid.as_str().to_owned();
+        let id = self.next_message_id();
+        let mut xmpp_message = XmppMessage::new(Some(bare_jid.into()))
+            .with_body(String::new(), message)
+            .with_payloads(payloads);
+        xmpp_message.id = Some(id.clone());
+        xmpp_message.thread = thread.map(Thread);
+
+        self.client
+            .send_stanza(xmpp_message.into())
+            .await
+            .inspect_err(|error| {
+                tracing::error!(target: LOG_TARGET, jid, ?error, "failed to send xmpp message");
+            })
+            .unwrap_or_default();
+
+        id
+    }
+
+    async fn process_response(&mut self, resp: ResponseMessage) {
+        let ResponseMessage {
+            jid,
+            thread,
+            request,
+            response,
+            model,
+            tokens_in,
+            tokens_in_cached,
+            tokens_out,
+            tokens_reasoning,
+            api_error,
+        } = resp;
+
+        tracing::debug!(
+            target: LOG_TARGET,
+            jid,
+            len = response.len(),
+            tokens_in,
+            tokens_cached = tokens_in_cached.and_then(|v| match v {
+                0 => None,
+                v => Some(v),
+            }),
+            tokens_out,
+            tokens_reasoning = tokens_reasoning.and_then(|v| match v {
+                0 => None,
+                v => Some(v),
+            }),
+            "response"
+        );
+
+        let Ok(bare_jid) = BareJid::new(&jid) else {
+            // This must not happen as jids were checked to compare equal to string representation
+            // of allowed users when receiving request.
+            tracing::error!(target: LOG_TARGET, jid, "failed to convert to `BareJid`; this is a bug");
+            debug_assert!(false);
+            return;
+        };
+
+        self.usage.entry(jid.clone()).or_default().add(&ChatUsage {
+            requests: 1,
+            tokens_in,
+            tokens_in_cached: tokens_in_cached.unwrap_or_default(),
+            tokens_out,
+        });
+
+        self.threads
+            .entry(jid.clone())
+            .or_default()
+            .touch(thread.as_deref(), Some(tokens_in + tokens_out));
+        self.pending_composing.remove(&bare_jid);
+        self.pending_requests = self.pending_requests.saturating_sub(1);
+        if api_error {
+            self.api_errors += 1;
+        } else {
+            self.api_errors = 0;
+        }
+        self.update_presence().await;
+
+        let plan = self.upload_plan(&response);
+        let sent_message = SentMessage {
+            jid,
+            thread,
+            request,
+            response,
+            model,
+            tokens_in,
+            tokens_out,
+            reactions: HashSet::new(),
+        };
+
+        let inline = sent_message.response.clone();
+        self.deliver(
+            bare_jid,
+            Outgoing {
+                sent_message: Some(sent_message),
+                plan,
+                inline,
+            },
+        )
+        .await;
+    }
+
+    /// Upload or send the response, or queue it behind the upload in progress for the user.
+    async fn deliver(&mut self, bare_jid: BareJid, outgoing: Outgoing) {
+        if self.uploading.contains(&bare_jid) {
+            self.queued_responses
+                .entry(bare_jid)
+                .or_default()
+                .push_back(outgoing);
+            return;
+        }
+
+        let Outgoing {
+            sent_message,
+            plan,
+            inline,
+        } = outgoing;
+
+        match plan {
+            Some(plan) => {
+                self.start_upload(bare_jid, plan, sent_message, inline)
+                    .await
+            }
+            None => {
+                self.send_response(bare_jid, sent_message, inline, Vec::new())
+                    .await
+            }
+        }
+    }
+
+    /// Send the response and remember it for reactions if it is a chatbot response.
+    ///
+    /// Responses longer than `max_message_length` are sent as several messages, any of them
+    /// can be reacted to.
+    async fn send_response(
+        &mut self,
+        bare_jid: BareJid,
+        sent_message: Option<SentMessage>,
+        body: String,
+        urls: Vec<String>,
+    ) {
+        let mut payloads = urls
+            .into_iter()
+            .map(|url| Oob { url, desc: None }.into())
+            .collect();
+
+        let styling = self.message_styling
+            && self
+                .preferences
+                .get(bare_jid.as_str())
+                .and_then(|preferences| preferences.styling)
+                .unwrap_or(true);
+
+        let thread = sent_message
+            .as_ref()
+            .and_then(|sent_message| sent_message.thread.clone());
+
+        let parts = match self.max_message_length {
+            Some(max_length) => {
+                self.fit_message(&bare_jid, body, thread.as_deref(), styling, max_length)
+            }
+            None => vec![body],
+        };
+        let num_parts = parts.len();
+        let mut ids = Vec::with_capacity(num_parts);
+
+        self.send_chat_state_active(bare_jid.clone()).await;
+
+        for (index, part) in parts.into_iter().enumerate() {
+            // Attach links to the last part.
+            let mut part_payloads = if index + 1 == num_parts {
+                std::mem::take(&mut payloads)
+            } else {
+                Vec::new()
+            };
+            let (part, xhtml) = self.render_part(&part, styling);
+            part_payloads.extend(xhtml);
+
+            let id = self
+                .send_xmpp_message_with_payloads(
+                    bare_jid.clone(),
+                    part,
+                    part_payloads,
+                    thread.clone(),
+                )
+                .await;
+
+            if let Some(sent_message) = &sent_message {
+                self.sent_messages.insert(id.clone(), sent_message.clone());
+            }
+            ids.push(id);
+        }
+
+        // Only the conversation outside threads is restored from the archive.
+        if let Some(sent_message) = sent_message.filter(|message| message.thread.is_none()) {
+            self.sent_responses
+                .insert(&sent_message.jid, ids, sent_message.response);
+            self.mam_state_dirty = true;
+        }
+    }
+
+    /// Split the Markdown response into parts whose rendered stanzas fit into `max_length`.
+    ///
+    /// The Markdown is split before conversion, so that every part is converted on its own.
+    /// Parts whose styled body and XHTML-IM copy turn out too large are split again with
+    /// a proportionally smaller limit.
+    fn fit_message(
+        &self,
+        bare_jid: &BareJid,
+        body: String,
+        thread: Option<&str>,
+        styling: bool,
+        max_length: usize,
+    ) -> Vec<String> {
+        if self.stanza_size(bare_jid, &body, thread, styling) <= max_length {
+            return vec![body];
+        }
+
+        let mut pending = split_message(&body, max_length)
+            .iter()
+            .map(|part| (strip_part_number(part).to_string(), max_length))
+            .collect::<VecDeque<_>>();
+        let mut parts = Vec::new();
+
+        while let Some((part, limit)) = pending.pop_front() {
+            let size = self.stanza_size(bare_jid, &part, thread, styling) + PART_NUMBER_RESERVE;
+            if size <= max_length || limit <= 1 {
+                parts.push(part);
+                continue;
+            }
+
+            let limit = (limit * max_length / size).clamp(1, limit - 1);
+            for piece in split_message(&part, limit).iter().rev() {
+                pending.push_front((strip_part_number(piece).to_string(), limit));
+            }
+        }
+
+        number_parts(parts)
+    }
+
+    /// Message body and XHTML-IM payload of the Markdown text.
+    fn render_part(&self, markdown: &str, styling: bool) -> (String, Option<Element>) {
+        let xhtml = self.xhtml_im.then(|| to_xhtml_im(markdown));
+        let body = if styling {
+            to_message_styling(markdown)
+        } else {
+            markdown.to_string()
+        };
+
+        (body, xhtml)
+    }
+
+    /// Size of the serialized message stanza carrying the Markdown text.
+    fn stanza_size(
+        &self,
+        bare_jid: &BareJid,
+        markdown: &str,
+        thread: Option<&str>,
+        styling: bool,
+    ) -> usize {
+        let (body, xhtml) = self.render_part(markdown, styling);
+        let mut message = XmppMessage::new(Some(bare_jid.clone().into()))
+            .with_body(String::new(), body)
+            .with_payloads(xhtml.into_iter().collect());
+        message.id = Some(format!("{}-{}", self.message_id_prefix, u64::MAX));
+        message.thread = thread.map(|thread| Thread(thread.to_string()));
+
+        let mut buffer = Vec::new();
+        match Element::from(message).write_to(&mut buffer) {
+            Ok(()) => buffer.len(),
+            // Can't happen when writing to a buffer, count the text twice to be on the safe side.
+            Err(_) => 2 * markdown.len(),
+        }
+    }
+
+    /// Parts of the response to upload instead of sending inline, if any.
+    fn upload_plan(&self, response: &str) -> Option<UploadPlan> {
+        let threshold = self.upload_threshold?;
+        let plan = UploadPlan::new(response, threshold)?;
+
+        self.can_upload(&plan).then_some(plan)
+    }
+
+    /// Whether the upload service is available and accepts the files of the plan.
+    fn can_upload(&self, plan: &UploadPlan) -> bool {
+        let Some(service) = &self.upload_service else {
+            return false;
+        };
+
+        if let Some(max_file_size) = service.max_file_size {
+            if plan
+                .files
+                .iter()
+                .any(|file| file.data.len() as u64 > max_file_size)
+            {
+                tracing::debug!(
+                    target: LOG_TARGET,
+                    max_file_size,
+                    "files too large for upload service, sending inline",
+                );
+                return false;
+            }
+        }
+
+        true
+    }
+
+    /// Request upload slots and upload the files in the background.
+    async fn start_upload(
+        &mut self,
+        bare_jid: BareJid,
+        plan: UploadPlan,
+        sent_message: Option<SentMessage>,
+        inline: String,
+    ) {
+        let Some(service) = self
+            .upload_service
+            .as_ref()
+            .map(|service| service.jid.clone())
+        else {
+            self.send_response(bare_jid, sent_message, inline, Vec::new())
+                .await;
+            return;
+        };
+
+        let mut slots = Vec::with_capacity(plan.files.len());
+        for file in &plan.files {
+            let id = self.next_message_id();
+            let request = SlotRequest {
+                filename: file.filename.clone(),
+                size: file.data.len() as u64,
+                content_type: Some(file.content_type.clone()),
+            };
+            let iq = Iq::from_get(id.clone(), request).with_to(service.clone());
+
+            if let Err(error) = self.client.send_stanza(iq.into()).await {
+                tracing::error!(target: LOG_TARGET, ?error, "failed to request upload slot");
+                break;
+            }
+
+            let (tx, rx) = oneshot::channel();
+            self.pending_slots.insert(id, (service.clone(), tx));
+            slots.push(rx);
+        }
+
+        let client = self.http_client.clone();
+        let files = plan.files.clone();
+        self.uploading.insert(bare_jid.clone());
+
+        self.pending_uploads.push(
+            async move {
+                let result = async {
+                    if slots.len() != files.len() {
+                        return Err(anyhow!("failed to request upload slots"));
+                    }
+
+                    let mut urls = Vec::with_capacity(files.len());
+                    for (slot, file) in slots.into_iter().zip(files) {
+                        let slot = tokio::time::timeout(UPLOAD_TIMEOUT, slot)
+                            .await
+                            .map_err(|_| anyhow!("upload slot request timed out"))?
+                            .map_err(|_| anyhow!("upload slot request canceled"))??;
+                        urls.push(upload(client.clone(), slot, file).await?);
+                    }
+
+                    Ok(urls)
+                }
+                .await;
+
+                CompletedUpload {
+                    bare_jid,
+                    plan,
+                    sent_message,
+                    inline,
+                    result,
+                }
+            }
+            .boxed(),
+        );
+    }
+
+    async fn finish_upload(&mut self, upload: CompletedUpload) {
+        let CompletedUpload {
+            bare_jid,
+            plan,
+            sent_message,
+            inline,
+            result,
+        } = upload;
+
+        match result {
+            Ok(urls) => {
+                tracing::debug!(
+                    target: LOG_TARGET,
+                    jid = bare_jid.as_str(),
+                    files = urls.len(),
+                    "response uploaded",
+                );
+
+                let body = plan.render(&urls);
+                self.send_response(bare_jid.clone(), sent_message, body, urls)
+                    .await;
+            }
+            Err(error) => {
+                tracing::warn!(
+                    target: LOG_TARGET,
+                    jid = bare_jid.as_str(),
+                    ?error,
+                    "failed to upload response, sending inline",
+                );
+
+                self.send_response(bare_jid.clone(), sent_message, inline, Vec::new())
+                    .await;
+            }
+        }
+
+        // Send the responses queued behind this upload, until the next one starts.
+        self.uploading.remove(&bare_jid);
+        while !self.uploading.contains(&bare_jid) {
+            let Some(outgoing) = self
+                .queued_responses
+                .get_mut(&bare_jid)
+                .and_then(VecDeque::pop_front)
+            else {
+                self.queued_responses.remove(&bare_jid);
+                break;
+            };
+            self.deliver(bare_jid.clone(), outgoing).await;
+        }
+    }
+
+    async fn process_xmpp_message(&mut self, mut message: XmppMessage) -> anyhow::Result<()> {
+        let Some(ref jid) = message.from else {
+            tracing::trace!(target: LOG_TARGET, ?message, "xmpp message without `from` field");
+            return Ok(());
+        };
+
+        let bare_jid = jid.to_bare();
+        let jid = bare_jid.as_str().to_owned();
+
+        if !self.active_jids.contains(&jid) {
+            if self.is_allowed(&jid) {
+                if !self.nicknames.contains_key(&jid) {
+                    self.query_vcard(bare_jid.clone()).await;
+                }
+                self.approve_presence_subscription(bare_jid.clone()).await;
+                self.send_chat_state_active(bare_jid.clone()).await;
+                self.active_jids.insert(jid.clone());
+            } else {
+                tracing::trace!(target: LOG_TARGET, jid, ?message, "message from unknown user");
+                if message.type_ == MessageType::Chat && !message.bodies.is_empty() {
+                    self.reply_not_authorized(bare_jid.clone()).await;
+                }
+                self.request_access(bare_jid).await;
+                return Ok(());
+            }
+        }
+
+        if message.type_ != MessageType::Chat {
+            tracing::warn!(
+                target: LOG_TARGET,
+                jid,
+                type_ = ?message.type_,
+                ?message,
+                "not a chat message received",
+            );
+            return Ok(());
+        }
+
+        match message.extract_payload::<Reactions>() {
+            Ok(Some(reactions)) => {
+                return self.process_reactions(bare_jid, reactions).await;
+            }
+            Ok(None) => {}
+            Err(error) => {
+                tracing::debug!(target: LOG_TARGET, jid, ?error, "invalid reactions payload");
+                return Ok(());
+            }
+        }
+
+        let Some(body) = message.bodies.get("").map(|body| body.0.clone()) else {
+            tracing::trace!(target: LOG_TARGET, jid, ?message, "chat message without a body");
+            return Ok(());
+        };
+
+        if message.payloads.iter().any(|p| p.name() == "encrypted") {
+            tracing::debug!(target: LOG_TARGET, jid, "encrypted message");
+            self.send_xmpp_message(
+                bare_jid.clone(),
+                "[ERROR] Encrypted messages are not supported".to_string(),
+            )
+            .await;
+            return Ok(());
+        }
+
+        if let Some(ref id) = message.id {
+            if !self.processed_messages.insert(&jid, id) {
+                tracing::debug!(target: LOG_TARGET, jid, id, "duplicate message, ignoring");
+                return Ok(());
+            }
+        }
+
+        if let Some(command) = ChatCommand::parse(&body) {
+            self.process_chat_command(bare_jid.clone(), command).await?;
+        } else {
+            // Unknown thread ids are handled by `submit_request`.
+            let thread = match message.thread.take() {
+                Some(Thread(thread)) => Some(thread),
+                None => self.current_thread(&jid),
+            };
+            let (request, attachments) = extract_attachments(&mut message, &body);
+            self.submit_request(bare_jid.clone(), thread, request, attachments)
+                .await?;
+        }
+
+        if let Some(id) = message.id {
+            if message
+                .payloads
+                .iter()
+                .any(|p| ReceiptRequest::try_from(p.clone()).is_ok())
+            {
+                self.send_delivery_receipt(bare_jid.clone(), &id).await;
+            }
+
+            self.send_displayed_marker(bare_jid, &id).await;
+        }
+
+        Ok(())
+    }
+
+    /// Process message received live, deferring it if the archive catch-up is in progress.
+    async fn process_live_message(&mut self, message: XmppMessage) -> anyhow::Result<()> {
+        if let Some(catch_up) = self.catch_up.as_mut() {
+            catch_up.deferred.push(message);
+            return Ok(());
+        }
+
+        let stanza_id = stanza_id(&self.auth_jid, &message);
+        let timestamp = delay_timestamp(&message);
+
+        self.process_incoming_message(message, stanza_id, timestamp)
+            .await
+    }
+
+    async fn process_archived_message(&mut self, mut message: XmppMessage) -> anyhow::Result<()> {
+        let Some(archived) = archived_message(&self.auth_jid, &mut message) else {
+            return Ok(());
+        };
+
+        if let Some(query) = self
+            .history_queries
+            .values_mut()
+            .find(|query| query.query_id == archived.query_id)
+        {
+            if let Some(message) = archived.message {
+                let jid = query.bare_jid.as_str();
+                query
+                    .history
+                    .push(&self.auth_jid, jid, &self.sent_responses, message);
+            }
+            return Ok(());
+        }
+
+        if self
+            .catch_up
+            .as_ref()
+            .is_none_or(|catch_up| catch_up.query_id != archived.query_id)
+        {
+            tracing::debug!(
+                target: LOG_TARGET,
+                query_id = archived.query_id,
+                "archive result of unknown query, ignoring",
+            );
+            return Ok(());
+        }
+
+        match archived.message {
+            // Our own messages are also in the archive.
+            Some(message)
+                if message
+                    .from
+                    .as_ref()
+                    .is_some_and(|from| from.to_bare() != self.auth_jid) =>
+            {
+                self.process_incoming_message(message, Some(archived.stanza_id), archived.timestamp)
+                    .await
+            }
+            _ => {
+                self.set_last_stanza_id(archived.stanza_id);
+                Ok(())
+            }
+        }
+    }
+
+    /// Process message unless it is too old and remember it as the last processed one.
+    async fn process_incoming_message(
+        &mut self,
+        message: XmppMessage,
+        stanza_id: Option<String>,
+        timestamp: Option<i64>,
+    ) -> anyhow::Result<()> {
+        if is_too_old(timestamp, self.mam_max_age) {
+            tracing::debug!(
+                target: LOG_TARGET,
+                from = ?message.from,
+                id = message.id,
+                "skipping message older than `mam_max_age`",
+            );
+        } else {
+            self.process_xmpp_message(message).await?;
+        }
+
+        if let Some(stanza_id) = stanza_id {
+            self.set_last_stanza_id(stanza_id);
+        }
+
+        Ok(())
+    }
+
+    /// Remember the last processed stanza id. It is saved by [`Self::save_mam_state`].
+    fn set_last_stanza_id(&mut self, stanza_id: String) {
+        if self.state_dir.is_none() {
+            return;
+        }
+
+        self.last_stanza_id = Some(stanza_id);
+        self.mam_state_dirty = true;
+    }
+
+    /// Save the last processed stanza id and the sent responses if they changed.
+    fn save_mam_state(&mut self) {
+        let Some(state_dir) = &self.state_dir else {
+            return;
+        };
+        if !self.mam_state_dirty {
+            return;
+        }
+
+        let state = MamState {
+            last_stanza_id: self.last_stanza_id.clone(),
+            responses: self.sent_responses.clone(),
+        };
+        match state_dir.save(MAM_STATE_FILE, &state) {
+            Ok(()) => self.mam_state_dirty = false,
+            Err(error) => {
+                tracing::error!(target: LOG_TARGET, ?error, "failed to save archive state")
+            }
+        }
+    }
+
+    /// Query the archive for messages received since the last processed one.
+    async fn start_catch_up(&mut self) {
+        self.catch_up = None;
+
+        if self.state_dir.is_none() {
+            return;
+        }
+
+        let Some(after) = self.last_stanza_id.clone() else {
+            tracing::debug!(target: LOG_TARGET, "no archive position yet, skipping catch-up");
+            return;
+        };
+
+        self.query_archive(after, Vec::new(), Instant::now() + CATCH_UP_TIMEOUT)
+            .await;
+    }
+
+    async fn query_archive(
+        &mut self,
+        after: String,
+        deferred: Vec<XmppMessage>,
+        deadline: Instant,
+    ) {
+        tracing::debug!(target: LOG_TARGET, after, "querying message archive");
+
+        let query_id = self.next_message_id();
+        let iq_id = self.next_message_id();
+        let iq = Iq::from_set(iq_id.clone(), archive_query(&query_id, after));
+
+        match self.client.send_stanza(iq.into()).await {
+            Ok(()) => {
+                self.catch_up = Some(CatchUp {
+                    query_id,
+                    iq_id,
+                    deferred,
+                    deadline,
+                });
+            }
+            Err(error) => tracing::error!(target: LOG_TARGET, ?error, "failed to query archive"),
+        }
+    }
+
+    async fn process_archive_fin(
+        &mut self,
+        from: Jid,
+        payload: Option<Element>,
+    ) -> anyhow::Result<()> {
+        if from.to_bare() != self.auth_jid {
+            tracing::warn!(target: LOG_TARGET, from = from.to_string(), "archive fin from foreign JID");
+            return Ok(());
+        }
+
+        let fin = match payload.map(Fin::try_from) {
+            Some(Ok(fin)) => fin,
+            _ => {
+                tracing::warn!(target: LOG_TARGET, "invalid archive query response");
+                return self.finish_catch_up().await;
+            }
+        };
+
+        match fin.set.last {
+            Some(last) if fin.complete == Complete::False => {
+                let Some(CatchUp {
+                    deferred, deadline, ..
+                }) = self.catch_up.take()
+                else {
+                    return Ok(());
+                };
+                self.query_archive(last, deferred, deadline).await;
+                Ok(())
+            }
+            _ => self.finish_catch_up().await,
+        }
+    }
+
+    /// Process messages received during the catch-up.
+    async fn finish_catch_up(&mut self) -> anyhow::Result<()> {
+        let Some(catch_up) = self.catch_up.take() else {
+            return Ok(());
+        };
+
+        tracing::debug!(
+            target: LOG_TARGET,
+            deferred = catch_up.deferred.len(),
+            "archive catch-up finished",
+        );
+
+        for message in catch_up.deferred {
+            self.process_live_message(message).await?;
+        }
+
+        Ok(())
+    }
+
+    async fn process_chat_command(
+        &mut self,
+        bare_jid: BareJid,
+        command: Result<ChatCommand, String>,
+    ) -> anyhow::Result<()> {
+        let jid = bare_jid.as_str().to_owned();
+        tracing::debug!(target: LOG_TARGET, jid, ?command, "chat command");
+
+        let reply = match command {
+            Err(usage) => usage,
+            Ok(ChatCommand::Help) if self.admins.contains(&bare_jid) => {
+                format!("{HELP}\n\n{ADMIN_HELP}")
+            }
+            Ok(ChatCommand::Help) => HELP.to_string(),
+            Ok(ChatCommand::Styling(_)) if !self.message_styling => {
+                "Message styling is disabled on this bot.".to_string()
+            }
+            Ok(ChatCommand::Styling(None)) => {
+                let styling = self.preferences.get(&jid).and_then(|p| p.styling);
+                if styling == Some(false) {
+                    "Message styling is off.".to_string()
+                } else {
+                    "Message styling is on.".to_string()
+                }
+            }
+            Ok(ChatCommand::Styling(Some(true))) => self
+                .set_preference(jid, Preference::Styling, Some("on".to_string()))
+                .map_or_else(|error| error, |_| "Message styling is on.".to_string()),
+            Ok(ChatCommand::Styling(Some(false))) => self
+                .set_preference(jid, Preference::Styling, Some("off".to_string()))
+                .map_or_else(
+                    |error| error,
+                    |_| "Message styling is off, responses are sent as is.".to_string(),
+                ),
+            Ok(ChatCommand::Preferences) => self
+                .preferences
+                .get(&jid)
+                .cloned()
+                .unwrap_or_default()
+                .describe(),
+            Ok(ChatCommand::Set(preference, value)) => self
+                .set_preference(jid, preference, value)
+                .unwrap_or_else(|error| error),
+            Ok(ChatCommand::Persona(None)) => self.describe_personas(&jid),
+            Ok(ChatCommand::Persona(Some((persona, reset)))) => {
+                self.switch_persona(bare_jid.clone(), persona, reset)
+                    .await?
+            }
+            Ok(ChatCommand::Summary) => self.conversation_summary(jid).await?,
+            Ok(ChatCommand::Threads) => self
+                .threads
+                .get(&jid)
+                .map(UserThreads::describe)
+                .unwrap_or_else(|| UserThreads::default().describe()),
+            Ok(ChatCommand::NewThread(thread)) => self.new_thread(jid, thread).await?,
+            Ok(ChatCommand::SwitchThread(thread)) => self.switch_thread(jid, thread),
+            Ok(ChatCommand::Export(format, full)) => {
+                match self
+                    .export_conversation(bare_jid.clone(), jid, format, full)
+                    .await?
+                {
+                    Ok(()) => return Ok(()),
+                    Err(error) => error,
+                }
+            }
+            Ok(ChatCommand::Approve(_) | ChatCommand::Deny(_) | ChatCommand::Access(_))
+                if !self.admins.contains(&bare_jid) =>
+            {
+                "This command is only available to admins.".to_string()
+            }
+            Ok(ChatCommand::Approve(user)) => match BareJid::new(&user) {
+                Ok(user) => self.approve_user(user).await,
+                Err(error) => format!("Invalid JID: {error}"),
+            },
+            Ok(ChatCommand::Deny(user)) => match BareJid::new(&user) {
+                Ok(user) => self.deny_user(user).await.unwrap_or_else(|error| error),
+                Err(error) => format!("Invalid JID: {error}"),
+            },
+            Ok(ChatCommand::Access(change)) => self.change_access_list(change).await,
+        };
+
+        self.send_xmpp_message(bare_jid, reply).await;
+
+        Ok(())
+    }
+
+    fn describe_personas(&self, jid: &str) -> String {
+        if self.personas.is_empty() {
+            return "No personas are configured on this bot.".to_string();
+        }
+
+        let current = self
+            .preferences
+            .get(jid)
+            .and_then(|preferences| preferences.persona.as_deref())
+            .filter(|persona| self.personas.iter().any(|p| p == persona))
+            .unwrap_or("default");
+
+        format!(
+            "Current persona: {current}\nAvailable: default, {}\n\
+             Switch with \"/persona <name>\", add \"reset\" to start a new conversation.",
+            self.personas.join(", "),
+        )
+    }
+
+    /// Switch the persona of the user and return the reply.
+    async fn switch_persona(
+        &mut self,
+        bare_jid: BareJid,
+        persona: Option<String>,
+        reset: bool,
+    ) -> anyhow::Result<String> {
+        let jid = bare_jid.as_str().to_owned();
+
+        if let Some(persona) = &persona {
+            if !self.personas.contains(persona) {
+                return Ok(format!(
+                    "Unknown persona {persona}, available: default, {}.",
+                    self.personas.join(", "),
+                ));
+            }
+        }
+
+        let preferences = self.preferences.entry(jid.clone()).or_default();
+        preferences.persona = persona.clone();
+        if preferences.is_empty() {
+            self.preferences.remove(&jid);
+        }
+        self.save_preferences();
+
+        tracing::debug!(target: LOG_TARGET, jid, ?persona, reset, "persona switched");
+
+        if reset {
+            self.pending_composing.remove(&bare_jid);
+            let thread = self.current_thread(&jid);
+            self.send_engine_command(EngineCommand::ResetConversation { jid, thread })
+                .await?;
+        }
+
+        let persona = persona.as_deref().unwrap_or("default");
+        Ok(match reset {
+            true => format!("Switched to {persona} persona, starting a new conversation."),
+            false => format!("Switched to {persona} persona, the conversation continues."),
+        })
+    }
+
+    /// Running summary of the conversation with the user as a reply.
+    async fn conversation_summary(&mut self, jid: String) -> anyhow::Result<String> {
+        if !self.summarize {
+            return Ok("Conversation summaries are disabled on this bot.".to_string());
+        }
+
+        let (reply, rx) = oneshot::channel();
+        let thread = self.current_thread(&jid);
+        self.send_engine_command(EngineCommand::Summary { jid, thread, reply })
+            .await?;
+
+        Ok(match rx.await.ok().flatten() {
+            Some(summary) => format!("Summary of the earlier conversation:\n\n{summary}"),
+            None => "No summary of this conversation yet.".to_string(),
+        })
+    }
+
+    /// Export the conversation with `jid` in its current thread and send it to `recipient`,
+    /// uploaded if possible. Returns the reason if there is nothing to send.
+    async fn export_conversation(
+        &mut self,
+        recipient: BareJid,
+        jid: String,
+        format: ExportFormat,
+        full: bool,
+    ) -> anyhow::Result<Result<(), String>> {
+        let (reply, rx) = oneshot::channel();
+        let thread = self.current_thread(&jid);
+        self.send_engine_command(EngineCommand::Export {
+            jid: jid.clone(),
+            thread,
+            format,
+            full,
+            reply,
+        })
+        .await?;
+
+        let document = match rx.await {
+            Ok(Ok(Some(document))) => document,
+            Ok(Ok(None)) => return Ok(Err("No conversation to export yet.".to_string())),
+            Ok(Err(error)) => return Ok(Err(format!("[ERROR] {error}"))),
+            Err(_) => return Err(anyhow!("export request dropped by the engine")),
+        };
+
+        tracing::debug!(
+            target: LOG_TARGET,
+            jid,
+            recipient = recipient.as_str(),
+            ?format,
+            full,
+            len = document.len(),
+            "conversation exported",
+        );
+
+        let inline = match format {
+            ExportFormat::Markdown => document.clone(),
+            ExportFormat::Json => format!("```json\n{document}\n```"),
+        };
+        let plan = UploadPlan::document(
+            "Conversation export:",
+            "conversation",
+            format.extension(),
+            document,
+        );
+
+        let plan = self.can_upload(&plan).then_some(plan);
+        self.deliver(
+            recipient,
+            Outgoing {
+                sent_message: None,
+                plan,
+                inline,
+            },
+        )
+        .await;
+
+        Ok(Ok(()))
+    }
+
+    /// Thread the messages of the user without `<thread>` element go to.
+    fn current_thread(&self, jid: &str) -> Option<String> {
+        self.threads
+            .get(jid)
+            .and_then(|threads| threads.current.clone())
+    }
+
+    /// Add the thread of the user, dropping the least recently active one if there are too many.
+    async fn add_thread(&mut self, jid: &str, thread: &str) -> anyhow::Result<()> {
+        let dropped = self
+            .threads
+            .entry(jid.to_owned())
+            .or_default()
+            .insert(thread);
+
+        if let Some(dropped) = dropped {
+            tracing::debug!(target: LOG_TARGET, jid, thread = dropped, "thread dropped");
+            self.send_engine_command(EngineCommand::ResetConversation {
+                jid: jid.to_owned(),
+                thread: Some(dropped),
+            })
+            .await?;
+        }
+
+        Ok(())
+    }
+
+    /// Start a new thread and switch to it.
+    async fn new_thread(&mut self, jid: String, thread: String) -> anyhow::Result<String> {
+        if thread == DEFAULT_THREAD
+            || self
+                .threads
+                .get(&jid)
+                .is_some_and(|threads| threads.contains(&thread))
+        {
+            return Ok(format!(
+                "Thread {thread} already exists, switch to it with \"/switch {thread}\"."
+            ));
+        }
+
+        self.add_thread(&jid, &thread).await?;
+        let threads = self.threads.entry(jid.clone()).or_default();
+        threads.current = Some(thread.clone());
+        threads.touch(Some(&thread), None);
+
+        tracing::debug!(target: LOG_TARGET, jid, thread, "thread started");
+
+        Ok(format!("Started thread {thread}."))
+    }
+
+    /// Switch to the existing thread.
+    fn switch_thread(&mut self, jid: String, thread: String) -> String {
+        let threads = self.threads.entry(jid.clone()).or_default();
+
+        if thread == DEFAULT_THREAD {
+            threads.current = None;
+        } else if threads.contains(&thread) {
+            threads.current = Some(thread.clone());
+        } else {
+            return format!("Unknown thread {thread}, start it with \"/new {thread}\".");
+        }
+
+        tracing::debug!(target: LOG_TARGET, jid, thread, "thread switched");
+
+        format!("Switched to thread {thread}.")
+    }
+
+    /// Change the preference of the user and return the reply.
+    fn set_preference(
+        &mut self,
+        jid: String,
+        preference: Preference,
+        value: Option<String>,
+    ) -> Result<String, String> {
+        let preferences = self.preferences.entry(jid.clone()).or_default();
+        preferences.set(preference, value.clone(), &self.user_models)?;
+        if preferences.is_empty() {
+            self.preferences.remove(&jid);
+        }
+        self.save_preferences();
+
+        tracing::debug!(target: LOG_TARGET, jid, %preference, "preference changed");
+
+        Ok(match value {
+            Some(value) => format!("{preference} set to {value}."),
+            None => format!("{preference} reset to default."),
+        })
+    }
+
+    fn save_preferences(&self) {
+        let Some(state_dir) = &self.state_dir else {
+            return;
+        };
+
+        if let Err(error) = state_dir.save(PREFERENCES_FILE, &self.preferences) {
+            tracing::error!(target: LOG_TARGET, ?error, "failed to save preferences");
+        }
+    }
+
+    async fn submit_request(
+        &mut self,
+        bare_jid: BareJid,
+        thread: Option<String>,
+        request: String,
+        attachments: Vec<String>,
+    ) -> anyhow::Result<()> {
+        // Only the threads started with `/new` are honoured: thread ids from other clients, or
+        // threads dropped since, go to the current thread.
+        let threads = self
+            .threads
+            .entry(bare_jid.as_str().to_owned())
+            .or_default();
+        let thread = match thread {
+            Some(thread) if !threads.contains(&thread) => threads.current.clone(),
+            thread => thread,
+        };
+        threads.touch(thread.as_deref(), None);
+
+        let req = RequestMessage {
+            jid: bare_jid.as_str().to_owned(),
+            thread,
+            request,
+            attachments,
+            history: Vec::new(),
+            user_nick: self.nicknames.get(bare_jid.as_str()).cloned(),
+            preferences: self
+                .preferences
+                .get(bare_jid.as_str())
+                .map(|preferences| preferences.allowed(&self.user_models, &self.personas))
+                .unwrap_or_default(),
+        };
+
+        // The archive only has the default thread.
+        if self.restore_history
+            && req.thread.is_none()
+            && self.online
+            && !self.client.is_component()
+            && !self.history_restored.contains(&req.jid)
+        {
+            match self.history_queries.get_mut(&req.jid) {
+                Some(query) => query.pending.push(req),
+                None => self.query_history(bare_jid, req).await?,
+            }
+            return Ok(());
+        }
+
+        self.send_request(bare_jid, req).await
+    }
+
+    async fn send_request(&mut self, bare_jid: BareJid, req: RequestMessage) -> anyhow::Result<()> {
+        tracing::debug!(
+            target: LOG_TARGET,
+            jid = req.jid,
+            len = req.request.len(),
+            attachments = req.attachments.len(),
+            "request",
+        );
+
+        match self.request_tx.send(req).await {
+            Ok(()) => {
+                self.schedule_pending_composing(bare_jid);
+                self.pending_requests += 1;
+                self.update_presence().await;
+                Ok(())
+            }
+            Err(_) => Err(anyhow!("requests channel closed, terminating")),
+        }
+    }
+
+    /// Query the archive for the previous conversation before submitting the first request.
+    async fn query_history(
+        &mut self,
+        bare_jid: BareJid,
+        req: RequestMessage,
+    ) -> anyhow::Result<()> {
+        let query_id = self.next_message_id();
+        let iq_id = self.next_message_id();
+        let iq = Iq::from_set(iq_id.clone(), history_query(&query_id, &bare_jid));
+
+        if let Err(error) = self.client.send_stanza(iq.into()).await {
+            tracing::error!(target: LOG_TARGET, ?error, "failed to query conversation history");
+            self.history_restored.insert(req.jid.clone());
+            return self.send_request(bare_jid, req).await;
+        }
+
+        self.history_queries.insert(
+            req.jid.clone(),
+            HistoryQuery {
+                bare_jid,
+                query_id,
+                iq_id,
+                history: History::default(),
+                pending: vec![req],
+                deadline: Instant::now() + HISTORY_QUERY_TIMEOUT,
+            },
+        );
+
+        Ok(())
+    }
+
+    /// Submit the requests waiting for the history query `iq_id`, restoring the history if
+    /// `complete`.
+    async fn finish_history_query(&mut self, iq_id: &str, complete: bool) -> anyhow::Result<()> {
+        let Some(jid) = self
+            .history_queries
+            .iter()
+            .find_map(|(jid, query)| (query.iq_id == iq_id).then(|| jid.clone()))
+        else {
+            return Ok(());
+        };
+        let HistoryQuery {
+            bare_jid,
+            history,
+            pending,
+            ..
+        } = self
+            .history_queries
+            .remove(&jid)
+            .expect("query found above; qed");
+
+        let history = if complete {
+            history.finish()
+        } else {
+            Vec::new()
+        };
+        tracing::debug!(
+            target: LOG_TARGET,
+            jid,
+            turns = history.len(),
+            "conversation history restored",
+        );
+        self.history_restored.insert(jid);
+
+        let mut history = Some(history);
+        for mut req in pending {
+            req.history = history.take().unwrap_or_default();
+            self.send_request(bare_jid.clone(), req).await?;
+        }
+
+        Ok(())
+    }
+
+    async fn process_reactions(
+        &mut self,
+        bare_jid: BareJid,
+        reactions: Reactions,
+    ) -> anyhow::Result<()> {
+        let jid = bare_jid.as_str();
+
+        let Some(message) = self.sent_messages.get_mut(&reactions.id) else {
+            tracing::debug!(
+                target: LOG_TARGET,
+                jid,
+                id = reactions.id,
+                "reactions to unknown message",
+            );
+            return Ok(());
+        };
+
+        if message.jid != jid {
+            tracing::warn!(
+                target: LOG_TARGET,
+                jid,
+                id = reactions.id,
+                "reactions to a message sent to another user",
+            );
+            return Ok(());
+        }
+
+        let mut actions = Vec::new();
+        for reaction in reactions.reactions {
+            if message.reactions.insert(reaction.emoji.clone()) {
+                if let Some(action) = self.reactions.get(&reaction.emoji) {
+                    actions.push(*action);
+                }
+            }
+        }
+
+        for action in actions {
+            tracing::debug!(target: LOG_TARGET, jid, id = reactions.id, ?action, "reaction");
+
+            let Some(message) = self.sent_messages.get_mut(&reactions.id) else {
+                break;
+            };
+
+            match action {
+                ReactionAction::Like | ReactionAction::Dislike => {
+                    let positive = action == ReactionAction::Like;
+
+                    tracing::info!(
+                        target: LOG_TARGET,
+                        jid,
+                        id = reactions.id,
+                        positive,
+                        model = message.model,
+                        tokens_in = message.tokens_in,
+                        tokens_out = message.tokens_out,
+                        "feedback",
+                    );
+
+                    if let Some(ref feedback_log) = self.feedback_log {
+                        let record = FeedbackRecord::new(
+                            self.auth_jid.as_str(),
+                            &reactions.id,
+                            message,
+                            positive,
+                        );
+                        if let Err(error) = feedback_log.append(&record) {
+                            tracing::error!(target: LOG_TARGET, ?error, "failed to record feedback");
+                        }
+                    }
+                }
+                ReactionAction::Regenerate => {
+                    let request = message.request.clone();
+                    let thread = message.thread.clone();
+                    self.submit_request(bare_jid.clone(), thread, request, Vec::new())
+                        .await?;
+                }
+                ReactionAction::Shorter => {
+                    let request = format!(
+                        "Please make this answer of yours shorter:\n\n{}",
+                        message.response,
+                    );
+                    let thread = message.thread.clone();
+                    self.submit_request(bare_jid.clone(), thread, request, Vec::new())
+                        .await?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn send_delivery_receipt(&mut self, bare_jid: BareJid, id: &str) {
+        tracing::trace!(target: LOG_TARGET, jid = bare_jid.as_str(), "sending delivery receipt");
+
+        let received = Received { id: id.to_owned() };
+        let message = XmppMessage::new(Some(bare_jid.clone().into())).with_payload(received);
+
+        self.client
+            .send_stanza(message.into())
+            .await
+            .inspect_err(|error| {
+                tracing::warn!(
+                    target: LOG_TARGET,
+                    jid = bare_jid.as_str(),
+                    ?error,
+                    "error sending delivery receipt",
+                );
+            })
+            .unwrap_or_default();
+    }
+
+    async fn send_displayed_marker(&mut self, bare_jid: BareJid, id: &str) {
+        tracing::trace!(target: LOG_TARGET, jid = bare_jid.as_str(), "sending displayed marker");
+
+        let displayed = Element::builder("displayed", "urn:xmpp:chat-markers:0")
+            .attr("id", id)
+            .build();
+        let message =
+            XmppMessage::new(Some(bare_jid.clone().into())).with_payloads(vec![displayed]);
+
+        self.client
+            .send_stanza(message.into())
+            .await
+            .inspect_err(|error| {
+                tracing::warn!(
+                    target: LOG_TARGET,
+                    jid = bare_jid.as_str(),
+                    ?error,
+                    "error sending displayed marker",
+                );
+            })
+            .unwrap_or_default();
+    }
+
+    fn schedule_pending_composing(&mut self, bare_jid: BareJid) {
+        self.pending_composing.insert(
+            bare_jid,
+            async {
+                tokio::time::sleep(COMPOSING_DELAY).await;
+            }
+            .into_stream()
+            .boxed(),
+        );
+    }
+
+    async fn send_chat_state_notification(&mut self, bare_jid: BareJid, state: &str) {
+        let composing = Element::builder(state, "http://jabber.org/protocol/chatstates")
+            .prefix(None, "http://jabber.org/protocol/chatstates")
+            .expect("not a duplicate prefix; qed")
+            .build();
+        let no_store = Element::builder("no-store", "urn:xmpp:hints")
+            .prefix(None, "urn:xmpp:hints")
+            .expect("not a duplicate prefix; qed")
+            .build();
+        let message = XmppMessage::new(Some(bare_jid.clone().into()))
+            .with_payloads(vec![composing, no_store]);
+
+        self.client
+            .send_stanza(message.into())
+            .await
+            .inspect_err(|error| {
+                tracing::warn!(
+                    target: LOG_TARGET,
+                    jid = bare_jid.as_str(),
+                    ?error,
+                    "error sending chat state notification",
+                );
+            })
+            .unwrap_or_default();
+    }
+
+    async fn send_chat_state_composing(&mut self, bare_jid: BareJid) {
+        tracing::trace!(target: LOG_TARGET, jid = bare_jid.as_str(), "sending state composing");
+
+        self.send_chat_state_notification(bare_jid, "composing")
+            .await;
+    }
+
+    async fn send_chat_state_active(&mut self, bare_jid: BareJid) {
+        tracing::trace!(target: LOG_TARGET, jid = bare_jid.as_str(), "sending state active");
+
+        self.send_chat_state_notification(bare_jid, "active").await;
+    }
+
+    async fn approve_presence_subscription(&mut self, bare_jid: BareJid) {
+        if self.roster_contacts.insert(bare_jid.as_str().to_owned()) {
+            self.save_roster_contacts();
+        }
+
+        let presence = Presence::subscribed().with_to(bare_jid.clone());
+        if let Err(error) = self.client.send_stanza(presence.into()).await {
+            tracing::error!(
+                target: LOG_TARGET,
+                jid = bare_jid.to_string(),
+                ?error,
+                "error sending presence subscription pre-approval",
+            )
+        }
+    }
+
+    /// Answer subscription requests, and probes when running as a component, as there is no
+    /// server roster for components.
+    async fn process_presence(&mut self, presence: Presence) {
+        let Some(bare_jid) = presence.from.as_ref().map(Jid::to_bare) else {
+            return;
+        };
+        if !matches!(
+            presence.type_,
+            PresenceType::Subscribe | PresenceType::Probe
+        ) {
+            return;
+        }
+        if !self.is_allowed(bare_jid.as_str()) {
+            tracing::trace!(target: LOG_TARGET, jid = bare_jid.as_str(), "presence from unknown user");
+            if presence.type_ == PresenceType::Subscribe {
+                self.request_access(bare_jid).await;
+            }
+            return;
+        }
+
+        match presence.type_ {
+            PresenceType::Subscribe => {
+                self.approve_presence_subscription(bare_jid.clone()).await;
+                if self.client.is_component() {
+                    self.send_presence_to(bare_jid).await;
+                }
+            }
+            PresenceType::Probe if self.client.is_component() => {
+                self.send_presence_to(bare_jid).await
+            }
+            _ => {}
+        }
+    }
+
+    /// Whether the user is allowed by the config, access list or approved by admins. Deny
+    /// patterns take precedence, except for admins.
+    fn is_allowed(&self, jid: &str) -> bool {
+        self.admins.iter().any(|admin| admin.as_str() == jid)
+            || !self.is_denied(jid)
+                && (self.approved_jids.contains(jid)
+                    || self.allowed_jids.iter().any(|p| p.matches(jid))
+                    || self.access_list.allows(jid))
+    }
+
+    fn is_denied(&self, jid: &str) -> bool {
+        self.denied_jids.iter().any(|p| p.matches(jid)) || self.access_list.denies(jid)
+    }
+
+    /// Tell the unknown user once that they are not authorised, if enabled.
+    async fn reply_not_authorized(&mut self, bare_jid: BareJid) {
+        let jid = bare_jid.as_str().to_owned();
+        if !self.unauthorized_reply
+            || bare_jid.node().is_none()
+            || bare_jid == self.auth_jid
+            || self.is_denied(&jid)
+            || self.notified_jids.len() >= MAX_ACCESS_REQUESTS
+            || !self.notified_jids.insert(jid)
+        {
+            return;
+        }
+
+        let reply = match self.admin_approval {
+            true => format!("{NOT_AUTHORIZED_REPLY} Your access request was sent to the admins."),
+            false => NOT_AUTHORIZED_REPLY.to_string(),
+        };
+        self.send_xmpp_message(bare_jid, reply).await;
+    }
+
+    /// Show or edit the access list and return the reply to the admin.
+    async fn change_access_list(&mut self, change: AccessChange) -> String {
+        let reply = match change {
+            AccessChange::List => return self.access_list.describe(),
+            AccessChange::Allow(pattern) => {
+                self.access_list.allow.insert(pattern.clone());
+                format!("{pattern} allowed.")
+            }
+            AccessChange::Deny(pattern) => {
+                self.access_list.deny.insert(pattern.clone());
+                self.block(std::slice::from_ref(&pattern)).await;
+                format!("{pattern} denied.")
+            }
+            AccessChange::Remove(pattern) => {
+                let allowed = self.access_list.allow.remove(&pattern);
+                let denied = self.access_list.deny.remove(&pattern);
+                if !allowed && !denied {
+                    return format!("{pattern} is not in the access list.");
+                }
+                if denied {
+                    self.unblock(&pattern).await;
+                }
+                format!("{pattern} removed from the access list.")
+            }
+        };
+
+        tracing::info!(target: LOG_TARGET, access_list = ?self.access_list, "access list changed");
+        self.save_access_list();
+        self.drop_denied_users().await;
+
+        reply
+    }
+
+    fn save_access_list(&self) {
+        let Some(state_dir) = &self.state_dir else {
+            return;
+        };
+
+        if let Err(error) = state_dir.save(ACCESS_LIST_FILE, &self.access_list) {
+            tracing::error!(target: LOG_TARGET, ?error, "failed to save access list");
+        }
+    }
+
+    /// Forget the active chats and access requests of users no longer allowed, and remove them
+    /// from the roster.
+    async fn drop_denied_users(&mut self) {
+        let active_jids = std::mem::take(&mut self.active_jids);
+        self.active_jids = active_jids
+            .into_iter()
+            .filter(|jid| self.is_allowed(jid))
+            .collect();
+        let access_requests = std::mem::take(&mut self.access_requests);
+        let requests = access_requests.len();
+        self.access_requests = access_requests
+            .into_iter()
+            .filter(|jid| !self.is_denied(jid))
+            .collect();
+        if self.access_requests.len() != requests {
+            self.save_access_requests();
+        }
+
+        if self.online && !self.client.is_component() {
+            self.query_roster().await;
+        }
+    }
+
+    /// Block the deny patterns on the server (XEP-0191), where expressible as JIDs.
+    ///
+    /// Patterns matching an admin are only enforced by the bot, as blocking them on the server
+    /// would cut the admin off the account.
+    async fn block(&mut self, patterns: &[String]) {
+        let items = patterns
+            .iter()
+            .filter(|pattern| {
+                let pattern = WildMatch::new(pattern);
+                !self
+                    .admins
+                    .iter()
+                    .any(|admin| pattern.matches(admin.as_str()))
+            })
+            .filter_map(|pattern| blockable_jid(pattern))
+            .collect::<Vec<_>>();
+        if items.is_empty() || !self.online || self.client.is_component() {
+            return;
+        }
+
+        tracing::debug!(target: LOG_TARGET, ?items, "blocking denied users");
+
+        let iq = Iq::from_set(self.next_message_id(), Block { items });
+        if let Err(error) = self.client.send_stanza(iq.into()).await {
+            tracing::error!(target: LOG_TARGET, ?error, "failed to block denied users");
+        }
+    }
+
+    /// Unblock the removed deny pattern on the server, unless another deny pattern, e.g. from
+    /// `denied_users` of the config, still blocks the same JID.
+    async fn unblock(&mut self, pattern: &str) {
+        let Some(jid) = blockable_jid(pattern) else {
+            return;
+        };
+        if !self.online || self.client.is_component() {
+            return;
+        }
+        if self
+            .deny_patterns()
+            .iter()
+            .any(|pattern| blockable_jid(pattern).as_ref() == Some(&jid))
+        {
+            return;
+        }
+
+        let iq = Iq::from_set(self.next_message_id(), Unblock { items: vec![jid] });
+        if let Err(error) = self.client.send_stanza(iq.into()).await {
+            tracing::error!(target: LOG_TARGET, ?error, "failed to unblock user");
+        }
+    }
+
+    /// All deny patterns from the config and the access list.
+    fn deny_patterns(&self) -> Vec<String> {
+        self.denied_jids
+            .iter()
+            .map(ToString::to_string)
+            .chain(self.access_list.deny.iter().cloned())
+            .collect()
+    }
+
+    /// Forward the access request of an unknown user to admins, once per user.
+    async fn request_access(&mut self, bare_jid: BareJid) {
+        if !self.admin_approval
+            || self.is_denied(bare_jid.as_str())
+            || bare_jid.node().is_none()
+            || bare_jid == self.auth_jid
+            || self.access_requests.len() >= MAX_ACCESS_REQUESTS
+            || !self.access_requests.insert(bare_jid.as_str().to_owned())
+        {
+            return;
+        }
+
+        self.save_access_requests();
+        tracing::info!(target: LOG_TARGET, jid = bare_jid.as_str(), "access requested");
+
+        let body = access_request_message(bare_jid.as_str());
+        for admin in self.admins.clone() {
+            self.send_xmpp_message(admin, body.clone()).await;
+        }
+    }
+
+    /// Approve the user and return the outcome to report to the admin.
+    async fn approve_user(&mut self, bare_jid: BareJid) -> String {
+        let jid = bare_jid.as_str().to_owned();
+        let requested = self.access_requests.remove(&jid);
+        if requested {
+            self.save_access_requests();
+        }
+
+        if self.is_denied(&jid) {
+            return format!("{jid} is denied, remove the deny pattern first.");
+        }
+        if self.is_allowed(&jid) {
+            return format!("{jid} already has access.");
+        }
+
+        self.approved_jids.insert(jid.clone());
+        self.save_approved_users();
+        tracing::info!(target: LOG_TARGET, jid, "user approved");
+
+        self.approve_presence_subscription(bare_jid.clone()).await;
+        if requested {
+            self.send_xmpp_message(bare_jid, "Your access request was approved.".to_string())
+                .await;
+        }
+
+        format!("{jid} approved.")
+    }
+
+    /// Deny the access request or revoke the approval of the user.
+    async fn deny_user(&mut self, bare_jid: BareJid) -> Result<String, String> {
+        let jid = bare_jid.as_str().to_owned();
+
+        if !self.approved_jids.contains(&jid) && self.is_allowed(&jid) {
+            return Err(format!(
+                "{jid} is allowed by the config, remove it from `allowed_users` instead."
+            ));
+        }
+
+        let requested = self.access_requests.remove(&jid);
+        let approved = self.approved_jids.remove(&jid);
+        if !requested && !approved {
+            return Err(format!(
+                "{jid} has neither requested nor been granted access."
+            ));
+        }
+        if requested {
+            self.save_access_requests();
+        }
+        if approved {
+            self.save_approved_users();
+        }
+        tracing::info!(target: LOG_TARGET, jid, "user denied");
+
+        self.active_jids.remove(&jid);
+        self.revoke_presence_subscription(bare_jid.clone()).await;
+        if !self.client.is_component() {
+            self.remove_roster_item(bare_jid).await;
+        }
+        if self.roster_contacts.remove(&jid) {
+            self.save_roster_contacts();
+        }
+
+        Ok(format!("{jid} denied."))
+    }
+
+    fn save_approved_users(&self) {
+        let Some(state_dir) = &self.state_dir else {
+            return;
+        };
+
+        let approved = ApprovedUsers {
+            jids: self.approved_jids.iter().cloned().collect(),
+        };
+        if let Err(error) = state_dir.save(APPROVED_USERS_FILE, &approved) {
+            tracing::error!(target: LOG_TARGET, ?error, "failed to save approved users");
+        }
+    }
+
+    fn save_access_requests(&self) {
+        let Some(state_dir) = &self.state_dir else {
+            return;
+        };
+
+        let requests = AccessRequests {
+            jids: self.access_requests.iter().cloned().collect(),
+        };
+        if let Err(error) = state_dir.save(ACCESS_REQUESTS_FILE, &requests) {
+            tracing::error!(target: LOG_TARGET, ?error, "failed to save access requests");
+        }
+    }
+
+    fn save_roster_contacts(&self) {
+        let Some(state_dir) = &self.state_dir else {
+            return;
+        };
+
+        let contacts = RosterContacts {
+            jids: self.roster_contacts.iter().cloned().collect(),
+        };
+        if let Err(error) = state_dir.save(ROSTER_CONTACTS_FILE, &contacts) {
+            tracing::error!(target: LOG_TARGET, ?error, "failed to save roster contacts");
+        }
+    }
+
+    async fn revoke_presence_subscription(&mut self, bare_jid: BareJid) {
+        let presence = Presence::new(PresenceType::Unsubscribed).with_to(bare_jid.clone());
+        if let Err(error) = self.client.send_stanza(presence.into()).await {
+            tracing::error!(
+                target: LOG_TARGET,
+                jid = bare_jid.as_str(),
+                ?error,
+                "error revoking presence subscription",
+            )
+        }
+    }
+
+    /// Fetch the roster to remove the contacts added by the bot that are no longer allowed.
+    async fn query_roster(&mut self) {
+        let id = self.next_message_id();
+        let iq = Iq::from_get(id.clone(), roster_query());
+
+        match self.client.send_stanza(iq.into()).await {
+            Ok(()) => self.roster_query_id = Some(id),
+            Err(error) => tracing::error!(target: LOG_TARGET, ?error, "failed to query roster"),
+        }
+    }
+
+    async fn process_roster(&mut self, payload: Option<Element>) {
+        let roster = match payload.map(Roster::try_from) {
+            Some(Ok(roster)) => roster,
+            Some(Err(error)) => {
+                tracing::debug!(target: LOG_TARGET, ?error, "invalid roster");
+                return;
+            }
+            None => return,
+        };
+
+        self.update_nicknames(&roster.items);
+
+        // Contacts the bot did not add itself belong to the account owner and are kept.
+        let stale = roster
+            .items
+            .into_iter()
+            .map(|item| item.jid)
+            .filter(|jid| {
+                let jid = jid.as_str();
+                self.roster_contacts.contains(jid)
+                    && !self.is_allowed(jid)
+                    && !self.access_requests.contains(jid)
+            })
+            .collect::<Vec<_>>();
+
+        if stale.is_empty() {
+            return;
+        }
+        for bare_jid in stale {
+            tracing::info!(target: LOG_TARGET, jid = bare_jid.as_str(), "removing stale roster entry");
+            self.roster_contacts.remove(bare_jid.as_str());
+            self.remove_roster_item(bare_jid).await;
+        }
+        self.save_roster_contacts();
+    }
+
+    fn update_nicknames(&mut self, items: &[RosterItem]) {
+        for item in items {
+            let jid = item.jid.as_str().to_owned();
+            match (&item.subscription, &item.name) {
+                (Subscription::Remove, _) => {
+                    self.nicknames.remove(&jid);
+                }
+                (_, Some(name)) if !name.is_empty() => {
+                    self.nicknames.insert(jid, name.clone());
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Query the vCard of the user for the nickname.
+    async fn query_vcard(&mut self, bare_jid: BareJid) {
+        let id = self.next_message_id();
+        let iq = Iq::from_get(id.clone(), VCard { photo: None }).with_to(bare_jid.clone().into());
+
+        match self.client.send_stanza(iq.into()).await {
+            Ok(()) => {
+                self.vcard_queries.insert(id, bare_jid);
+            }
+            Err(error) => {
+                tracing::debug!(
+                    target: LOG_TARGET,
+                    jid = bare_jid.as_str(),
+                    ?error,
+                    "failed to query vcard",
+                );
+            }
+        }
+    }
+
+    async fn remove_roster_item(&mut self, bare_jid: BareJid) {
+        let id = self.next_message_id();
+        let iq = Iq::from_set(id, remove_item(bare_jid.clone()));
+
+        if let Err(error) = self.client.send_stanza(iq.into()).await {
+            tracing::error!(
+                target: LOG_TARGET,
+                jid = bare_jid.as_str(),
+                ?error,
+                "failed to remove roster entry",
+            );
+        }
+    }
+
+    async fn process_xmpp_event(&mut self, event: Event) -> anyhow::Result<()> {
+        match event {
+            Event::Online { bound_jid, .. } => {
+                tracing::info!(target: LOG_TARGET, "connected to XMPP server");
+                self.bound_jid = Some(bound_jid);
+                self.pending_ping = None;
+                self.online = true;
+                // Carbons, roster presence and the archive are only available to accounts.
+                if !self.client.is_component() {
+                    self.enable_carbons().await;
+                    self.presence_sent = None;
+                    self.send_presence().await;
+                    self.publish_profile().await;
+                    self.discover_upload_service().await;
+                    self.query_roster().await;
+                    self.block(&self.deny_patterns()).await;
+                    self.start_catch_up().await;
+                }
+            }
+            Event::Disconnected(error) => {
+                // Make sure to not spam with error during every reconnection attemp.
+                if self.online {
+                    tracing::error!(
+                        target: LOG_TARGET,
+                        ?error,
+                        "disconnected from XMPP server, reconnecting",
+                    );
+                    self.online = false;
+                }
+                self.save_mam_state();
+                self.pending_ping = None;
+                self.roster_query_id = None;
+                self.vcard_queries.clear();
+                self.upload_discovery.clear();
+                // Deferred messages are fetched from the archive again on the next catch-up,
+                // as the last processed stanza id was not advanced past them.
+                self.catch_up = None;
+                let history_queries = self
+                    .history_queries
+                    .values()
+                    .map(|query| query.iq_id.clone())
+                    .collect::<Vec<_>>();
+                for iq_id in history_queries {
+                    self.finish_history_query(&iq_id, false).await?;
+                }
+                // Dropping the senders fails the uploads waiting for slots.
+                self.pending_slots.clear();
+                // It is safe to sleep here, because we don't have any events to process while
+                // XMPP cllient is disconnected.
+                tokio::time::sleep(RECONNECT_DELAY).await;
+                self.reconnect();
+            }
+            Event::Stanza(stanza) => {
+                if stanza.is("message", ns::JABBER_CLIENT) {
+                    match XmppMessage::try_from(stanza) {
+                        Ok(message) if is_archive_result(&message) => {
+                            self.process_archived_message(message).await?;
+                        }
+                        Ok(message) => {
+                            if let Some(message) = unwrap_carbon(&self.auth_jid, message) {
+                                self.process_live_message(message).await?;
+                            }
+                        }
+                        Err(error) => {
+                            tracing::debug!(target: LOG_TARGET, ?error, "invalid message stanza");
+                        }
+                    }
+                } else if stanza.is("iq", ns::JABBER_CLIENT) {
+                    match Iq::try_from(stanza) {
+                        Ok(iq) => self.process_iq(iq).await?,
+                        Err(error) => {
+                            tracing::debug!(target: LOG_TARGET, ?error, "invalid iq stanza");
+                        }
+                    }
+                } else if stanza.is("presence", ns::JABBER_CLIENT) {
+                    match Presence::try_from(stanza) {
+                        Ok(presence) => self.process_presence(presence).await,
+                        Err(error) => {
+                            tracing::debug!(target: LOG_TARGET, ?error, "invalid presence stanza");
+                        }
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Sender of the IQ stanza.
+    ///
+    /// Stanzas without `from` come from our own account: roster pushes, and results of queries
+    /// to the account, like the archive query `<fin/>` that some servers send without `from`.
+    /// Treating them as coming from the account lets their ownership checks pass.
+    fn iq_sender(&self, from: Option<Jid>) -> Jid {
+        from.unwrap_or_else(|| self.auth_jid.clone().into())
+    }
+
+    async fn process_iq(&mut self, iq: Iq) -> anyhow::Result<()> {
+        let from = self.iq_sender(iq.from);
+
+        match iq.payload {
+            IqType::Get(payload) => {
+                self.process_iq_get(from, iq.id, payload).await;
+                Ok(())
+            }
+            IqType::Set(payload) => self.process_iq_set(from, iq.id, payload).await,
+            IqType::Result(payload) => {
+                self.process_ping_response(&iq.id);
+                if self
+                    .catch_up
+                    .as_ref()
+                    .is_some_and(|catch_up| catch_up.iq_id == iq.id)
+                {
+                    return self.process_archive_fin(from, payload).await;
+                }
+                if from.to_bare() == self.auth_jid {
+                    if self.roster_query_id.as_ref() == Some(&iq.id) {
+                        self.roster_query_id = None;
+                        self.process_roster(payload).await;
+                        return Ok(());
+                    }
+                    self.finish_history_query(&iq.id, true).await?;
+                }
+                self.process_iq_result(from, iq.id, payload).await;
+                Ok(())
+            }
+            IqType::Error(error) => {
+                self.process_ping_response(&iq.id);
+                if self
+                    .catch_up
+                    .as_ref()
+                    .is_some_and(|catch_up| catch_up.iq_id == iq.id)
+                    && from.to_bare() == self.auth_jid
+                {
+                    tracing::warn!(
+                        target: LOG_TARGET,
+                        condition = ?error.defined_condition,
+                        "archive query failed",
+                    );
+                    return self.finish_catch_up().await;
+                }
+                if from.to_bare() == self.auth_jid {
+                    self.finish_history_query(&iq.id, false).await?;
+                }
+                self.upload_discovery.remove(&iq.id);
+                self.vcard_queries.remove(&iq.id);
+                if let Some((jid, tx)) = self.pending_slots.remove(&iq.id) {
+                    if jid == from {
+                        let _ = tx.send(Err(anyhow!(
+                            "upload slot request failed: {:?}",
+                            error.defined_condition
+                        )));
+                    } else {
+                        self.pending_slots.insert(iq.id, (jid, tx));
+                    }
+                }
+                Ok(())
+            }
+        }
+    }
+
+    fn process_ping_response(&mut self, id: &str) {
+        // Even an error response to our ping proves the connection is alive.
+        if self
+            .pending_ping
+            .as_ref()
+            .is_some_and(|(ping_id, _)| ping_id == id)
+        {
+            tracing::trace!(target: LOG_TARGET, "ping response received");
+            self.pending_ping = None;
+        }
+    }
+
+    async fn process_iq_result(&mut self, from: Jid, id: String, payload: Option<Element>) {
+        if let Some(bare_jid) = self.vcard_queries.remove(&id) {
+            if from.to_bare() == bare_jid {
+                if let Some(nickname) = payload.as_ref().and_then(vcard_nickname) {
+                    self.nicknames
+                        .insert(bare_jid.as_str().to_owned(), nickname);
+                }
+            }
+            return;
+        }
+
+        if let Some((jid, tx)) = self.pending_slots.remove(&id) {
+            if jid != from {
+                tracing::warn!(
+                    target: LOG_TARGET,
+                    from = from.to_string(),
+                    "upload slot from unexpected JID",
+                );
+                self.pending_slots.insert(id, (jid, tx));
+                return;
+            }
+
+            let slot = payload
+                .ok_or_else(|| anyhow!("empty upload slot response"))
+                .and_then(|payload| {
+                    SlotResult::try_from(payload)
+                        .map_err(|error| anyhow!("invalid upload slot: {error}"))
+                });
+            let _ = tx.send(slot);
+            return;
+        }
+
+        if self.upload_discovery.get(&id) != Some(&from) {
+            return;
+        }
+        self.upload_discovery.remove(&id);
+
+        let Some(payload) = payload else {
+            return;
+        };
+
+        if let Ok(items) = DiscoItemsResult::try_from(payload.clone()) {
+            for item in items.items {
+                self.send_upload_discovery_query(item.jid).await;
+            }
+        } else if let Ok(info) = DiscoInfoResult::try_from(payload) {
+            if self.upload_service.is_some() {
+                return;
+            }
+
+            if let Some(service) = UploadService::from_disco_info(from, &info) {
+                tracing::info!(
+                    target: LOG_TARGET,
+                    jid = service.jid.to_string(),
+                    max_file_size = service.max_file_size,
+                    "discovered HTTP upload service",
+                );
+                self.upload_service = Some(service);
+            }
+        }
+    }
+
+    async fn process_iq_get(&mut self, from: Jid, id: String, payload: Element) {
+        let is_admin = self.admins.contains(&from.to_bare());
+
+        if let Ok(query) = DiscoInfoQuery::try_from(payload.clone()) {
+            if let Some(info) = self.disco_info.info(query.node.clone()) {
+                self.send_iq_result(from, id, Some(info.into())).await;
+            } else if let Some(command) = query.node.as_deref().and_then(AdminCommand::from_node) {
+                if is_admin {
+                    self.send_iq_result(from, id, Some(command_info(command).into()))
+                        .await;
+                } else {
+                    self.send_iq_error(from, id, ErrorType::Auth, DefinedCondition::Forbidden)
+                        .await;
+                }
+            } else {
+                self.send_iq_error(from, id, ErrorType::Cancel, DefinedCondition::ItemNotFound)
+                    .await;
+            }
+        } else if payload.is("vCard", ns::VCARD) {
+            self.send_iq_result(from, id, Some(self.profile.vcard()))
+                .await;
+        } else if Ping::try_from(payload.clone()).is_ok() {
+            self.send_iq_result(from, id, None).await;
+        } else if VersionQuery::try_from(payload.clone()).is_ok() {
+            let version = VersionResult {
+                name: SOFTWARE_NAME.to_string(),
+                version: env!("CARGO_PKG_VERSION").to_string(),
+                os: self.report_os.then(|| std::env::consts::OS.to_string()),
+            };
+            self.send_iq_result(from, id, Some(version.into())).await;
+        } else if let Ok(query) = DiscoItemsQuery::try_from(payload) {
+            match query.node.as_deref() {
+                None => {
+                    let items = DiscoItemsResult {
+                        node: None,
+                        items: Vec::new(),
+                        rsm: None,
+                    };
+                    self.send_iq_result(from, id, Some(items.into())).await;
+                }
+                Some(NS_COMMANDS) => {
+                    let commands: &[AdminCommand] = if is_admin { &AdminCommand::ALL } else { &[] };
+                    let jid = self.own_jid();
+                    self.send_iq_result(from, id, Some(command_list(&jid, commands).into()))
+                        .await;
+                }
+                Some(_) => {
+                    self.send_iq_error(from, id, ErrorType::Cancel, DefinedCondition::ItemNotFound)
+                        .await;
+                }
+            }
+        } else {
+            self.send_iq_error(
+                from,
+                id,
+                ErrorType::Cancel,
+                DefinedCondition::ServiceUnavailable,
+            )
+            .await;
+        }
+    }
+
+    async fn process_iq_set(
+        &mut self,
+        from: Jid,
+        id: String,
+        payload: Element,
+    ) -> anyhow::Result<()> {
+        if payload.is("query", ns::ROSTER)
+            || payload.is("block", ns::BLOCKING)
+            || payload.is("unblock", ns::BLOCKING)
+        {
+            // Roster and blocklist pushes must be acknowledged, the fresh roster is fetched on
+            // reconnect.
+            if from.to_bare() == self.auth_jid {
+                if let Ok(roster) = Roster::try_from(payload) {
+                    self.update_nicknames(&roster.items);
+                }
+                self.send_iq_result(from, id, None).await;
+            } else {
+                self.send_iq_error(
+                    from,
+                    id,
+                    ErrorType::Cancel,
+                    DefinedCondition::ServiceUnavailable,
+                )
+                .await;
+            }
+            return Ok(());
+        }
+
+        let Some(request) = CommandRequest::parse(&payload) else {
+            self.send_iq_error(
+                from,
+                id,
+                ErrorType::Cancel,
+                DefinedCondition::ServiceUnavailable,
+            )
+            .await;
+            return Ok(());
+        };
+
+        if !self.admins.contains(&from.to_bare()) {
+            tracing::warn!(
+                target: LOG_TARGET,
+                jid = from.to_string(),
+                node = request.node,
+                "ad-hoc command from non-admin user",
+            );
+            self.send_iq_error(from, id, ErrorType::Auth, DefinedCondition::Forbidden)
+                .await;
+            return Ok(());
+        }
+
+        let Some(command) = AdminCommand::from_node(&request.node) else {
+            self.send_iq_error(from, id, ErrorType::Cancel, DefinedCondition::ItemNotFound)
+                .await;
+            return Ok(());
+        };
+
+        let sessionid = request
+            .sessionid
+            .clone()
+            .unwrap_or_else(|| self.next_message_id());
+
+        if request.action.as_deref() == Some("cancel") {
+            let response = canceled_response(&request.node, &sessionid);
+            self.send_iq_result(from, id, Some(response)).await;
+            return Ok(());
+        }
+
+        if request.form.is_none() {
+            if let Some(form) = command.input_form(&self.model) {
+                let response = executing_response(&request.node, &sessionid, form);
+                self.send_iq_result(from, id, Some(response)).await;
+                return Ok(());
+            }
+        }
+
+        tracing::info!(
+            target: LOG_TARGET,
+            jid = from.to_string(),
+            ?command,
+            "executing admin command",
+        );
+
+        let outcome = self
+            .execute_admin_command(command, &request, from.to_bare())
+            .await?;
+        let response = completed_response(&request.node, &sessionid, outcome);
+        self.send_iq_result(from, id, Some(response)).await;
+
+        Ok(())
+    }
+
+    /// Execute admin command and return the outcome to report to the admin.
+    async fn execute_admin_command(
+        &mut self,
+        command: AdminCommand,
+        request: &CommandRequest,
+        admin: BareJid,
+    ) -> anyhow::Result<Result<String, String>> {
+        let outcome = match command {
+            AdminCommand::ListChats => {
+                let mut chats = self.active_jids.iter().collect::<Vec<_>>();
+                chats.sort();
+
+                if chats.is_empty() {
+                    Ok("No active chats.".to_string())
+                } else {
+                    Ok(chats
+                        .into_iter()
+                        .map(|jid| {
+                            let usage = self.usage.get(jid).copied().unwrap_or_default();
+                            format!(
+                                "{jid}: {} requests, {} tokens in ({} cached), {} tokens out",
+                                usage.requests,
+                                usage.tokens_in,
+                                usage.tokens_in_cached,
+                                usage.tokens_out,
+                            )
+                        })
+                        .collect::<Vec<_>>()
+                        .join("\n"))
+                }
+            }
+            AdminCommand::Usage => {
+                let mut total = ChatUsage::default();
+                self.usage.values().for_each(|usage| total.add(usage));
+
+                Ok(format!(
+                    "Active chats: {}\nRequests: {}\nTokens in: {} ({} cached)\n\
+                     Tokens out: {}",
+                    self.active_jids.len(),
+                    total.requests,
+                    total.tokens_in,
+                    total.tokens_in_cached,
+                    total.tokens_out,
+                ))
+            }
+            AdminCommand::ResetConversation => {
+                let bare_jid = request.value("jid").map(|jid| BareJid::new(jid.trim()));
+
+                match bare_jid {
+                    Some(Ok(bare_jid)) => {
+                        let jid = bare_jid.as_str().to_owned();
+                        self.pending_composing.remove(&bare_jid);
+                        self.active_jids.remove(&jid);
+                        let threads = self.threads.remove(&jid).unwrap_or_default();
+                        for thread in
+                            std::iter::once(None).chain(threads.names().cloned().map(Some))
+                        {
+                            self.send_engine_command(EngineCommand::ResetConversation {
+                                jid: jid.clone(),
+                                thread,
+                            })
+                            .await?;
+                        }
+                        Ok(format!("Conversation with {jid} reset."))
+                    }
+                    Some(Err(error)) => Err(format!("Invalid JID: {error}")),
+                    None => Err("JID is required.".to_string()),
+                }
+            }
+            AdminCommand::ReloadConfig => {
+                match FileConfig::load(&self.config_path).and_then(|config| match &self.bot {
+                    Some(name) if self.client.is_component() => config.for_bot(name),
+                    Some(name) => config.for_account(name),
+                    None => Ok(config),
+                }) {
+                    Ok(config) => {
+                        self.apply_config(config).await?;
+                        Ok(
+                            "Config reloaded. Changes to JID and password require restart."
+                                .to_string(),
+                        )
+                    }
+                    Err(error) => Err(format!("Failed to reload config: {error:#}")),
+                }
+            }
+            AdminCommand::SetModel => match request.value("model") {
+                Some(model) => {
+                    let model = model.trim().to_string();
+                    self.model = model.clone();
+                    self.send_engine_command(EngineCommand::SetModel {
+                        model: model.clone(),
+                    })
+                    .await?;
+                    Ok(format!(
+                        "Default model set to {model}. It is used for new conversations."
+                    ))
+                }
+                None => Err("Model is required.".to_string()),
+            },
+            AdminCommand::Broadcast => match request.value("message") {
+                Some(message) => {
+                    let mut jids = self.active_jids.iter().cloned().collect::<Vec<_>>();
+                    jids.sort();
+
+                    for jid in &jids {
+                        if let Ok(bare_jid) = BareJid::new(jid) {
+                            self.send_xmpp_message(bare_jid, message.clone()).await;
+                        }
+                    }
+
+                    Ok(format!("Message sent to {} users.", jids.len()))
+                }
+                None => Err("Message is required.".to_string()),
+            },
+            AdminCommand::AccessRequests => {
+                let mut jids = self.access_requests.iter().collect::<Vec<_>>();
+                jids.sort();
+
+                if jids.is_empty() {
+                    Ok("No pending access requests.".to_string())
+                } else {
+                    Ok(jids
+                        .into_iter()
+                        .map(String::as_str)
+                        .collect::<Vec<_>>()
+                        .join("\n"))
+                }
+            }
+            AdminCommand::ApproveUser => {
+                match request.value("jid").map(|jid| BareJid::new(jid.trim())) {
+                    Some(Ok(bare_jid)) => Ok(self.approve_user(bare_jid).await),
+                    Some(Err(error)) => Err(format!("Invalid JID: {error}")),
+                    None => Err("JID is required.".to_string()),
+                }
+            }
+            AdminCommand::DenyUser => {
+                match request.value("jid").map(|jid| BareJid::new(jid.trim())) {
+                    Some(Ok(bare_jid)) => self.deny_user(bare_jid).await,
+                    Some(Err(error)) => Err(format!("Invalid JID: {error}")),
+                    None => Err("JID is required.".to_string()),
+                }
+            }
+            AdminCommand::ExportConversation => {
+                let bare_jid = request.value("jid").map(|jid| BareJid::new(jid.trim()));
+                let format = request
+                    .value("format")
+                    .map(|format| format.parse::<ExportFormat>())
+                    .unwrap_or(Ok(ExportFormat::Markdown));
+                let full = request
+                    .value("full")
+                    .is_some_and(|full| full == "1" || full == "true");
+
+                match (bare_jid, format) {
+                    (Some(Ok(bare_jid)), Ok(format)) => {
+                        let jid = bare_jid.as_str().to_owned();
+                        self.export_conversation(admin, jid.clone(), format, full)
+                            .await?
+                            .map(|()| format!("Conversation with {jid} sent to you."))
+                    }
+                    (Some(Err(error)), _) => Err(format!("Invalid JID: {error}")),
+                    (None, _) => Err("JID is required.".to_string()),
+                    (_, Err(error)) => Err(error.to_string()),
+                }
+            }
+        };
+
+        Ok(outcome)
+    }
+
+    /// Apply reloaded config. JID and password are only read on startup.
+    async fn apply_config(&mut self, config: FileConfig) -> anyhow::Result<()> {
+        let FileConfig {
+            auth_jid: _,
+            auth_password: _,
+            server: _,
+            ping_timeout,
+            report_os,
+            profile,
+            allowed_users,
+            denied_users,
+            admins,
+            admin_approval,
+            unauthorized_reply,
+            api_url,
+            api_options,
+            api_version,
+            api_auth,
+            http_timeout,
+            model,
+            vision_models,
+            system_message,
+            personas,
+            variables,
+            verbosity,
+            min_history_tokens,
+            max_history_tokens,
+            summary,
+            max_attachment_size,
+            reactions,
+            feedback_log,
+            upload_threshold,
+            max_message_length,
+            message_styling,
+            user_models,
+            xhtml_im,
+            state_dir: _,
+            mam_max_age,
+            restore_history,
+            component: _,
+            bots: _,
+            accounts: _,
+            bot: _,
+        } = config;
+
+        self.ping_timeout = ping_timeout;
+        self.report_os = report_os;
+        self.profile = profile;
+        self.allowed_jids = allowed_users
+            .into_iter()
+            .map(|p| WildMatch::new(&p))
+            .collect();
+        self.denied_jids = denied_users
+            .into_iter()
+            .map(|p| WildMatch::new(&p))
+            .collect();
+        self.admins = admins;
+        self.admin_approval = admin_approval;
+        self.unauthorized_reply = unauthorized_reply;
+        self.model = model.clone();
+        self.reactions = reactions;
+        self.feedback_log = feedback_log.map(FeedbackLog::new);
+        self.upload_threshold = upload_threshold;
+        self.max_message_length = max_message_length;
+        self.message_styling = message_styling;
+        self.user_models = user_models;
+        self.personas = personas.keys().cloned().collect();
+        self.summarize = summary.is_some();
+        self.xhtml_im = xhtml_im;
+        self.mam_max_age = mam_max_age;
+        self.restore_history = restore_history;
+
+        self.block(&self.deny_patterns()).await;
+        self.drop_denied_users().await;
+        if self.online && !self.client.is_component() {
+            self.publish_profile().await;
+        }
+        self.presence_sent = None;
+        self.update_presence().await;
+
+        self.send_engine_command(EngineCommand::UpdateConfig(Box::new(EngineConfig {
+            api_url,
+            api_options,
+            api_version,
+            api_auth,
+            http_timeout,
+            model,
+            vision_models,
+            system_message,
+            personas,
+            variables,
+            verbosity,
+            min_history_tokens,
+            max_history_tokens,
+            summary,
+            max_attachment_size,
+        })))
+        .await
+    }
+
+    async fn send_engine_command(&mut self, command: EngineCommand) -> anyhow::Result<()> {
+        self.command_tx
+            .send(command)
+            .await
+            .map_err(|_| anyhow!("commands channel closed, terminating"))
+    }
+
+    /// Own JID as bound by the server.
+    fn own_jid(&self) -> Jid {
+        self.bound_jid
+            .clone()
+            .unwrap_or_else(|| self.auth_jid.clone().into())
+    }
+
+    async fn send_iq_result(&mut self, to: Jid, id: String, payload: Option<Element>) {
+        let iq = Iq {
+            from: None,
+            to: Some(to.clone()),
+            id,
+            payload: IqType::Result(payload),
+        };
+
+        if let Err(error) = self.client.send_stanza(iq.into()).await {
+            tracing::warn!(
+                target: LOG_TARGET,
+                jid = to.to_string(),
+                ?error,
+                "error sending iq result",
+            );
+        }
+    }
+
+    async fn send_iq_error(
+        &mut self,
+        to: Jid,
+        id: String,
+        type_: ErrorType,
+        condition: DefinedCondition,
+    ) {
+        let mut error = StanzaError::new(type_, condition, "en", "");
+        error.texts.clear();
+        let iq = Iq::from_error(id, error).with_to(to.clone());
+
+        if let Err(error) = self.client.send_stanza(iq.into()).await {
+            tracing::warn!(
+                target: LOG_TARGET,
+                jid = to.to_string(),
+                ?error,
+                "error sending iq error",
+            );
+        }
+    }
+
+    async fn enable_carbons(&mut self) {
+        tracing::trace!(target: LOG_TARGET, "enabling message carbons");
+
+        let id = self.next_message_id();
+        let iq = Iq::from_set(id, EnableCarbons);
+
+        if let Err(error) = self.client.send_stanza(iq.into()).await {
+            tracing::error!(target: LOG_TARGET, ?error, "failed to enable message carbons");
+        }
+    }
+
+    async fn send_ping(&mut self) {
+        tracing::trace!(target: LOG_TARGET, "sending ping");
+
+        let id = self.next_message_id();
+        let server = BareJid::from_parts(None, self.auth_jid.domain());
+        let iq = Iq::from_get(id.clone(), Ping).with_to(server.into());
+
+        match self.client.send_stanza(iq.into()).await {
+            Ok(()) => self.pending_ping = Some((id, Instant::now() + self.ping_timeout)),
+            Err(error) => tracing::error!(target: LOG_TARGET, ?error, "failed to send ping"),
+        }
+    }
+
+    /// Look for XEP-0363 upload service on the server and its components.
+    async fn discover_upload_service(&mut self) {
+        tracing::trace!(target: LOG_TARGET, "discovering upload service");
+
+        self.upload_service = None;
+        let server: Jid = BareJid::from_parts(None, self.auth_jid.domain()).into();
+
+        self.send_upload_discovery_query(server.clone()).await;
+
+        let id = self.next_message_id();
+        let iq = Iq::from_get(
+            id.clone(),
+            DiscoItemsQuery {
+                node: None,
+                rsm: None,
+            },
+        )
+        .with_to(server.clone());
+        match self.client.send_stanza(iq.into()).await {
+            Ok(()) => {
+                self.upload_discovery.insert(id, server);
+            }
+            Err(error) => {
+                tracing::error!(target: LOG_TARGET, ?error, "failed to query disco#items")
+            }
+        }
+    }
+
+    async fn send_upload_discovery_query(&mut self, jid: Jid) {
+        let id = self.next_message_id();
+        let iq = Iq::from_get(id.clone(), DiscoInfoQuery { node: None }).with_to(jid.clone());
+
+        match self.client.send_stanza(iq.into()).await {
+            Ok(()) => {
+                self.upload_discovery.insert(id, jid);
+            }
+            Err(error) => tracing::error!(target: LOG_TARGET, ?error, "failed to query disco#info"),
+        }
+    }
+
+    /// Availability and status advertising the model and the load.
+    fn presence_status(&self) -> (PresenceShow, String) {
+        if self.api_errors >= DEGRADED_AFTER_ERRORS {
+            (PresenceShow::Away, format!("{} · API degraded", self.model))
+        } else if self.pending_requests >= BUSY_REQUESTS {
+            (
+                PresenceShow::Dnd,
+                format!("{} · busy: {} queued", self.model, self.pending_requests),
+            )
+        } else {
+            (PresenceShow::Chat, format!("{} · ready", self.model))
+        }
+    }
+
+    fn presence(&self) -> Presence {
+        let (show, status) = self.presence_status();
+        let mut payloads = vec![self.disco_info.caps().into()];
+        if let Some(update) = self.profile.vcard_update() {
+            payloads.push(update.into());
+        }
+
+        let mut presence = Presence::available()
+            .with_show(show)
+            .with_payloads(payloads);
+        presence.set_status("", status);
+        presence
+    }
+
+    /// Resend presence if the status changed. Changes of the queue size only are rate-limited.
+    async fn update_presence(&mut self) {
+        if !self.online {
+            return;
+        }
+
+        let (show, status) = self.presence_status();
+        if let Some((sent_show, sent_status, sent_at)) = &self.presence_sent {
+            if *sent_status == status
+                || *sent_show == show && sent_at.elapsed() < PRESENCE_UPDATE_INTERVAL
+            {
+                return;
+            }
+        }
+
+        if self.client.is_component() {
+            let mut jids = self.active_jids.iter().cloned().collect::<Vec<_>>();
+            jids.sort();
+            for jid in jids {
+                if let Ok(bare_jid) = BareJid::new(&jid) {
+                    self.send_presence_to(bare_jid).await;
+                }
+            }
+        } else {
+            self.send_presence().await;
+        }
+    }
+
+    /// Publish the nickname and avatar via PEP and vCard.
+    async fn publish_profile(&mut self) {
+        if self.profile.is_empty() {
+            return;
+        }
+
+        let publications = match self.profile.publications() {
+            Ok(publications) => publications,
+            Err(error) => {
+                tracing::error!(target: LOG_TARGET, ?error, "failed to prepare profile");
+                return;
+            }
+        };
+        let mut iqs = publications
+            .into_iter()
+            .map(|publication| Iq::from_set(self.next_message_id(), publication))
+            .collect::<Vec<_>>();
+        iqs.push(Iq {
+            from: None,
+            to: None,
+            id: self.next_message_id(),
+            payload: IqType::Set(self.profile.vcard()),
+        });
+
+        tracing::debug!(target: LOG_TARGET, nickname = ?self.profile.nickname, "publishing profile");
+
+        for iq in iqs {
+            if let Err(error) = self.client.send_stanza(iq.into()).await {
+                tracing::error!(target: LOG_TARGET, ?error, "failed to publish profile");
+            }
+        }
+    }
+
+    async fn send_presence(&mut self) {
+        tracing::trace!(target: LOG_TARGET, "sending presence");
+
+        let presence = self.presence();
+        self.record_presence();
+
+        if let Err(error) = self.client.send_stanza(presence.into()).await {
+            tracing::error!(target: LOG_TARGET, ?error, "failed to send presence");
+        }
+    }
+
+    async fn send_presence_to(&mut self, bare_jid: BareJid) {
+        let presence = self.presence().with_to(bare_jid.clone());
+        self.record_presence();
+
+        if let Err(error) = self.client.send_stanza(presence.into()).await {
+            tracing::error!(
+                target: LOG_TARGET,
+                jid = bare_jid.as_str(),
+                ?error,
+                "failed to send presence",
+            );
+        }
+    }
+
+    fn record_presence(&mut self) {
+        let (show, status) = self.presence_status();
+        self.presence_sent = Some((show, status, Instant::now()));
+    }
+
+    pub async fn run(mut self) -> anyhow::Result<()> {
+        let mut ping_tick = tokio::time::interval(PING_INTERVAL);
+        ping_tick.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+        loop {
+            let ping_deadline = self.pending_ping.as_ref().map(|(_, deadline)| *deadline);
+            let catch_up_deadline = self.catch_up.as_ref().map(|catch_up| catch_up.deadline);
+            let history_deadline = self
+                .history_queries
+                .values()
+                .map(|query| query.deadline)
+                .min();
+
+            tokio::select! {
+                event = self.client.next() => {
+                    if let Some(event) = event {
+                        self.process_xmpp_event(event).await?;
+                    } else {
+                        self.save_mam_state();
+                        return Err(anyhow!("XMPP event stream was closed, terminating"))
+                    }
+                }
+                // TODO: checking for `self.online` here is a band-aid to reduce the chances of
+                // losing responses. Ideally, we should queue responses and only discard them
+                // once they have been sent out without errors.
+                message = self.response_rx.recv(), if self.online => {
+                    if let Some(message) = message {
+                        self.process_response(message).await;
+                    } else {
+                        tracing::trace!(target: LOG_TARGET, "response channel closed, shutting down");
+                        self.save_mam_state();
+                        return Ok(())
+                    }
+                }
+                _ = ping_tick.tick() => {
+                    // Component connection is monitored by `ComponentRouter`.
+                    if self.online && self.pending_ping.is_none() && !self.client.is_component() {
+                        // This makes sure we detect dropped TCP stream and reconnect.
+                        self.send_ping().await;
+                    }
+                    // Catch up on the rate-limited status changes.
+                    self.update_presence().await;
+                    self.save_mam_state();
+                }
+                _ = tokio::time::sleep_until(ping_deadline.unwrap_or_else(Instant::now)),
+                    if ping_deadline.is_some() =>
+                {
+                    tracing::error!(
+                        target: LOG_TARGET,
+                        timeout = ?self.ping_timeout,
+                        "no ping response from XMPP server, reconnecting",
+                    );
+                    self.pending_ping = None;
+                    self.online = false;
+                    // Dropping the senders fails the uploads waiting for slots.
+                    self.pending_slots.clear();
+                    self.save_mam_state();
+                    self.reconnect();
+                }
+                _ = tokio::time::sleep_until(catch_up_deadline.unwrap_or_else(Instant::now)),
+                    if catch_up_deadline.is_some() =>
+                {
+                    tracing::warn!(
+                        target: LOG_TARGET,
+                        timeout = ?CATCH_UP_TIMEOUT,
+                        "archive catch-up timed out, processing live messages",
+                    );
+                    self.finish_catch_up().await?;
+                }
+                _ = tokio::time::sleep_until(history_deadline.unwrap_or_else(Instant::now)),
+                    if history_deadline.is_some() =>
+                {
+                    let now = Instant::now();
+                    let expired = self
+                        .history_queries
+                        .values()
+                        .filter(|query| query.deadline <= now)
+                        .map(|query| query.iq_id.clone())
+                        .collect::<Vec<_>>();
+                    for iq_id in expired {
+                        tracing::warn!(
+                            target: LOG_TARGET,
+                            iq_id,
+                            "conversation history query timed out",
+                        );
+                        self.finish_history_query(&iq_id, false).await?;
+                    }
+                }
+                upload = self.pending_uploads.select_next_some(),
+                    if self.online && !self.pending_uploads.is_empty() =>
+                {
+                    self.finish_upload(upload).await;
+                }
+                event = self.pending_composing.next(), if !self.pending_composing.is_empty() => {
+                    if let Some((bare_jid, ())) = event {
+                        self.send_chat_state_composing(bare_jid).await;
+                    }
+                }
+            }
+        }
+    }
+}
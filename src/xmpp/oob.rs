@@ -0,0 +1,120 @@
+// Copyright (c) 2024 Dmitry Markin
+//
+// SPDX-License-Identifier: MIT
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! XEP-0066 out of band data and XEP-0363 HTTP upload attachments.
+
+use xmpp_parsers::{message::Message as XmppMessage, oob::Oob};
+
+// Log target for this file.
+const LOG_TARGET: &str = "jutella::oob";
+
+/// Split the message into the request text and attachment URLs.
+///
+/// Attachments are the URLs from `jabber:x:oob` payloads and `aesgcm://` links of OMEMO
+/// encrypted uploads. Clients also put the attachment URL into the body for compatibility,
+/// so these lines are removed from the request text.
+pub fn extract_attachments(message: &mut XmppMessage, body: &str) -> (String, Vec<String>) {
+    let mut attachments = Vec::new();
+
+    loop {
+        match message.extract_payload::<Oob>() {
+            Ok(Some(oob)) => {
+                if !attachments.contains(&oob.url) {
+                    attachments.push(oob.url);
+                }
+            }
+            Ok(None) => break,
+            Err(error) => {
+                tracing::debug!(target: LOG_TARGET, ?error, "invalid oob payload");
+                break;
+            }
+        }
+    }
+
+    let mut text = Vec::new();
+    for line in body.lines() {
+        let line_trimmed = line.trim();
+
+        if attachments.iter().any(|url| url == line_trimmed) {
+            continue;
+        }
+
+        if line_trimmed.starts_with("aesgcm://") && !line_trimmed.contains(char::is_whitespace) {
+            attachments.push(line_trimmed.to_string());
+            continue;
+        }
+
+        text.push(line);
+    }
+
+    (text.join("\n").trim().to_string(), attachments)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use xmpp_parsers::minidom::Element;
+
+    fn message(payloads: &str) -> XmppMessage {
+        let element: Element = format!(
+            "<message xmlns='jabber:client' to='bot@example.org' type='chat'>{payloads}</message>"
+        )
+        .parse()
+        .unwrap();
+
+        XmppMessage::try_from(element).unwrap()
+    }
+
+    #[test]
+    fn oob_url_removed_from_body() {
+        let url = "https://upload.example.org/a/photo.png";
+        let mut message = message(&format!(
+            "<x xmlns='jabber:x:oob'><url>{url}</url></x><x xmlns='jabber:x:oob'><url>{url}</url></x>"
+        ));
+
+        let (text, attachments) =
+            extract_attachments(&mut message, &format!("What is this?\n{url}"));
+        assert_eq!(text, "What is this?");
+        assert_eq!(attachments, vec![url.to_string()]);
+        assert!(message.payloads.is_empty());
+    }
+
+    #[test]
+    fn aesgcm_link_in_body() {
+        let url = "aesgcm://upload.example.org/a/photo.jpg#00ff";
+        let mut message = message("");
+
+        let (text, attachments) = extract_attachments(&mut message, &format!("  {url}  \n"));
+        assert_eq!(text, "");
+        assert_eq!(attachments, vec![url.to_string()]);
+    }
+
+    #[test]
+    fn plain_links_kept() {
+        let body = "Look at https://example.org/photo.png\naesgcm://not a link";
+        let mut message = message("");
+
+        let (text, attachments) = extract_attachments(&mut message, body);
+        assert_eq!(text, body);
+        assert!(attachments.is_empty());
+    }
+}
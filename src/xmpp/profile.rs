@@ -0,0 +1,146 @@
+// Copyright (c) 2024 Dmitry Markin
+//
+// SPDX-License-Identifier: MIT
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! Bot nickname and avatar published via PEP (XEP-0084, XEP-0172) and vCard (XEP-0153).
+
+use crate::config::{Avatar, Profile};
+use anyhow::anyhow;
+use xmpp_parsers::{
+    avatar::{Data, Info, Metadata},
+    hashes::Sha1HexAttribute,
+    minidom::Element,
+    nick::Nick,
+    ns,
+    pubsub::{
+        pubsub::{Item, Publish},
+        Item as PubSubItem, ItemId, NodeName, PubSub,
+    },
+    vcard::{Binval, Photo, Type, VCard},
+    vcard_update::{Photo as UpdatePhoto, VCardUpdate},
+};
+
+impl Avatar {
+    /// SHA-1 of the image as hex, used as the avatar id.
+    fn id(&self) -> String {
+        self.hash.iter().map(|byte| format!("{byte:02x}")).collect()
+    }
+}
+
+impl Profile {
+    /// vCard-temp with the nickname and photo.
+    pub fn vcard(&self) -> Element {
+        let photo = self.avatar.as_ref().map(|avatar| Photo {
+            type_: Type {
+                data: avatar.content_type.clone(),
+            },
+            binval: Binval {
+                data: avatar.data.clone(),
+            },
+        });
+        let mut vcard = Element::from(VCard { photo });
+
+        if let Some(nickname) = &self.nickname {
+            vcard.append_child(
+                Element::builder("NICKNAME", ns::VCARD)
+                    .append(nickname.as_str())
+                    .build(),
+            );
+        }
+
+        vcard
+    }
+
+    /// Avatar hash advertised in presence.
+    pub fn vcard_update(&self) -> Option<VCardUpdate> {
+        self.avatar.as_ref().map(|avatar| VCardUpdate {
+            photo: Some(UpdatePhoto {
+                data: Some(avatar.hash),
+            }),
+        })
+    }
+
+    /// PEP publications of the avatar data, avatar metadata and nickname, in this order.
+    pub fn publications(&self) -> anyhow::Result<Vec<PubSub>> {
+        let mut publications = Vec::new();
+
+        if let Some(avatar) = &self.avatar {
+            let id = avatar.id();
+            let info = Info {
+                bytes: avatar.data.len() as u32,
+                width: None,
+                height: None,
+                id: id
+                    .parse::<Sha1HexAttribute>()
+                    .map_err(|error| anyhow!("invalid avatar hash: {error}"))?,
+                type_: avatar.content_type.clone(),
+                url: None,
+            };
+
+            publications.push(publish(
+                ns::AVATAR_DATA,
+                Some(&id),
+                Data {
+                    data: avatar.data.clone(),
+                }
+                .into(),
+            ));
+            publications.push(publish(
+                ns::AVATAR_METADATA,
+                Some(&id),
+                Metadata { infos: vec![info] }.into(),
+            ));
+        }
+
+        if let Some(nickname) = &self.nickname {
+            publications.push(publish(ns::NICK, None, Nick(nickname.clone()).into()));
+        }
+
+        Ok(publications)
+    }
+}
+
+/// Nickname, or the full name if missing, from the vCard of a contact.
+pub fn vcard_nickname(vcard: &Element) -> Option<String> {
+    if !vcard.is("vCard", ns::VCARD) {
+        return None;
+    }
+
+    ["NICKNAME", "FN"]
+        .into_iter()
+        .filter_map(|name| vcard.get_child(name, ns::VCARD))
+        .map(|element| element.text().trim().to_string())
+        .find(|text| !text.is_empty())
+}
+
+fn publish(node: &str, id: Option<&str>, payload: Element) -> PubSub {
+    PubSub::Publish {
+        publish: Publish {
+            node: NodeName(node.to_string()),
+            items: vec![Item(PubSubItem {
+                id: id.map(|id| ItemId(id.to_string())),
+                publisher: None,
+                payload: Some(payload),
+            })],
+        },
+        publish_options: None,
+    }
+}
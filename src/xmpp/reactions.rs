@@ -0,0 +1,165 @@
+// Copyright (c) 2024 Dmitry Markin
+//
+// SPDX-License-Identifier: MIT
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! XEP-0444 message reactions and feedback log.
+
+use anyhow::{anyhow, Context as _};
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    fs::OpenOptions,
+    io::Write as _,
+    path::PathBuf,
+    sync::mpsc::{channel, Sender},
+    thread,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+// Log target for this file.
+const LOG_TARGET: &str = "jutella::reactions";
+
+// Number of recently sent messages we remember to be able to process reactions to them.
+const SENT_MESSAGES_CAPACITY: usize = 1024;
+
+/// Bot message sent in response to a request.
+#[derive(Debug, Clone)]
+pub struct SentMessage {
+    pub jid: String,
+    pub thread: Option<String>,
+    pub request: String,
+    pub response: String,
+    pub model: String,
+    pub tokens_in: usize,
+    pub tokens_out: usize,
+    /// Reactions already processed. Every reactions update contains the full set of reactions,
+    /// so we need to remember them to only act on new ones.
+    pub reactions: HashSet<String>,
+}
+
+/// Recently sent messages, indexed by message id.
+#[derive(Debug, Default)]
+pub struct SentMessages {
+    messages: HashMap<String, SentMessage>,
+    order: VecDeque<String>,
+}
+
+impl SentMessages {
+    pub fn insert(&mut self, id: String, message: SentMessage) {
+        if self.order.len() >= SENT_MESSAGES_CAPACITY {
+            if let Some(oldest) = self.order.pop_front() {
+                self.messages.remove(&oldest);
+            }
+        }
+
+        self.order.push_back(id.clone());
+        self.messages.insert(id, message);
+    }
+
+    pub fn get_mut(&mut self, id: &str) -> Option<&mut SentMessage> {
+        self.messages.get_mut(id)
+    }
+}
+
+/// Single feedback record.
+#[derive(Debug, serde::Serialize)]
+pub struct FeedbackRecord<'a> {
+    pub timestamp: u64,
+    /// JID of the bot, to tell the records of several bots apart.
+    pub bot: &'a str,
+    pub jid: &'a str,
+    pub message_id: &'a str,
+    pub positive: bool,
+    pub model: &'a str,
+    pub tokens_in: usize,
+    pub tokens_out: usize,
+    pub request: &'a str,
+    pub response: &'a str,
+}
+
+impl<'a> FeedbackRecord<'a> {
+    pub fn new(
+        bot: &'a str,
+        message_id: &'a str,
+        message: &'a SentMessage,
+        positive: bool,
+    ) -> Self {
+        Self {
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs(),
+            bot,
+            jid: &message.jid,
+            message_id,
+            positive,
+            model: &message.model,
+            tokens_in: message.tokens_in,
+            tokens_out: message.tokens_out,
+            request: &message.request,
+            response: &message.response,
+        }
+    }
+}
+
+/// Feedback log in JSON Lines format.
+///
+/// Records are written in order by a dedicated thread, so that file I/O doesn't stall the event
+/// loop.
+#[derive(Debug)]
+pub struct FeedbackLog {
+    line_tx: Sender<String>,
+}
+
+impl FeedbackLog {
+    /// Start the writer thread. It exits once the log is dropped.
+    pub fn new(path: PathBuf) -> Self {
+        let (line_tx, line_rx) = channel::<String>();
+
+        thread::spawn(move || {
+            for line in line_rx {
+                if let Err(error) = OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(&path)
+                    .and_then(|mut file| file.write_all(line.as_bytes()))
+                {
+                    tracing::error!(
+                        target: LOG_TARGET,
+                        ?error,
+                        "failed to write feedback log {}",
+                        path.display(),
+                    );
+                }
+            }
+        });
+
+        Self { line_tx }
+    }
+
+    pub fn append(&self, record: &FeedbackRecord<'_>) -> anyhow::Result<()> {
+        let mut line = serde_json::to_string(record).context("Failed to serialize feedback")?;
+        line.push('\n');
+
+        self.line_tx
+            .send(line)
+            .map_err(|_| anyhow!("Feedback log writer terminated"))
+    }
+}
@@ -0,0 +1,88 @@
+// Copyright (c) 2024 Dmitry Markin
+//
+// SPDX-License-Identifier: MIT
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! Roster management and access requests.
+
+use std::collections::BTreeSet;
+use xmpp_parsers::{
+    jid::BareJid,
+    roster::{Ask, Item, Roster, Subscription},
+};
+
+/// State file with the users approved by admins.
+pub const APPROVED_USERS_FILE: &str = "approved_users.json";
+
+/// Persisted list of users approved by admins.
+#[derive(Debug, Default, serde::Serialize, serde::Deserialize)]
+pub struct ApprovedUsers {
+    pub jids: BTreeSet<String>,
+}
+
+/// State file with the pending access requests.
+pub const ACCESS_REQUESTS_FILE: &str = "access_requests.json";
+
+/// Persisted list of users waiting for an admin to approve their access request.
+#[derive(Debug, Default, serde::Serialize, serde::Deserialize)]
+pub struct AccessRequests {
+    pub jids: BTreeSet<String>,
+}
+
+/// State file with the roster contacts added by the bot.
+pub const ROSTER_CONTACTS_FILE: &str = "roster_contacts.json";
+
+/// Persisted list of users the bot approved the presence subscription of. Only these are
+/// removed from the roster once they lose access, other contacts of the account are kept.
+#[derive(Debug, Default, serde::Serialize, serde::Deserialize)]
+pub struct RosterContacts {
+    pub jids: BTreeSet<String>,
+}
+
+/// Roster get query.
+pub fn roster_query() -> Roster {
+    Roster {
+        ver: None,
+        items: Vec::new(),
+    }
+}
+
+/// Roster set removing the contact.
+pub fn remove_item(jid: BareJid) -> Roster {
+    Roster {
+        ver: None,
+        items: vec![Item {
+            jid,
+            name: None,
+            subscription: Subscription::Remove,
+            ask: Ask::None,
+            groups: Vec::new(),
+        }],
+    }
+}
+
+/// Access request forwarded to admins.
+pub fn access_request_message(jid: &str) -> String {
+    format!(
+        "{jid} requests access to the bot.\n\
+         Reply \"/approve {jid}\" or \"/deny {jid}\", or use the \"Approve user\" and \
+         \"Deny user\" commands."
+    )
+}
@@ -0,0 +1,245 @@
+// Copyright (c) 2024 Dmitry Markin
+//
+// SPDX-License-Identifier: MIT
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! Splitting of long responses into several messages.
+
+use crate::xmpp::markdown::{split_code_blocks, Block};
+
+/// Space reserved for the part number.
+pub const PART_NUMBER_RESERVE: usize = 16;
+
+/// Split the message into parts of at most `max_length` bytes.
+///
+/// The message is split at paragraph and code block boundaries where possible. Code blocks
+/// that don't fit into one part are split by lines, with every piece fenced separately. Parts
+/// are numbered if the message is split.
+pub fn split_message(message: &str, max_length: usize) -> Vec<String> {
+    if message.len() <= max_length {
+        return vec![message.to_string()];
+    }
+
+    let limit = max_length.saturating_sub(PART_NUMBER_RESERVE).max(1);
+
+    let mut chunks = Vec::new();
+    for block in split_code_blocks(message) {
+        match block {
+            Block::Text(text) => {
+                for paragraph in text.split("\n\n") {
+                    chunks.extend(split_lines(paragraph, limit));
+                }
+            }
+            Block::Code { fenced, .. } if fenced.len() <= limit => {
+                chunks.push(fenced.to_string());
+            }
+            Block::Code {
+                language, content, ..
+            } => {
+                // Opening fence with a newline and closing fence.
+                let fences_length = language.len() + 7;
+                let content_limit = limit.saturating_sub(fences_length).max(1);
+
+                for piece in split_lines(content, content_limit) {
+                    let newline = if piece.ends_with('\n') { "" } else { "\n" };
+                    chunks.push(format!("```{language}\n{piece}{newline}```"));
+                }
+            }
+        }
+    }
+
+    let mut parts = Vec::new();
+    let mut current = String::new();
+    for chunk in chunks {
+        let chunk = chunk.trim_matches('\n');
+        if chunk.is_empty() {
+            continue;
+        }
+
+        if !current.is_empty() && current.len() + 2 + chunk.len() > limit {
+            parts.push(std::mem::take(&mut current));
+        }
+        if !current.is_empty() {
+            current.push_str("\n\n");
+        }
+        current.push_str(chunk);
+    }
+    if !current.is_empty() {
+        parts.push(current);
+    }
+
+    number_parts(parts)
+}
+
+/// Number the parts of a split message, unless there is only one.
+pub fn number_parts(parts: Vec<String>) -> Vec<String> {
+    let total = parts.len();
+    if total <= 1 {
+        return parts;
+    }
+
+    // The number goes on a separate line, so that it doesn't break a leading code fence.
+    parts
+        .into_iter()
+        .enumerate()
+        .map(|(index, part)| format!("({}/{total})\n{part}", index + 1))
+        .collect()
+}
+
+/// Strip the part number added by [`split_message`].
+pub fn strip_part_number(part: &str) -> &str {
+    let Some((number, rest)) = part.split_once('\n') else {
+        return part;
+    };
+
+    let is_part_number = number
+        .strip_prefix('(')
+        .and_then(|number| number.strip_suffix(')'))
+        .and_then(|number| number.split_once('/'))
+        .is_some_and(|(index, total)| {
+            index.parse::<usize>().is_ok() && total.parse::<usize>().is_ok()
+        });
+
+    if is_part_number {
+        rest
+    } else {
+        part
+    }
+}
+
+/// Split the text by lines into pieces of at most `limit` bytes. Lines longer than the limit
+/// are split at whitespace, or at any character if there is none.
+fn split_lines(text: &str, limit: usize) -> Vec<String> {
+    let mut pieces = Vec::new();
+    let mut current = String::new();
+
+    for line in text.split_inclusive('\n') {
+        if current.len() + line.len() > limit && !current.is_empty() {
+            pieces.push(std::mem::take(&mut current));
+        }
+
+        if line.len() <= limit {
+            current.push_str(line);
+            continue;
+        }
+
+        let mut rest = line;
+        while rest.len() > limit {
+            let mut end = limit;
+            while !rest.is_char_boundary(end) {
+                end -= 1;
+            }
+            if let Some(space) = rest[..end].rfind(char::is_whitespace) {
+                if space > 0 {
+                    end = space;
+                }
+            }
+            if end == 0 {
+                // Single character longer than the limit.
+                end = rest.chars().next().map_or(rest.len(), char::len_utf8);
+            }
+
+            pieces.push(rest[..end].to_string());
+            rest = rest[end..].trim_start_matches([' ', '\t']);
+        }
+        current.push_str(rest);
+    }
+
+    if !current.is_empty() {
+        pieces.push(current);
+    }
+
+    pieces
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn short_message_is_not_split() {
+        assert_eq!(split_message("Hello!", 100), vec!["Hello!".to_string()]);
+    }
+
+    #[test]
+    fn split_at_paragraphs() {
+        let message = format!("{}\n\n{}", "a".repeat(60), "b".repeat(60));
+        let parts = split_message(&message, 100);
+
+        assert_eq!(
+            parts,
+            vec![
+                format!("(1/2)\n{}", "a".repeat(60)),
+                format!("(2/2)\n{}", "b".repeat(60)),
+            ]
+        );
+    }
+
+    #[test]
+    fn parts_fit_max_length() {
+        let message = "word ".repeat(200);
+        let parts = split_message(&message, 100);
+
+        assert!(parts.len() > 1);
+        assert!(parts.iter().all(|part| part.len() <= 100));
+        let words = parts
+            .iter()
+            .flat_map(|part| strip_part_number(part).split_whitespace())
+            .count();
+        assert_eq!(words, 200);
+    }
+
+    #[test]
+    fn long_code_block_is_fenced_in_every_part() {
+        let code = (0..40)
+            .map(|index| format!("let x{index} = {index};"))
+            .collect::<Vec<_>>()
+            .join("\n");
+        let message = format!("Code:\n\n```rust\n{code}\n```");
+        let parts = split_message(&message, 200);
+
+        assert!(parts.len() > 2);
+        for part in &parts[1..] {
+            let part = strip_part_number(part);
+            assert!(part.starts_with("```rust\n"), "{part}");
+            assert!(part.ends_with("```"), "{part}");
+        }
+    }
+
+    #[test]
+    fn multibyte_characters_are_not_broken() {
+        let message = "ж".repeat(100);
+        let parts = split_message(&message, 50);
+
+        let joined = parts
+            .iter()
+            .map(|part| strip_part_number(part))
+            .collect::<String>();
+        assert_eq!(joined, message);
+    }
+
+    #[test]
+    fn strip_part_number_only_strips_numbers() {
+        assert_eq!(strip_part_number("(2/3)\nText"), "Text");
+        assert_eq!(strip_part_number("(a/b)\nText"), "(a/b)\nText");
+        assert_eq!(strip_part_number("(2/3) Text"), "(2/3) Text");
+        assert_eq!(strip_part_number("Text"), "Text");
+    }
+}
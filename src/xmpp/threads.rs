@@ -0,0 +1,211 @@
+// Copyright (c) 2024 Dmitry Markin
+//
+// SPDX-License-Identifier: MIT
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! Parallel conversations (threads) of a user.
+
+use chrono::{DateTime, Utc};
+use std::{collections::BTreeMap, fmt::Write, time::SystemTime};
+
+/// Name of the default thread in commands.
+pub const DEFAULT_THREAD: &str = "default";
+
+/// Maximum number of named threads per user. The least recently active one is dropped when
+/// exceeded.
+pub const MAX_THREADS: usize = 20;
+
+/// Activity of a single thread.
+#[derive(Debug, Clone, Default)]
+pub struct ThreadInfo {
+    pub last_active: Option<DateTime<Utc>>,
+    /// Size of the context in tokens as of the last response.
+    pub tokens: usize,
+}
+
+/// Threads of a user. `None` stands for the default thread.
+#[derive(Debug, Default)]
+pub struct UserThreads {
+    /// Thread the messages without `<thread>` element go to.
+    pub current: Option<String>,
+    default: ThreadInfo,
+    named: BTreeMap<String, ThreadInfo>,
+}
+
+impl UserThreads {
+    pub fn contains(&self, thread: &str) -> bool {
+        self.named.contains_key(thread)
+    }
+
+    /// Names of the threads, excluding the default one.
+    pub fn names(&self) -> impl Iterator<Item = &String> {
+        self.named.keys()
+    }
+
+    /// Add the thread if it doesn't exist yet. Returns the thread dropped to stay within
+    /// [`MAX_THREADS`], if any.
+    pub fn insert(&mut self, thread: &str) -> Option<String> {
+        if self.named.contains_key(thread) {
+            return None;
+        }
+
+        let dropped = if self.named.len() >= MAX_THREADS {
+            self.named
+                .iter()
+                .filter(|(name, _)| Some(*name) != self.current.as_ref())
+                .min_by_key(|(_, info)| info.last_active)
+                .map(|(name, _)| name.clone())
+        } else {
+            None
+        };
+        if let Some(dropped) = &dropped {
+            self.named.remove(dropped);
+        }
+
+        self.named.insert(thread.to_string(), ThreadInfo::default());
+
+        dropped
+    }
+
+    /// Record the activity in the thread. Threads not added with [`Self::insert`] are ignored.
+    pub fn touch(&mut self, thread: Option<&str>, tokens: Option<usize>) {
+        let info = match thread {
+            Some(thread) => match self.named.get_mut(thread) {
+                Some(info) => info,
+                None => return,
+            },
+            None => &mut self.default,
+        };
+
+        info.last_active = Some(SystemTime::now().into());
+        if let Some(tokens) = tokens {
+            info.tokens = tokens;
+        }
+    }
+
+    /// Human-readable list of the threads.
+    pub fn describe(&self) -> String {
+        let current = self.current.as_deref().unwrap_or(DEFAULT_THREAD);
+        let mut description = format!("Current thread: {current}\nThreads:");
+
+        let threads = std::iter::once((DEFAULT_THREAD, &self.default))
+            .chain(self.named.iter().map(|(name, info)| (name.as_str(), info)));
+        for (name, info) in threads {
+            let last_active = info
+                .last_active
+                .map(|time| time.format("%Y-%m-%d %H:%M UTC").to_string())
+                .unwrap_or_else(|| "never".to_string());
+            let _ = write!(
+                description,
+                "\n{name} – last active {last_active}, {} tokens",
+                info.tokens,
+            );
+        }
+
+        description
+            .push_str("\nStart a thread with \"/new <topic>\", switch with \"/switch <topic>\".");
+        description
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Threads with [`MAX_THREADS`] named threads `t00`, `t01`, …, all active except `idle`.
+    fn full_threads(idle: &[&str]) -> UserThreads {
+        let mut threads = UserThreads::default();
+        for n in 0..MAX_THREADS {
+            let name = format!("t{n:02}");
+            assert_eq!(threads.insert(&name), None);
+            if !idle.contains(&name.as_str()) {
+                threads.touch(Some(&name), None);
+            }
+        }
+        threads
+    }
+
+    #[test]
+    fn insert_and_lookup() {
+        let mut threads = UserThreads::default();
+        assert!(!threads.contains("rust"));
+
+        assert_eq!(threads.insert("rust"), None);
+        assert_eq!(threads.insert("cooking"), None);
+        assert_eq!(threads.insert("rust"), None);
+
+        assert!(threads.contains("rust"));
+        assert!(!threads.contains(DEFAULT_THREAD));
+        assert_eq!(threads.names().collect::<Vec<_>>(), vec!["cooking", "rust"],);
+    }
+
+    #[test]
+    fn touch_records_activity() {
+        let mut threads = UserThreads::default();
+        threads.insert("rust");
+
+        threads.touch(Some("rust"), Some(120));
+        threads.touch(Some("rust"), None);
+        threads.touch(None, Some(30));
+        threads.touch(Some("unknown"), Some(50));
+
+        let rust = &threads.named["rust"];
+        assert!(rust.last_active.is_some());
+        assert_eq!(rust.tokens, 120);
+        assert!(threads.default.last_active.is_some());
+        assert_eq!(threads.default.tokens, 30);
+        assert!(!threads.contains("unknown"));
+    }
+
+    #[test]
+    fn least_recently_active_dropped() {
+        let mut threads = full_threads(&["t05"]);
+
+        assert_eq!(threads.insert("new"), Some("t05".to_string()));
+        assert!(threads.contains("new"));
+        assert!(!threads.contains("t05"));
+        assert_eq!(threads.names().count(), MAX_THREADS);
+    }
+
+    #[test]
+    fn current_thread_kept() {
+        let mut threads = full_threads(&["t05", "t07"]);
+        threads.current = Some("t05".to_string());
+
+        assert_eq!(threads.insert("new"), Some("t07".to_string()));
+        assert!(threads.contains("t05"));
+    }
+
+    #[test]
+    fn describe_threads() {
+        let mut threads = UserThreads::default();
+        threads.insert("rust");
+        threads.current = Some("rust".to_string());
+
+        assert_eq!(
+            threads.describe(),
+            "Current thread: rust\n\
+             Threads:\n\
+             default – last active never, 0 tokens\n\
+             rust – last active never, 0 tokens\n\
+             Start a thread with \"/new <topic>\", switch with \"/switch <topic>\".",
+        );
+    }
+}
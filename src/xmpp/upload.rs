@@ -0,0 +1,263 @@
+// Copyright (c) 2024 Dmitry Markin
+//
+// SPDX-License-Identifier: MIT
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! XEP-0363 HTTP file upload of long responses.
+
+use crate::xmpp::markdown::{split_code_blocks, Block};
+use anyhow::anyhow;
+use std::time::Duration;
+use xmpp_parsers::{
+    disco::DiscoInfoResult,
+    http_upload::{Header, SlotResult},
+    jid::Jid,
+    ns,
+};
+
+// Timeout of a single HTTP upload.
+pub const UPLOAD_TIMEOUT: Duration = Duration::from_secs(60);
+
+// Maximum length of the summary sent along with the uploaded response.
+const SUMMARY_LENGTH: usize = 300;
+
+/// Upload service discovered on the server.
+#[derive(Debug, Clone)]
+pub struct UploadService {
+    pub jid: Jid,
+    pub max_file_size: Option<u64>,
+}
+
+impl UploadService {
+    /// Extract upload service from `disco#info` response, if the entity is one.
+    pub fn from_disco_info(jid: Jid, info: &DiscoInfoResult) -> Option<Self> {
+        if !info
+            .features
+            .iter()
+            .any(|feature| feature.var == ns::HTTP_UPLOAD)
+        {
+            return None;
+        }
+
+        let max_file_size = info
+            .extensions
+            .iter()
+            .filter(|form| form.form_type.as_deref() == Some(ns::HTTP_UPLOAD))
+            .flat_map(|form| form.fields.iter())
+            .find(|field| field.var.as_deref() == Some("max-file-size"))
+            .and_then(|field| field.values.first())
+            .and_then(|value| value.parse().ok());
+
+        Some(Self { jid, max_file_size })
+    }
+}
+
+/// File to upload.
+#[derive(Debug, Clone)]
+pub struct UploadFile {
+    pub filename: String,
+    pub content_type: String,
+    pub data: Vec<u8>,
+}
+
+#[derive(Debug, Clone)]
+enum Segment {
+    Text(String),
+    /// Link to the uploaded file with the index.
+    File(usize),
+}
+
+/// Response with parts replaced by links to uploaded files.
+#[derive(Debug, Clone)]
+pub struct UploadPlan {
+    segments: Vec<Segment>,
+    pub files: Vec<UploadFile>,
+}
+
+impl UploadPlan {
+    /// Decide what to upload if the response is longer than `threshold` characters.
+    ///
+    /// Fenced code blocks longer than the threshold are uploaded as separate files. If the rest
+    /// of the response is still too long, the whole response is uploaded as a Markdown document
+    /// instead and only its beginning is kept in the message.
+    pub fn new(response: &str, threshold: usize) -> Option<Self> {
+        if response.chars().count() <= threshold {
+            return None;
+        }
+
+        let mut segments = Vec::new();
+        let mut files = Vec::new();
+        let mut text = String::new();
+
+        for block in split_code_blocks(response) {
+            match block {
+                Block::Code {
+                    fenced: _,
+                    language,
+                    content,
+                } if content.chars().count() > threshold => {
+                    let extension = file_extension(language);
+                    let filename = format!("code-{}.{extension}", files.len() + 1);
+                    text.push_str(&format!("[{filename}, {} lines] ", content.lines().count()));
+                    segments.push(Segment::Text(std::mem::take(&mut text)));
+                    segments.push(Segment::File(files.len()));
+                    text.push('\n');
+                    files.push(UploadFile {
+                        filename,
+                        content_type: content_type(extension).to_string(),
+                        data: content.as_bytes().to_vec(),
+                    });
+                }
+                Block::Code { fenced, .. } => text.push_str(fenced),
+                Block::Text(t) => text.push_str(t),
+            }
+        }
+        segments.push(Segment::Text(text));
+
+        let remaining = segments
+            .iter()
+            .map(|segment| match segment {
+                Segment::Text(text) => text.chars().count(),
+                Segment::File(_) => 0,
+            })
+            .sum::<usize>();
+
+        if files.is_empty() || remaining > threshold {
+            return Some(Self {
+                segments: vec![
+                    Segment::Text(format!("{}\n\nFull answer: ", summary(response))),
+                    Segment::File(0),
+                ],
+                files: vec![UploadFile {
+                    filename: "response.md".to_string(),
+                    content_type: content_type("md").to_string(),
+                    data: response.as_bytes().to_vec(),
+                }],
+            });
+        }
+
+        Some(Self { segments, files })
+    }
+
+    /// Upload the whole document as `<name>.<extension>`, with `text` before the link.
+    pub fn document(text: &str, name: &str, extension: &str, data: String) -> Self {
+        Self {
+            segments: vec![Segment::Text(format!("{text} ")), Segment::File(0)],
+            files: vec![UploadFile {
+                filename: format!("{name}.{extension}"),
+                content_type: content_type(extension).to_string(),
+                data: data.into_bytes(),
+            }],
+        }
+    }
+
+    /// Message text with links to the uploaded files.
+    pub fn render(&self, urls: &[String]) -> String {
+        self.segments
+            .iter()
+            .map(|segment| match segment {
+                Segment::Text(text) => text.as_str(),
+                Segment::File(index) => urls.get(*index).map(String::as_str).unwrap_or_default(),
+            })
+            .collect::<String>()
+            .trim()
+            .to_string()
+    }
+}
+
+/// Upload the file to the slot and return the download URL.
+pub async fn upload(
+    client: reqwest::Client,
+    slot: SlotResult,
+    file: UploadFile,
+) -> anyhow::Result<String> {
+    let mut request = client
+        .put(&slot.put.url)
+        .timeout(UPLOAD_TIMEOUT)
+        .header(reqwest::header::CONTENT_TYPE, file.content_type)
+        .body(file.data);
+
+    for header in slot.put.headers {
+        request = match header {
+            Header::Authorization(value) => request.header(reqwest::header::AUTHORIZATION, value),
+            Header::Cookie(value) => request.header(reqwest::header::COOKIE, value),
+            Header::Expires(value) => request.header(reqwest::header::EXPIRES, value),
+        };
+    }
+
+    request
+        .send()
+        .await
+        .and_then(|response| response.error_for_status())
+        .map_err(|error| anyhow!("failed to upload file: {}", error.without_url()))?;
+
+    Ok(slot.get.url)
+}
+
+fn file_extension(language: &str) -> &'static str {
+    match language.to_ascii_lowercase().as_str() {
+        "rust" | "rs" => "rs",
+        "python" | "py" => "py",
+        "javascript" | "js" => "js",
+        "typescript" | "ts" => "ts",
+        "bash" | "sh" | "shell" | "zsh" => "sh",
+        "c" => "c",
+        "cpp" | "c++" => "cpp",
+        "go" => "go",
+        "java" => "java",
+        "html" => "html",
+        "xml" => "xml",
+        "json" => "json",
+        "yaml" | "yml" => "yaml",
+        "toml" => "toml",
+        "sql" => "sql",
+        "csv" => "csv",
+        "markdown" | "md" => "md",
+        _ => "txt",
+    }
+}
+
+fn content_type(extension: &str) -> &'static str {
+    match extension {
+        "md" => "text/markdown; charset=utf-8",
+        "csv" => "text/csv; charset=utf-8",
+        "html" => "text/html; charset=utf-8",
+        "json" => "application/json",
+        "xml" => "application/xml",
+        _ => "text/plain; charset=utf-8",
+    }
+}
+
+/// First paragraph of the response, shortened if needed.
+fn summary(response: &str) -> String {
+    let paragraph = response
+        .split("\n\n")
+        .map(str::trim)
+        .find(|paragraph| !paragraph.is_empty() && !paragraph.starts_with("```"))
+        .unwrap_or_default();
+
+    if paragraph.chars().count() <= SUMMARY_LENGTH {
+        paragraph.to_string()
+    } else {
+        let mut summary = paragraph.chars().take(SUMMARY_LENGTH).collect::<String>();
+        summary.push('…');
+        summary
+    }
+}